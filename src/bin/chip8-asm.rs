@@ -0,0 +1,56 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use chip8::cli::{self, Arg, Parser, exit_with_error};
+
+fn usage(program: &str) -> String {
+    let mut usage = String::new();
+    usage += &format!("Usage: {} [OPTIONS] <SOURCE>\n", program);
+    usage += "\n";
+    usage += "Assembles a source file using the mnemonics printed by chip8-disasm into a ROM.\n";
+    usage += "\n";
+    usage += "Options:\n";
+    usage += "  -o, --output <FILE>         ROM file to write, defaults to the source without its extension\n";
+    usage += "  --config <FILE>             Settings file, for the ROM address\n";
+    usage += "  -h, --help                  Print this help\n";
+    usage
+}
+
+fn main() {
+    let mut args = env::args();
+    let program = args.next().unwrap_or_else(|| "chip8-asm".to_string());
+
+    let mut source = None;
+    let mut output = None;
+    let mut config = None;
+    let mut args = Parser::new(args);
+    loop {
+        let result = match args.next_arg() {
+            Ok(None) => break,
+            Ok(Some(Arg::Help)) => {
+                print!("{}", usage(&program));
+                return;
+            }
+            Ok(Some(Arg::Path(path))) => cli::set_path(&mut source, path, "source file"),
+            Ok(Some(Arg::Option(name))) => match name.as_str() {
+                "-o" | "--output" => args.value().map(|value| output = Some(PathBuf::from(value))),
+                "--config" => args.value().map(|value| config = Some(PathBuf::from(value))),
+                _ => Err(format!("unknown option '{}'", name)),
+            },
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            cli::exit_with_usage(&error, &usage(&program));
+        }
+    }
+    let source = source.unwrap_or_else(|| cli::exit_with_usage("missing source path", &usage(&program)));
+    let output = output.unwrap_or_else(|| source.with_extension(""));
+    if output == source {
+        exit_with_error(format!("output would overwrite the source {}, use --output", source.display()));
+    }
+
+    let settings = cli::settings(config.as_deref()).unwrap_or_else(|error| exit_with_error(error));
+
+    let rom = chip8::assemble_file(&source, &settings).unwrap_or_else(|error| exit_with_error(error.to_string()));
+    fs::write(&output, &rom).unwrap_or_else(|error| exit_with_error(format!("can't write ROM {}: {}", output.display(), error)));
+}
@@ -0,0 +1,60 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use chip8::cli::{self, Arg, Parser, exit_with_error};
+use chip8::{Database, Profile};
+
+fn usage(program: &str) -> String {
+    let mut usage = String::new();
+    usage += &format!("Usage: {} [OPTIONS] <ROM>\n", program);
+    usage += "\n";
+    usage += "Prints a disassembly of the ROM, separating code from data by following jumps, calls and skips.\n";
+    usage += "The variant is taken from the ROM database when the ROM is in it.\n";
+    usage += "\n";
+    usage += "Options:\n";
+    usage += "  --config <FILE>             Settings file, applied before the ROM database\n";
+    usage += "  --variant <chip8|schip|xochip>\n";
+    usage += "                              Instruction set to decode\n";
+    usage += "  -h, --help                  Print this help\n";
+    usage
+}
+
+fn main() {
+    let mut args = env::args();
+    let program = args.next().unwrap_or_else(|| "chip8-disasm".to_string());
+
+    let mut rom = None;
+    let mut config = None;
+    let mut overrides = Profile::new();
+    let mut args = Parser::new(args);
+    loop {
+        let result = match args.next_arg() {
+            Ok(None) => break,
+            Ok(Some(Arg::Help)) => {
+                print!("{}", usage(&program));
+                return;
+            }
+            Ok(Some(Arg::Path(path))) => cli::set_path(&mut rom, path, "ROM"),
+            Ok(Some(Arg::Option(name))) => match name.as_str() {
+                "--config" => args.value().map(|value| config = Some(PathBuf::from(value))),
+                "--variant" => args.value().and_then(|value| overrides.set("variant", &value)),
+                _ => Err(format!("unknown option '{}'", name)),
+            },
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            cli::exit_with_usage(&error, &usage(&program));
+        }
+    }
+    let rom = rom.unwrap_or_else(|| cli::exit_with_usage("missing ROM path", &usage(&program)));
+
+    let data = fs::read(&rom).unwrap_or_else(|error| exit_with_error(format!("can't load ROM {}: {}", rom.display(), error)));
+
+    let mut settings = cli::settings(config.as_deref()).unwrap_or_else(|error| exit_with_error(error));
+    if let Some((_, profile)) = Database::builtin().lookup(&data) {
+        settings.apply(profile);
+    }
+    settings.apply(&overrides);
+
+    print!("{}", chip8::disassemble(&data, &settings));
+}
@@ -1,64 +1,194 @@
-use std::fs::File;
-use std::io;
-use std::io::{BufReader, Read};
-use std::path::Path;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use clk::Clock;
 use cpu::CPU;
-use dsp::Display;
+use dbg::Debugger;
+use flt::Persistence;
 use kbd::Keyboard;
+use key::Remap;
 use mem::Memory;
 use rng::RNG;
-use set::Settings;
-use super::platform::{Platform, PlatformEvent};
+use rwd::Rewind;
+use sav::{Reader, Writer};
+use super::frontend::{Frontend, FrontendEvent};
 
+mod asm;
+mod cfg;
 mod clk;
 mod cpu;
+mod db;
+mod dbg;
+mod dis;
 mod dsp;
+mod err;
+mod flt;
 mod kbd;
+mod key;
 mod mem;
+mod mov;
+mod oct;
 mod op;
+mod pal;
 mod rng;
+mod rwd;
+mod sav;
 mod set;
+mod sha;
+mod vip;
 
-pub struct CHIP8 {
+const FRAME_FREQ: u16 = 60;
+
+pub use asm::{AsmError, assemble, assemble_file};
+pub use oct::{compile_octo, compile_octo_file};
+pub use db::Database;
+pub use dis::disassemble;
+pub use dsp::Display;
+pub use err::Chip8Error;
+pub use flt::Filter;
+pub use key::Keymap;
+pub use mov::Movie;
+pub use pal::Palette;
+pub use set::{Profile, Settings, Variant};
+
+// What happened during a step, a number of cycles or a frame
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Status {
+    // The display changed and should be redrawn
+    pub frame_ready: bool,
+    // The sound timer is active, or LDKP (Fx0A) is waiting for a key to be released
+    pub beep: bool,
+    // The CPU is blocked on LDKP (Fx0A) until a key is pressed, or released with wait_key_release
+    pub waiting_for_key: bool,
+    // The CPU jumped to the instruction itself or executed EXIT (00FD) and will not make progress anymore
+    pub halted: bool,
+    // The step ended a 60 Hz frame
+    pub frame_done: bool,
+}
+
+pub struct CHIP8<F: Frontend> {
     settings: Settings,
-    platform: Platform,
+    // Settings chosen by the user for this ROM, applied after the database entry
+    overrides: Profile,
+    database: Database,
+    // Save states are only accepted for the ROM they were made with
+    rom_path: Option<PathBuf>,
+    rom_sha1: [u8; 20],
+    frontend: F,
     memory: Memory,
     display: Display,
+    // What the display filter shows, only updated while it's on
+    persistence: Persistence,
     keyboard: Keyboard,
     cpu: CPU,
+    rng: RNG,
+    debugger: Debugger,
+
+    // While a movie is recorded or played back the input is latched at the start of every frame, keys and presses
+    // given during a frame wait for the next one
+    recording: Option<Movie>,
+    playback: Option<(Movie, usize)>,
+    keys: [bool; 16],
+    presses: Vec<u8>,
+
+    // Execution is scheduled in 60 Hz frames, each gets cpu_freq / 60 instructions (or with vip_timing the COSMAC VIP
+    // machine cycles left by the display interrupt). What a frame runs over is taken from the next one.
+    frame_started: bool,
+    frames: u64,
+    budget: i32,
+    // The timers tick at the end of every frame their period has elapsed in
+    dt_clock: Clock,
+    st_clock: Clock,
 }
 
-impl CHIP8 {
-    pub fn new() -> Self {
+impl<F: Frontend> CHIP8<F> {
+    pub fn new(frontend: F, settings: Settings) -> Self {
+        let time = Duration::from_secs(0);
         Self {
-            settings: Settings::new(),
-            platform: Platform::new(),
-            memory: Memory::new(),
+            overrides: Profile::new(),
+            database: Database::builtin(),
+            rom_path: None,
+            rom_sha1: sha::sha1(&[]),
+            frontend,
+            memory: Memory::new(settings.variant.memory_size()),
             display: Display::new(),
+            persistence: Persistence::new(),
             keyboard: Keyboard::new(),
             cpu: CPU::new(),
+            rng: RNG::new(settings.rng_seed),
+            debugger: Debugger::new(),
+            recording: None,
+            playback: None,
+            keys: [false; 16],
+            presses: Vec::new(),
+            frame_started: false,
+            frames: 0,
+            budget: 0,
+            dt_clock: Clock::new(settings.dt_freq, time),
+            st_clock: Clock::new(settings.st_freq, time),
+            settings,
         }
     }
 
-    pub fn load_rom<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
-        let rom_file = File::open(path)?;
-        let rom_file = BufReader::new(rom_file);
+    pub fn database_mut(&mut self) -> &mut Database {
+        &mut self.database
+    }
+
+    pub fn set_overrides(&mut self, overrides: Profile) {
+        self.overrides = overrides;
+    }
+
+    // Loads the ROM and applies the settings it needs from the database, followed by the overrides.
+    // Octo sources (.8o) are compiled first.
+    pub fn load_rom<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Chip8Error> {
+        let rom = if path.as_ref().extension().is_some_and(|extension| extension == "8o") {
+            // The overrides may move the ROM address
+            self.settings.apply(&self.overrides);
+            oct::compile_octo_file(&path, &self.settings)?
+        } else {
+            fs::read(&path)?
+        };
+
+        let rom_name = match self.database.lookup(&rom) {
+            Some((name, profile)) => {
+                self.settings.apply(profile);
+                Some(name.unwrap_or("unnamed").to_string())
+            }
+            None => None,
+        };
+        self.settings.apply(&self.overrides);
+        self.update_timing();
+        self.frontend.set_keymap(&self.settings.keymap).map_err(Chip8Error::InvalidKeymap)?;
+        self.frontend.set_palette(&self.settings.palette);
+        self.memory = Memory::new(self.settings.variant.memory_size());
+
+        let max_size = self.memory.size().saturating_sub(self.settings.rom_addr as usize);
+        if rom.len() > max_size {
+            return Err(Chip8Error::RomTooLarge { size: rom.len(), max_size });
+        }
 
-        let mut rom_size = 0;
-        for byte in rom_file.bytes() {
-            let addr = (0x200 + rom_size) as u16;
-            rom_size += 1;
-            self.memory.write(addr, byte?);
+        for (i, byte) in rom.iter().enumerate() {
+            let addr = self.settings.rom_addr + i as u16;
+            self.memory.write(addr, *byte)?;
         }
+        self.rom_path = Some(path.as_ref().to_path_buf());
+        self.rom_sha1 = sha::sha1(&rom);
 
         if self.settings.print_rom {
+            let rom_size = rom.len();
+            match rom_name {
+                Some(name) => println!("ROM found in database: {}", name),
+                None => println!("ROM not found in database"),
+            }
             println!("ROM size: {}", rom_size);
             println!("ROM data:");
 
             for i in 0..rom_size {
-                let addr = (0x200 + i) as u16;
-                let byte = self.memory.read(addr);
+                let addr = self.settings.rom_addr + i as u16;
+                let byte = self.memory.read(addr)?;
                 print!("{:02x} ", byte);
                 if (i + 1) % 16 == 0 || i == rom_size - 1 {
                     println!();
@@ -71,51 +201,669 @@ impl CHIP8 {
         Ok(())
     }
 
+    // Restart the clocks and the RNG after the settings changed
+    fn update_timing(&mut self) {
+        self.rng = RNG::new(self.settings.rng_seed);
+        self.dt_clock = Clock::new(self.settings.dt_freq, self.time());
+        self.st_clock = Clock::new(self.settings.st_freq, self.time());
+    }
+
+    // Emulated time at the end of the last frame
+    fn time(&self) -> Duration {
+        Duration::from_nanos(self.frames * 1_000_000_000 / FRAME_FREQ as u64)
+    }
+
+    // Snapshot of the whole machine, including the settings, that load_state can restore
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.bytes(sav::MAGIC);
+        w.u16(sav::VERSION);
+        w.bytes(&self.rom_sha1);
+        self.settings.save(&mut w);
+        self.save_machine(&mut w);
+        w.finish()
+    }
+
+    // Only the parts that change while the ROM runs, not the settings. Rewind keeps one of these every frame.
+    fn machine_state(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        self.save_machine(&mut w);
+        w.finish()
+    }
+
+    fn save_machine(&self, w: &mut Writer) {
+        w.bool(self.frame_started);
+        w.u64(self.frames);
+        w.u32(self.budget as u32);
+        self.dt_clock.save(w);
+        self.st_clock.save(w);
+        self.cpu.save(w);
+        self.memory.save(w);
+        self.display.save(w);
+        self.keyboard.save(w);
+        self.rng.save(w);
+    }
+
+    // The state is checked completely before anything is changed, on error the machine is left as it was
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), Chip8Error> {
+        let mut r = Reader::new(state);
+        if r.bytes(sav::MAGIC.len()).ok() != Some(&sav::MAGIC[..]) {
+            return Err(sav::invalid("not a save state".to_string()));
+        }
+        let version = r.u16()?;
+        if version != sav::VERSION {
+            return Err(sav::invalid(format!("unsupported version {}", version)));
+        }
+        if r.bytes(self.rom_sha1.len())? != self.rom_sha1 {
+            return Err(Chip8Error::StateRomMismatch);
+        }
+
+        let mut settings = Settings::load(&mut r)?;
+        // These are about the host, not the machine, they stay as they are
+        settings.mute = self.settings.mute;
+        settings.keymap = self.settings.keymap.clone();
+        settings.palette = self.settings.palette;
+        settings.filter = self.settings.filter;
+        settings.decay_frames = self.settings.decay_frames;
+        settings.rewind_seconds = self.settings.rewind_seconds;
+        settings.print_rom = self.settings.print_rom;
+        settings.print_opcodes = self.settings.print_opcodes;
+        settings.debug = self.settings.debug;
+        let machine = MachineState::load(&mut r, &settings)?;
+        r.finish()?;
+
+        self.settings = settings;
+        self.restore_machine(machine);
+        Ok(())
+    }
+
+    // Restores a state made by machine_state with the current settings
+    fn load_machine_state(&mut self, state: &[u8]) -> Result<(), Chip8Error> {
+        let mut r = Reader::new(state);
+        let machine = MachineState::load(&mut r, &self.settings)?;
+        r.finish()?;
+        self.restore_machine(machine);
+        Ok(())
+    }
+
+    fn restore_machine(&mut self, machine: MachineState) {
+        self.frame_started = machine.frame_started;
+        self.frames = machine.frames;
+        self.budget = machine.budget;
+        self.dt_clock = machine.dt_clock;
+        self.st_clock = machine.st_clock;
+        self.cpu = machine.cpu;
+        self.memory = machine.memory;
+        self.display = machine.display;
+        self.persistence.reset(&self.display);
+        self.keyboard = machine.keyboard;
+        self.rng = machine.rng;
+    }
+
+    // Slot files are stored next to the ROM as <ROM>.state<slot>
+    fn state_path(&self, slot: u8) -> Option<PathBuf> {
+        let mut path = self.rom_path.clone()?.into_os_string();
+        path.push(format!(".state{}", slot));
+        Some(PathBuf::from(path))
+    }
+
+    fn config_path(&self) -> Option<PathBuf> {
+        self.rom_path.as_deref().map(rom_config_path)
+    }
+
+    // Switch to a keymap made on the remap screen and keep it in <ROM>.toml, so it's used whenever the ROM runs
+    fn save_keymap(&mut self, keymap: Keymap) -> Result<PathBuf, Chip8Error> {
+        self.frontend.set_keymap(&keymap).map_err(Chip8Error::InvalidKeymap)?;
+        self.settings.keymap = keymap;
+
+        let path = self.config_path().ok_or_else(|| io::Error::other("no ROM loaded"))?;
+        let text = if path.exists() { fs::read_to_string(&path)? } else { String::new() };
+        let values: Vec<(&str, String)> = key::SETTINGS.iter()
+            .enumerate()
+            .map(|(key, setting)| (*setting, self.settings.keymap.value(key as u8)))
+            .collect();
+        fs::write(&path, cfg::set_values(&text, &values))?;
+        Ok(path)
+    }
+
+    fn save_state_slot(&self, slot: u8) -> Result<PathBuf, Chip8Error> {
+        let path = self.state_path(slot).ok_or_else(|| sav::invalid("no ROM loaded".to_string()))?;
+        fs::write(&path, self.save_state())?;
+        Ok(path)
+    }
+
+    fn load_state_slot(&mut self, slot: u8) -> Result<PathBuf, Chip8Error> {
+        let path = self.state_path(slot).ok_or_else(|| sav::invalid("no ROM loaded".to_string()))?;
+        self.load_state(&fs::read(&path)?)?;
+        Ok(path)
+    }
+
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+
+    // Whether the CPU is blocked on LDKP (Fx0A), e.g. to show that the game waits for input
+    pub fn waiting_for_key(&self) -> bool {
+        self.keyboard.waiting()
+    }
+
+    pub fn set_keys(&mut self, keys: [bool; 16]) {
+        if self.movie_active() {
+            self.keys = keys;
+        } else {
+            self.keyboard.set_keys(keys);
+        }
+    }
+
+    pub fn push_keypress(&mut self, key: u8) {
+        if self.movie_active() {
+            self.presses.push(key);
+        } else {
+            self.keyboard.push_keypress(key);
+        }
+    }
+
+    // Starts recording the input of every frame, from the current state
+    pub fn record(&mut self) {
+        self.recording = Some(Movie::new(self.save_state()));
+        self.playback = None;
+        self.keys = self.keyboard.keys();
+        self.presses.clear();
+        self.frame_started = false;
+    }
+
+    pub fn stop_recording(&mut self) -> Option<Movie> {
+        self.recording.take()
+    }
+
+    // Restores the state the movie starts from, its input replaces the one given with set_keys and push_keypress
+    // until it ends
+    pub fn play(&mut self, movie: Movie) -> Result<(), Chip8Error> {
+        self.load_state(movie.state())?;
+        self.recording = None;
+        self.playback = Some((movie, 0));
+        self.presses.clear();
+        self.frame_started = false;
+        Ok(())
+    }
+
+    pub fn playing(&self) -> bool {
+        self.playback.is_some()
+    }
+
+    fn movie_active(&self) -> bool {
+        self.recording.is_some() || self.playback.is_some()
+    }
+
+    fn start_frame(&mut self) {
+        self.cpu.vblank();
+        self.budget += if self.settings.vip_timing {
+            (vip::FRAME_CYCLES - vip::INTERRUPT_CYCLES) as i32
+        } else {
+            // Spread evenly when cpu_freq isn't a multiple of 60
+            let freq = self.settings.cpu_freq as u64;
+            let fps = FRAME_FREQ as u64;
+            (freq * (self.frames + 1) / fps - freq * self.frames / fps) as i32
+        };
+        if let Some((movie, frame)) = &mut self.playback {
+            match movie.frame(*frame) {
+                Some((keys, presses)) => {
+                    self.keyboard.set_keys(keys);
+                    for key in presses {
+                        self.keyboard.push_keypress(*key);
+                    }
+                    *frame += 1;
+                }
+                None => self.playback = None,
+            }
+        } else if let Some(movie) = &mut self.recording {
+            let presses = std::mem::take(&mut self.presses);
+            self.keyboard.set_keys(self.keys);
+            for key in &presses {
+                self.keyboard.push_keypress(*key);
+            }
+            movie.push(self.keys, presses);
+        }
+    }
+
+    // Execute a single instruction, ending the frame when it used up the frame's budget
+    // On error the CPU stays on the failing instruction and the machine state is left as is
+    pub fn step(&mut self) -> Result<Status, Chip8Error> {
+        if !self.frame_started {
+            self.frame_started = true;
+            self.start_frame();
+        }
+
+        self.cpu.cycle(
+            &mut self.memory,
+            &mut self.display,
+            &mut self.keyboard,
+            &mut self.rng,
+            &self.settings
+        )?;
+
+        self.budget -= if self.settings.vip_timing { self.cpu.cycles() as i32 } else { 1 };
+        let frame_done = self.budget <= 0;
+        if frame_done {
+            self.end_frame();
+        }
+
+        Ok(self.status(frame_done))
+    }
+
+    fn status(&mut self, frame_done: bool) -> Status {
+        Status {
+            frame_ready: self.display.take_redraw(),
+            beep: self.cpu.beep() || self.keyboard.beep(),
+            waiting_for_key: self.keyboard.waiting(),
+            halted: self.cpu.halted(),
+            frame_done,
+        }
+    }
+
+    fn end_frame(&mut self) {
+        self.frame_started = false;
+        self.frames += 1;
+        // Only an overrun carries over, a frame cut short by a halt doesn't give the next one more time
+        self.budget = self.budget.min(0);
+
+        let time = self.time();
+        while self.st_clock.tick(time) {
+            self.cpu.cycle_st();
+        }
+        while self.dt_clock.tick(time) {
+            self.cpu.cycle_dt();
+        }
+    }
+
+    // Execute up to n instructions, stopping early if the CPU halts
+    pub fn run_cycles(&mut self, n: usize) -> Result<Status, Chip8Error> {
+        let mut status = Status::default();
+        for _ in 0..n {
+            status = self.merge_step(status)?;
+            if status.halted {
+                break;
+            }
+        }
+        Ok(status)
+    }
+
+    // Execute the rest of the current 60 Hz frame. Once the CPU halts the frame ends without running the instructions
+    // left in it.
+    pub fn run_frame(&mut self) -> Result<Status, Chip8Error> {
+        let mut status = Status::default();
+        while !status.frame_done {
+            status = self.merge_step(status)?;
+            if status.halted && !status.frame_done {
+                self.end_frame();
+                status = Status { frame_done: true, ..status };
+            }
+        }
+        Ok(status)
+    }
+
+    fn merge_step(&mut self, status: Status) -> Result<Status, Chip8Error> {
+        let step = self.step()?;
+        Ok(Status {
+            frame_ready: status.frame_ready || step.frame_ready,
+            ..step
+        })
+    }
+
+    // Registers, timers and stack in a human readable form
+    pub fn cpu_state(&self) -> String {
+        self.cpu.to_string()
+    }
+
+    // Executes a debugger command, e.g. "break 0x2A4" or "step", and returns its output
+    pub fn debug(&mut self, command: &str) -> String {
+        self.debugger.command(command, &self.cpu, &self.memory, self.settings.variant)
+    }
+
+    fn debug_view(&self) -> String {
+        self.debugger.view(&self.cpu, &self.memory, self.settings.variant)
+    }
+
+    // While paused the display is shown as it is, so stepping through a frame shows every change
+    fn draw(&mut self) {
+        self.frontend.clear();
+        if self.filtered() {
+            self.persistence.draw(&mut self.frontend);
+        } else {
+            self.display.draw(&mut self.frontend);
+        }
+        self.frontend.present();
+    }
+
+    fn filtered(&self) -> bool {
+        self.settings.filter != Filter::Off && self.debugger.running()
+    }
+
+    // Runs in real time, one frame every 60th of a second, until the window is closed. Between frames the thread
+    // sleeps, and all the events that came in are handled before the next frame.
     pub fn run(&mut self) {
-        let mut cpu_clock = Clock::new(self.settings.cpu_freq);
-        let mut dt_clock = Clock::new(self.settings.dt_freq);
-        let mut st_clock = Clock::new(self.settings.st_freq);
+        let frame_period = clk::period(FRAME_FREQ);
+        let mut beep = false;
+        let mut audio = None;
+
+        // A state is saved every frame, while the rewind key is held they are restored at the same rate
+        let mut rewind = Rewind::new(self.settings.rewind_seconds as usize * FRAME_FREQ as usize);
+        let mut rewinding = false;
+
+        // While fast-forwarding frames run back to back and the display is only presented at 60 Hz
+        let mut fast_forward = false;
+        let mut next_frame = self.frontend.time();
+        let mut last_present = next_frame;
+        let mut redraw = false;
+
+        // While the remap screen is open execution is paused and key presses bind keys instead
+        let mut remap: Option<Remap> = None;
 
-        let mut rng = RNG::new(self.settings.rng_seed);
+        // With the debugger enabled execution starts paused and commands are read from the terminal on another
+        // thread, so the window keeps rendering while waiting for input
+        let debug = self.settings.debug;
+        let commands = if debug {
+            self.debugger.pause();
+            println!("{}", self.debug_view());
+            prompt();
+            Some(read_commands())
+        } else {
+            None
+        };
+        let mut was_running = self.debugger.running();
+        self.persistence.reset(&self.display);
 
         let mut done = false;
         while !done {
-            match self.platform.poll_event() {
-                PlatformEvent::KeyPress(key) => {
-                    self.keyboard.push_keypress(key);
+            if let Some(commands) = &commands {
+                while let Ok(command) = commands.try_recv() {
+                    print!("{}", self.debug(&command));
+                    if self.debugger.quit() {
+                        done = true;
+                    }
+                    prompt();
                 }
-                PlatformEvent::Quit => {
-                    done = true;
+            }
+
+            loop {
+                match self.frontend.poll_event() {
+                    FrontendEvent::None => break,
+                    FrontendEvent::KeyPress(key) => {
+                        self.push_keypress(key);
+                    }
+                    FrontendEvent::SaveState(slot) => {
+                        match self.save_state_slot(slot) {
+                            Ok(path) => println!("State saved to {}", path.display()),
+                            Err(error) => eprintln!("error: can't save state {}: {}", slot, error),
+                        }
+                    }
+                    // Loading a state would make the movie impossible to play back
+                    FrontendEvent::LoadState(_) | FrontendEvent::Rewind(true) if self.movie_active() => {
+                        eprintln!("error: can't load states while a movie is recorded or played");
+                    }
+                    FrontendEvent::LoadState(slot) => {
+                        match self.load_state_slot(slot) {
+                            Ok(path) => {
+                                println!("State loaded from {}", path.display());
+                                // The history was made with the settings from before the state
+                                rewind = Rewind::new(self.settings.rewind_seconds as usize * FRAME_FREQ as usize);
+                                // A state loaded after an error makes execution possible again
+                                if !debug {
+                                    self.debugger.resume();
+                                }
+                                redraw = true;
+                            }
+                            Err(error) => eprintln!("error: can't load state {}: {}", slot, error),
+                        }
+                    }
+                    FrontendEvent::Rewind(pressed) => {
+                        if pressed && !rewinding {
+                            beep = false;
+                            self.frontend.beep(false);
+                        }
+                        rewinding = pressed;
+                    }
+                    FrontendEvent::FastForward(pressed) => {
+                        fast_forward = pressed;
+                    }
+                    FrontendEvent::NextPalette => {
+                        let (name, palette) = self.settings.palette.next();
+                        println!("Palette: {}", name);
+                        self.settings.palette = palette;
+                        self.frontend.set_palette(&palette);
+                        redraw = true;
+                    }
+                    FrontendEvent::Remap => {
+                        if remap.take().is_some() {
+                            println!("Remapping cancelled");
+                            redraw = true;
+                        } else {
+                            println!("Press a key for each highlighted CHIP-8 key");
+                            remap = Some(Remap::new(&self.settings.keymap));
+                            beep = false;
+                            self.frontend.beep(false);
+                        }
+                        self.frontend.capture_keys(remap.is_some());
+                    }
+                    FrontendEvent::KeyName(name) => {
+                        let keymap = remap.as_mut().and_then(|remap| remap.bind(&name));
+                        if let Some(keymap) = keymap {
+                            remap = None;
+                            self.frontend.capture_keys(false);
+                            match self.save_keymap(keymap) {
+                                Ok(path) => println!("Keymap saved to {}", path.display()),
+                                Err(error) => eprintln!("error: can't save keymap: {}", error),
+                            }
+                            redraw = true;
+                        }
+                    }
+                    FrontendEvent::Quit => {
+                        done = true;
+                    }
                 }
-                PlatformEvent::None => {
-                    if st_clock.tick() {
-                        let beep = self.cpu.cycle_st();
-                        self.platform.beep(beep && !self.settings.mute);
+            }
+
+            // Execution is paused by the debugger or after an error
+            let running = self.debugger.running();
+            if !running && was_running {
+                beep = false;
+                self.frontend.beep(false);
+            }
+            // The filter starts over from the display as it was left when paused
+            if running != was_running && self.settings.filter != Filter::Off {
+                self.persistence.reset(&self.display);
+                redraw = true;
+            }
+            was_running = running;
+
+            if let Some(remap) = &remap {
+                remap.draw(&mut self.frontend);
+            } else if rewinding {
+                if let Some(state) = rewind.pop() {
+                    self.load_machine_state(&state).expect("Rewind states are made by machine_state");
+                    if !debug {
+                        self.debugger.resume();
                     }
+                    self.display.take_redraw();
+                    redraw = true;
+                }
+            } else if running {
+                let keys = self.frontend.keyboard_state();
+                self.set_keys(keys);
 
-                    if dt_clock.tick() {
-                        self.cpu.cycle_dt();
+                let mut output = String::new();
+                let (frame_ready, status) = self.run_frame_debugged(debug, &mut output);
+                if !output.is_empty() {
+                    print!("{}", output);
+                    if debug {
+                        prompt();
                     }
+                }
+                if !self.filtered() {
+                    redraw |= frame_ready;
+                }
 
-                    if cpu_clock.tick() {
-                        self.keyboard.set_keys(self.platform.keyboard_state());
+                if let Some(status) = status {
+                    let current_audio = self.cpu.audio();
+                    if audio != Some(current_audio) {
+                        audio = Some(current_audio);
+                        self.frontend.set_audio(current_audio.0, current_audio.1);
+                    }
 
-                        self.cpu.cycle(
-                            &mut self.memory,
-                            &mut self.display,
-                            &mut self.keyboard,
-                            &mut rng,
-                            &self.settings
-                        );
+                    if status.beep != beep {
+                        beep = status.beep;
+                        self.frontend.beep(beep && !self.settings.mute);
+                    }
 
-                        if self.display.redraw() {
-                            self.platform.clear();
-                            self.display.draw(&mut self.platform);
-                            self.platform.present();
+                    if status.frame_done {
+                        rewind.push(self.machine_state());
+                        // The filter shows the display once a frame, not every time it changes
+                        if self.filtered() {
+                            let (filter, decay_frames) = (self.settings.filter, self.settings.decay_frames);
+                            redraw |= self.persistence.update(&self.display, filter, decay_frames);
                         }
                     }
                 }
             }
+
+            let now = self.frontend.time();
+            if redraw && remap.is_none() && (!fast_forward || now >= last_present + frame_period) {
+                self.draw();
+                redraw = false;
+                last_present = now;
+            }
+
+            if fast_forward {
+                next_frame = now;
+            } else {
+                next_frame += frame_period;
+                let now = self.frontend.time();
+                if next_frame > now {
+                    self.frontend.sleep(next_frame - now);
+                } else if now - next_frame > frame_period * 4 {
+                    // Too far behind, e.g. after the window was moved, don't try to catch up
+                    next_frame = now;
+                }
+            }
+        }
+    }
+
+    // Runs the rest of the frame, or until the debugger pauses execution. Returns whether the display changed and
+    // the status of the last instruction, if one ran. What the debugger reports is added to output, errors are
+    // printed right away.
+    fn run_frame_debugged(&mut self, debug: bool, output: &mut String) -> (bool, Option<Status>) {
+        let mut frame_ready = false;
+        let mut last = None;
+        while self.debugger.running() {
+            if let Some(reason) = self.debugger.check_breakpoints(&self.cpu) {
+                *output += &format!("\n{}\n{}\n", reason, self.debug_view());
+                break;
+            }
+
+            let playing = self.playing();
+            let pc = self.cpu.pc();
+            self.memory.set_tracing(self.debugger.has_watchpoints());
+
+            let mut status = match self.step() {
+                Ok(status) => status,
+                Err(error) => {
+                    self.memory.take_accesses();
+                    self.debugger.pause();
+                    eprintln!("\nerror: {}", error);
+                    if debug {
+                        *output += &format!("{}\n", self.debug_view());
+                    } else {
+                        eprintln!("{}", self.cpu_state());
+                        eprintln!("Execution paused, close the window to quit");
+                    }
+                    break;
+                }
+            };
+
+            if playing && !self.playing() {
+                *output += "Movie finished, input is live again\n";
+            }
+
+            if let Some(report) = self.debugger.check_watchpoints(pc, &self.memory.take_accesses()) {
+                *output += &format!("\n{}", report);
+                if !self.debugger.running() {
+                    *output += &format!("{}\n", self.debug_view());
+                }
+            }
+
+            if self.debugger.after_step(&self.cpu) {
+                *output += &format!("{}\n", self.debug_view());
+            }
+
+            // A halted CPU makes no progress, the rest of the frame only has to pass
+            if status.halted && !status.frame_done {
+                self.end_frame();
+                status.frame_done = true;
+            }
+
+            frame_ready |= status.frame_ready;
+            last = Some(status);
+            if status.frame_done {
+                break;
+            }
         }
+        (frame_ready, last)
     }
 }
+
+// What save states hold besides the settings, read completely before any of it replaces the running machine
+struct MachineState {
+    frame_started: bool,
+    frames: u64,
+    budget: i32,
+    dt_clock: Clock,
+    st_clock: Clock,
+    cpu: CPU,
+    memory: Memory,
+    display: Display,
+    keyboard: Keyboard,
+    rng: RNG,
+}
+
+impl MachineState {
+    fn load(r: &mut Reader, settings: &Settings) -> Result<Self, Chip8Error> {
+        Ok(Self {
+            frame_started: r.bool()?,
+            frames: r.u64()?,
+            budget: r.u32()? as i32,
+            dt_clock: Clock::load(r, settings.dt_freq)?,
+            st_clock: Clock::load(r, settings.st_freq)?,
+            cpu: CPU::load(r)?,
+            memory: Memory::load(r, settings.variant.memory_size())?,
+            display: Display::load(r)?,
+            keyboard: Keyboard::load(r)?,
+            rng: RNG::load(r)?,
+        })
+    }
+}
+
+// Settings for the ROM only are stored next to it as <ROM>.toml
+pub fn rom_config_path(rom: &Path) -> PathBuf {
+    let mut path = rom.to_path_buf().into_os_string();
+    path.push(".toml");
+    PathBuf::from(path)
+}
+
+fn prompt() {
+    print!("(chip8) ");
+    io::stdout().flush().ok();
+}
+
+// Lines typed on the terminal, the thread ends when stdin is closed
+fn read_commands() -> mpsc::Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            match line {
+                Ok(line) => if sender.send(line).is_err() { break },
+                Err(_) => break,
+            }
+        }
+    });
+    receiver
+}
@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use super::op::Op;
+use super::set::Settings;
+
+// Includes nested deeper than this are most likely a file including itself
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+pub struct AsmError {
+    // Empty for source given as a string
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.file.is_empty() {
+            write!(f, "{}:", self.file)?;
+        }
+        // Line 0 is for errors about the whole file, e.g. when it can't be read
+        if self.line > 0 {
+            write!(f, "{}:{}:", self.line, self.column)?;
+        }
+        write!(f, " {}", self.message)
+    }
+}
+
+impl fmt::Debug for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Error for AsmError {}
+
+#[derive(Clone)]
+struct Location {
+    file: String,
+    line: usize,
+}
+
+impl Location {
+    fn error(&self, column: usize, message: String) -> AsmError {
+        AsmError { file: self.file.clone(), line: self.line, column, message }
+    }
+}
+
+#[derive(Clone)]
+struct Token {
+    text: String,
+    column: usize,
+}
+
+enum Kind {
+    Op { name: Token, operands: Vec<Token> },
+    Bytes(Vec<Token>),
+    Words(Vec<Token>),
+}
+
+// An instruction or data directive with the address it's assembled at
+struct Item {
+    location: Location,
+    addr: u16,
+    kind: Kind,
+}
+
+enum Symbol {
+    Label(u16),
+    Constant(Token, Location),
+}
+
+struct Assembler {
+    rom_addr: u16,
+    addr: u32,
+    items: Vec<Item>,
+    symbols: HashMap<String, Symbol>,
+}
+
+// Assembles source using the mnemonics Op prints, includes are relative to the current directory
+pub fn assemble(source: &str, settings: &Settings) -> Result<Vec<u8>, AsmError> {
+    let mut assembler = Assembler::new(settings.rom_addr);
+    assembler.parse(source, "", Path::new(""), 0)?;
+    assembler.generate()
+}
+
+// Assembles a file, includes are relative to the file including them
+pub fn assemble_file<P: AsRef<Path>>(path: P, settings: &Settings) -> Result<Vec<u8>, AsmError> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|e| AsmError {
+        file: path.display().to_string(), line: 0, column: 0, message: e.to_string(),
+    })?;
+    let mut assembler = Assembler::new(settings.rom_addr);
+    assembler.parse(&source, &path.display().to_string(), path.parent().unwrap_or_else(|| Path::new("")), 0)?;
+    assembler.generate()
+}
+
+impl Assembler {
+    fn new(rom_addr: u16) -> Self {
+        Self {
+            rom_addr,
+            addr: rom_addr as u32,
+            items: Vec::new(),
+            symbols: HashMap::new(),
+        }
+    }
+
+    // First pass, collects the items and the addresses of the labels
+    fn parse(&mut self, source: &str, file: &str, dir: &Path, depth: usize) -> Result<(), AsmError> {
+        for (i, line) in source.lines().enumerate() {
+            let location = Location { file: file.to_string(), line: i + 1 };
+            let mut tokens = tokenize(strip_comment(line));
+
+            // Any number of labels
+            while let Some(label) = tokens.first().and_then(|token| token.text.strip_suffix(':')) {
+                let label = label.to_string();
+                let column = tokens[0].column;
+                self.define(&label, Symbol::Label(self.addr as u16), &location, column)?;
+                tokens.remove(0);
+            }
+
+            let (first, rest) = match tokens.split_first() {
+                Some((first, rest)) => (first, rest),
+                None => continue,
+            };
+
+            // NAME = VALUE
+            if rest.first().map(|token| token.text.as_str()) == Some("=") {
+                match &rest[1..] {
+                    [value] => self.define(&first.text, Symbol::Constant(value.clone(), location.clone()), &location, first.column)?,
+                    _ => return Err(location.error(first.column, "expected a single value after '='".to_string())),
+                }
+                continue;
+            }
+
+            let (kind, size) = match first.text.to_lowercase().as_str() {
+                "include" => {
+                    let name = strip_comment(line)[first.column - 1 + first.text.len()..].trim().trim_matches('"');
+                    self.include(name, dir, &location, first.column, depth)?;
+                    continue;
+                }
+                "db" => (Kind::Bytes(rest.to_vec()), rest.len() as u32),
+                "dw" => (Kind::Words(rest.to_vec()), rest.len() as u32 * 2),
+                name => {
+                    let size = if name == "ldl" { 4 } else { 2 };
+                    (Kind::Op { name: first.clone(), operands: rest.to_vec() }, size)
+                }
+            };
+            if self.addr + size > 0x10000 {
+                return Err(location.error(first.column, "program doesn't fit in 64 KiB".to_string()));
+            }
+            self.items.push(Item { location, addr: self.addr as u16, kind });
+            self.addr += size;
+        }
+        Ok(())
+    }
+
+    fn include(&mut self, name: &str, dir: &Path, location: &Location, column: usize, depth: usize) -> Result<(), AsmError> {
+        if name.is_empty() {
+            return Err(location.error(column, "expected a file name after include".to_string()));
+        }
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(location.error(column, format!("includes nested more than {} deep", MAX_INCLUDE_DEPTH)));
+        }
+        let path: PathBuf = dir.join(name);
+        let source = fs::read_to_string(&path)
+            .map_err(|e| location.error(column, format!("can't include {}: {}", path.display(), e)))?;
+        self.parse(&source, &path.display().to_string(), path.parent().unwrap_or_else(|| Path::new("")), depth + 1)
+    }
+
+    fn define(&mut self, name: &str, symbol: Symbol, location: &Location, column: usize) -> Result<(), AsmError> {
+        let valid = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(location.error(column, format!("'{}' is not a valid name", name)));
+        }
+        if self.symbols.insert(name.to_string(), symbol).is_some() {
+            return Err(location.error(column, format!("'{}' is already defined", name)));
+        }
+        Ok(())
+    }
+
+    // Second pass, now that every label is known
+    fn generate(&self) -> Result<Vec<u8>, AsmError> {
+        let mut rom = Vec::new();
+        for item in &self.items {
+            debug_assert_eq!(item.addr as usize, self.rom_addr as usize + rom.len());
+            let location = &item.location;
+            match &item.kind {
+                Kind::Op { name, operands } => {
+                    let op = self.op(name, operands, location)?;
+                    rom.extend_from_slice(&op.encode().to_be_bytes());
+                    if let Op::LDL { addr } = op {
+                        rom.extend_from_slice(&addr.to_be_bytes());
+                    }
+                }
+                Kind::Bytes(values) => {
+                    for value in values {
+                        rom.push(self.value(value, location, 0xFF)? as u8);
+                    }
+                }
+                Kind::Words(values) => {
+                    for value in values {
+                        rom.extend_from_slice(&(self.value(value, location, 0xFFFF)? as u16).to_be_bytes());
+                    }
+                }
+            }
+        }
+        Ok(rom)
+    }
+
+    fn op(&self, name: &Token, operands: &[Token], location: &Location) -> Result<Op, AsmError> {
+        let expect = |count: usize| {
+            if operands.len() == count {
+                Ok(())
+            } else {
+                Err(location.error(name.column, format!("{} takes {} operand{}, got {}",
+                    name.text.to_uppercase(), count, if count == 1 { "" } else { "s" }, operands.len())))
+            }
+        };
+        let reg = |i: usize| register(&operands[i], location);
+        let byte = |i: usize| self.value(&operands[i], location, 0xFF).map(|value| value as u8);
+        let addr = |i: usize| self.value(&operands[i], location, 0xFFF).map(|value| value as u16);
+        // Op prints nibbles as a single hex digit
+        let nibble = |i: usize| match u8::from_str_radix(&operands[i].text, 16) {
+            Ok(value) if operands[i].text.len() == 1 => Ok(value),
+            _ => self.value(&operands[i], location, 0xF).map(|value| value as u8),
+        };
+
+        let op = match name.text.to_uppercase().as_str() {
+            "CLS"  => { expect(0)?; Op::CLS }
+            "RET"  => { expect(0)?; Op::RET }
+            "SCR"  => { expect(0)?; Op::SCR }
+            "SCL"  => { expect(0)?; Op::SCL }
+            "EXIT" => { expect(0)?; Op::EXIT }
+            "LOW"  => { expect(0)?; Op::LOW }
+            "HIGH" => { expect(0)?; Op::HIGH }
+            "LDAU" => { expect(0)?; Op::LDAU }
+            "SCD"  => { expect(1)?; Op::SCD { nibble: nibble(0)? } }
+            "SCU"  => { expect(1)?; Op::SCU { nibble: nibble(0)? } }
+            "PLN"  => { expect(1)?; Op::PLN { nibble: nibble(0)? } }
+            "SYS"  => { expect(1)?; Op::SYS { addr: addr(0)? } }
+            "JP"   => { expect(1)?; Op::JP { addr: addr(0)? } }
+            "CALL" => { expect(1)?; Op::CALL { addr: addr(0)? } }
+            "LDA"  => { expect(1)?; Op::LDA { addr: addr(0)? } }
+            "JPO"  => { expect(1)?; Op::JPO { addr: addr(0)? } }
+            "LDL"  => { expect(1)?; Op::LDL { addr: self.value(&operands[0], location, 0xFFFF)? as u16 } }
+            "SKP"  => { expect(1)?; Op::SKP { reg: reg(0)? } }
+            "SKNP" => { expect(1)?; Op::SKNP { reg: reg(0)? } }
+            "LDDT" => { expect(1)?; Op::LDDT { reg: reg(0)? } }
+            "LDKP" => { expect(1)?; Op::LDKP { reg: reg(0)? } }
+            "STDT" => { expect(1)?; Op::STDT { reg: reg(0)? } }
+            "STST" => { expect(1)?; Op::STST { reg: reg(0)? } }
+            "ADDA" => { expect(1)?; Op::ADDA { reg: reg(0)? } }
+            "LDSA" => { expect(1)?; Op::LDSA { reg: reg(0)? } }
+            "LDHF" => { expect(1)?; Op::LDHF { reg: reg(0)? } }
+            "PTCH" => { expect(1)?; Op::PTCH { reg: reg(0)? } }
+            "STDR" => { expect(1)?; Op::STDR { reg: reg(0)? } }
+            "STRR" => { expect(1)?; Op::STRR { reg: reg(0)? } }
+            "LDRR" => { expect(1)?; Op::LDRR { reg: reg(0)? } }
+            "STRP" => { expect(1)?; Op::STRP { reg: reg(0)? } }
+            "LDRP" => { expect(1)?; Op::LDRP { reg: reg(0)? } }
+            "SE"   => { expect(2)?; Op::SE { reg: reg(0)?, byte: byte(1)? } }
+            "SNE"  => { expect(2)?; Op::SNE { reg: reg(0)?, byte: byte(1)? } }
+            "LD"   => { expect(2)?; Op::LD { reg: reg(0)?, byte: byte(1)? } }
+            "ADD"  => { expect(2)?; Op::ADD { reg: reg(0)?, byte: byte(1)? } }
+            "RND"  => { expect(2)?; Op::RND { reg: reg(0)?, byte: byte(1)? } }
+            "SER"  => { expect(2)?; Op::SER { reg_a: reg(0)?, reg_b: reg(1)? } }
+            "STRG" => { expect(2)?; Op::STRG { reg_a: reg(0)?, reg_b: reg(1)? } }
+            "LDRG" => { expect(2)?; Op::LDRG { reg_a: reg(0)?, reg_b: reg(1)? } }
+            "LDR"  => { expect(2)?; Op::LDR { reg_a: reg(0)?, reg_b: reg(1)? } }
+            "OR"   => { expect(2)?; Op::OR { reg_a: reg(0)?, reg_b: reg(1)? } }
+            "AND"  => { expect(2)?; Op::AND { reg_a: reg(0)?, reg_b: reg(1)? } }
+            "XOR"  => { expect(2)?; Op::XOR { reg_a: reg(0)?, reg_b: reg(1)? } }
+            "ADDR" => { expect(2)?; Op::ADDR { reg_a: reg(0)?, reg_b: reg(1)? } }
+            "SUB"  => { expect(2)?; Op::SUB { reg_a: reg(0)?, reg_b: reg(1)? } }
+            "SHR"  => { expect(2)?; Op::SHR { reg_a: reg(0)?, reg_b: reg(1)? } }
+            "SUBN" => { expect(2)?; Op::SUBN { reg_a: reg(0)?, reg_b: reg(1)? } }
+            "SHL"  => { expect(2)?; Op::SHL { reg_a: reg(0)?, reg_b: reg(1)? } }
+            "SNER" => { expect(2)?; Op::SNER { reg_a: reg(0)?, reg_b: reg(1)? } }
+            "DRW"  => { expect(3)?; Op::DRW { reg_a: reg(0)?, reg_b: reg(1)?, nibble: nibble(2)? } }
+            "INV"  => { expect(1)?; Op::INV { opcode: self.value(&operands[0], location, 0xFFFF)? as u16 } }
+            _ => return Err(location.error(name.column, format!("unknown instruction '{}'", name.text))),
+        };
+        Ok(op)
+    }
+
+    // Evaluates a value made of numbers, labels and constants added or subtracted, e.g. sprites+5
+    fn value(&self, token: &Token, location: &Location, max: i64) -> Result<i64, AsmError> {
+        let value = self.evaluate(token, location, 0)?;
+        if value < 0 || value > max {
+            return Err(location.error(token.column, format!("{} is out of range, expected 0 to {:#X}", value, max)));
+        }
+        Ok(value)
+    }
+
+    fn evaluate(&self, token: &Token, location: &Location, depth: usize) -> Result<i64, AsmError> {
+        let text = &token.text;
+        let mut total = 0;
+        let mut start = 0;
+        let mut sign = 1;
+        for (i, c) in text.char_indices().chain(std::iter::once((text.len(), '+'))) {
+            if (c == '+' || c == '-') && i > start {
+                let term = &text[start..i];
+                total += sign * self.term(term, token.column + start, location, depth)?;
+                sign = if c == '+' { 1 } else { -1 };
+                start = i + 1;
+            } else if c == '+' || c == '-' {
+                return Err(location.error(token.column + i, format!("expected a value in '{}'", text)));
+            }
+        }
+        Ok(total)
+    }
+
+    fn term(&self, term: &str, column: usize, location: &Location, depth: usize) -> Result<i64, AsmError> {
+        if term.starts_with(|c: char| c.is_ascii_digit()) {
+            return parse_number(term).ok_or_else(|| location.error(column, format!("'{}' is not a number", term)));
+        }
+        match self.symbols.get(term) {
+            Some(Symbol::Label(addr)) => Ok(*addr as i64),
+            Some(Symbol::Constant(value, constant_location)) => {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(location.error(column, format!("'{}' is defined in terms of itself", term)));
+                }
+                self.evaluate(value, constant_location, depth + 1)
+            }
+            None => Err(location.error(column, format!("'{}' is not defined", term))),
+        }
+    }
+}
+
+fn register(token: &Token, location: &Location) -> Result<u8, AsmError> {
+    let text = token.text.to_uppercase();
+    match text.strip_prefix('V').and_then(|reg| u8::from_str_radix(reg, 16).ok()) {
+        Some(reg) if text.len() == 2 => Ok(reg),
+        _ => Err(location.error(token.column, format!("expected a register V0 to VF, got '{}'", token.text))),
+    }
+}
+
+// Decimal, hexadecimal with 0x or binary with 0b
+pub fn parse_number(text: &str) -> Option<i64> {
+    let lower = text.to_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.find(';') {
+        Some(i) => &line[..i],
+        None => line,
+    }
+}
+
+// Operands are separated by spaces or commas, columns start at 1
+fn tokenize(line: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (i, c) in line.char_indices().chain(std::iter::once((line.len(), ' '))) {
+        let separator = c.is_whitespace() || c == ',';
+        match (start, separator) {
+            (None, false) => start = Some(i),
+            (Some(s), true) => {
+                tokens.push(Token { text: line[s..i].to_string(), column: s + 1 });
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn assemble_ok(source: &str) -> Vec<u8> {
+        assemble(source, &Settings::new()).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    // Line, column and message
+    fn error(source: &str) -> (usize, usize, String) {
+        let error = assemble(source, &Settings::new()).expect_err("the source assembles");
+        (error.line, error.column, error.message)
+    }
+
+    #[test]
+    fn labels_can_be_used_before_they_are_defined() {
+        assert_eq!(assemble_ok("    JP end\n    CLS\nend: RET"), [0x12, 0x04, 0x00, 0xE0, 0x00, 0xEE]);
+        assert_eq!(assemble_ok("a: b: CALL b"), [0x22, 0x00]);
+    }
+
+    #[test]
+    fn operands_as_op_prints_them() {
+        let source = "LD V1, 0x2A\nDRW V0 V1 A\nADDR va, vB\nscd f";
+        assert_eq!(assemble_ok(source), [0x61, 0x2A, 0xD0, 0x1A, 0x8A, 0xB4, 0x00, 0xCF]);
+    }
+
+    #[test]
+    fn constants() {
+        let source = "SPEED = 4\nNEXT = start+2\nstart: LD V1, SPEED\n    LDA NEXT-1";
+        assert_eq!(assemble_ok(source), [0x61, 0x04, 0xA2, 0x01]);
+        assert_eq!(error("X = 1 2"), (1, 1, "expected a single value after '='".to_string()));
+    }
+
+    #[test]
+    fn constants_defined_in_terms_of_themselves() {
+        // Reported where the loop is closed
+        assert_eq!(error("A = B\nB = A+1\nLD V0, A"), (2, 5, "'A' is defined in terms of itself".to_string()));
+    }
+
+    #[test]
+    fn data() {
+        let source = "db 1, 0xFF, 0b101\ndw 0x1234 end\nend:";
+        assert_eq!(assemble_ok(source), [0x01, 0xFF, 0x05, 0x12, 0x34, 0x02, 0x07]);
+        assert_eq!(error("db 1 256"), (1, 6, "256 is out of range, expected 0 to 0xFF".to_string()));
+        assert_eq!(error("dw 0x10000"), (1, 4, "65536 is out of range, expected 0 to 0xFFFF".to_string()));
+        assert_eq!(error("db 0-1"), (1, 4, "-1 is out of range, expected 0 to 0xFF".to_string()));
+    }
+
+    #[test]
+    fn ldl_is_4_bytes_long() {
+        assert_eq!(assemble_ok("LDL data\ndata: db 7"), [0xF0, 0x00, 0x02, 0x04, 0x07]);
+        assert_eq!(assemble_ok("LDL 0xFFFF"), [0xF0, 0x00, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn errors_point_at_the_token() {
+        assert_eq!(error("CLS\n  LD VG, 1"), (2, 6, "expected a register V0 to VF, got 'VG'".to_string()));
+        assert_eq!(error("CLS\n  LD V0, VG"), (2, 10, "'VG' is not defined".to_string()));
+        assert_eq!(error("  FOO"), (1, 3, "unknown instruction 'FOO'".to_string()));
+        assert_eq!(error("JP nowhere"), (1, 4, "'nowhere' is not defined".to_string()));
+        assert_eq!(error("JP start+nowhere\nstart:"), (1, 10, "'nowhere' is not defined".to_string()));
+        assert_eq!(error("CLS V0"), (1, 1, "CLS takes 0 operands, got 1".to_string()));
+        assert_eq!(error("a: a:"), (1, 4, "'a' is already defined".to_string()));
+        assert_eq!(error("JP 0x1000"), (1, 4, "4096 is out of range, expected 0 to 0xFFF".to_string()));
+        let error = assemble("\n\n  CLS V0", &Settings::new()).unwrap_err();
+        assert_eq!(error.to_string(), "3:3: CLS takes 0 operands, got 1");
+    }
+
+    #[test]
+    fn includes_are_relative_to_the_including_file() {
+        let dir = env::temp_dir().join(format!("chip8-asm-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("main.asm"), "include lib/sprites.asm\nLDA sprite").unwrap();
+        fs::write(dir.join("lib").join("sprites.asm"), "include \"data.asm\"").unwrap();
+        fs::write(dir.join("lib").join("data.asm"), "sprite: db 0x3C").unwrap();
+        fs::write(dir.join("self.asm"), "CLS\ninclude self.asm").unwrap();
+
+        let rom = assemble_file(dir.join("main.asm"), &Settings::new());
+        let error = assemble_file(dir.join("self.asm"), &Settings::new()).expect_err("includes forever");
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(rom.unwrap(), [0x3C, 0xA2, 0x00]);
+        assert_eq!((error.line, error.column), (2, 1));
+        assert_eq!(error.message, format!("includes nested more than {} deep", MAX_INCLUDE_DEPTH));
+    }
+}
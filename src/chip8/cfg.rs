@@ -0,0 +1,101 @@
+// Configuration files use a small subset of TOML:
+//
+//   # comment
+//   key = value
+//   [section]
+//   key = "string value"
+//
+// Values are kept as strings, interpreting them is up to the caller.
+
+pub struct Entry {
+    pub line: usize,
+    pub key: String,
+    pub value: String,
+}
+
+pub struct Section {
+    pub line: usize,
+    // None for the entries before the first [section] header
+    pub name: Option<String>,
+    pub entries: Vec<Entry>,
+}
+
+pub fn parse(text: &str) -> Result<Vec<Section>, String> {
+    let mut sections = vec![Section { line: 0, name: None, entries: Vec::new() }];
+
+    for (i, line) in text.lines().enumerate() {
+        let line_number = i + 1;
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            if !line.ends_with(']') {
+                return Err(format!("line {}: expected ']'", line_number));
+            }
+            let name = unquote(line[1..line.len() - 1].trim());
+            if name.is_empty() {
+                return Err(format!("line {}: empty section name", line_number));
+            }
+            sections.push(Section { line: line_number, name: Some(name), entries: Vec::new() });
+            continue;
+        }
+
+        let (key, value) = match line.find('=') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            None => return Err(format!("line {}: expected 'key = value'", line_number)),
+        };
+        if key.is_empty() {
+            return Err(format!("line {}: missing key", line_number));
+        }
+        if value.is_empty() {
+            return Err(format!("line {}: missing value for '{}'", line_number, key));
+        }
+
+        let section = sections.last_mut().unwrap();
+        section.entries.push(Entry { line: line_number, key: key.to_string(), value: unquote(value) });
+    }
+
+    Ok(sections)
+}
+
+// Sets values in the text of a file without sections, keeping the rest of it. The lines setting any of the keys are
+// removed and the new values added at the end, so they also take precedence over other lines setting them.
+pub fn set_values(text: &str, values: &[(&str, String)]) -> String {
+    let mut result = String::new();
+    for line in text.lines() {
+        let entry = strip_comment(line);
+        let key = entry.find('=').map(|i| entry[..i].trim());
+        if key.is_some_and(|key| values.iter().any(|(k, _)| *k == key)) {
+            continue;
+        }
+        result += line;
+        result += "\n";
+    }
+    for (key, value) in values {
+        result += &format!("{} = \"{}\"\n", key, value);
+    }
+    result
+}
+
+// A '#' starts a comment unless it's inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn unquote(value: &str) -> String {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        value[1..value.len() - 1].to_string()
+    } else {
+        value.to_string()
+    }
+}
@@ -1,24 +1,41 @@
-use std::time::{Duration, Instant};
+use std::time::Duration;
+use super::err::Chip8Error;
+use super::sav::{Reader, Writer};
+
+pub fn period(freq: u16) -> Duration {
+    Duration::from_nanos(1_000_000_000 / freq as u64)
+}
 
 pub struct Clock {
     period: Duration,
-    offset: Instant,
+    offset: Duration,
 }
 
 impl Clock {
-    pub fn new(freq: u16) -> Self {
+    pub fn new(freq: u16, now: Duration) -> Self {
         Self {
-            period: Duration::from_nanos(1_000_000_000 / freq as u64),
-            offset: Instant::now()
+            period: period(freq),
+            offset: now
         }
     }
 
-    pub fn tick(&mut self) -> bool {
-        if self.offset.elapsed() >= self.period {
+    pub fn save(&self, w: &mut Writer) {
+        w.u64(self.offset.as_nanos() as u64);
+    }
+
+    pub fn load(r: &mut Reader, freq: u16) -> Result<Self, Chip8Error> {
+        Ok(Self {
+            period: period(freq),
+            offset: Duration::from_nanos(r.u64()?),
+        })
+    }
+
+    pub fn tick(&mut self, now: Duration) -> bool {
+        if now >= self.offset + self.period {
             self.offset += self.period;
             true
         } else {
             false
         }
     }
-}
\ No newline at end of file
+}
@@ -1,11 +1,23 @@
+use std::fmt;
+use super::err::Chip8Error;
 use super::rng::RNG;
+use super::sav::{self, Reader, Writer};
 use super::mem::Memory;
-use super::dsp::Display;
+use super::dsp::{Display, PLANES};
 use super::kbd::Keyboard;
 use super::op::Op;
-use super::set::Settings;
+use super::set::{Settings, Variant};
+use super::vip;
 
 const STACK_SIZE: usize = 16;
+const RPL_SIZE: usize = 16;
+
+// XO-CHIP audio, the pattern is played 1 bit per sample at 4000 * 2^((pitch - 64) / 48) samples per second.
+// Until a ROM loads its own pattern this plays a 250 Hz square wave.
+pub const DEFAULT_AUDIO_PATTERN: [u8; 16] = [
+    0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00,
+];
+pub const DEFAULT_PITCH: u8 = 64;
 
 pub struct CPU {
     v: [u8; 16],
@@ -15,6 +27,15 @@ pub struct CPU {
     stack: [u16; STACK_SIZE],
     dt: u8,
     st: u8,
+    // SCHIP flags saved by STRP (Fx75), named after the HP-48 RPL user flags they were stored in
+    rpl: [u8; RPL_SIZE],
+    audio_pattern: [u8; 16],
+    pitch: u8,
+    halted: bool,
+    // No sprite was drawn since the frame started, for display_wait_quirk
+    vblank: bool,
+    // COSMAC VIP machine cycles taken by the last instruction
+    cycles: u32,
 }
 
 impl CPU {
@@ -26,10 +47,21 @@ impl CPU {
             sp: 0,
             stack: [0; STACK_SIZE],
             dt: 0,
-            st: 0
+            st: 0,
+            rpl: [0; RPL_SIZE],
+            audio_pattern: DEFAULT_AUDIO_PATTERN,
+            pitch: DEFAULT_PITCH,
+            halted: false,
+            vblank: true,
+            cycles: 0,
         }
     }
 
+    // Called at the start of every frame
+    pub fn vblank(&mut self) {
+        self.vblank = true;
+    }
+
     pub fn cycle_dt(&mut self) {
         if self.dt > 0 {
             self.dt -= 1;
@@ -43,6 +75,79 @@ impl CPU {
         self.st > 0
     }
 
+    pub fn beep(&self) -> bool {
+        self.st > 0
+    }
+
+    pub fn audio(&self) -> ([u8; 16], u8) {
+        (self.audio_pattern, self.pitch)
+    }
+
+    pub fn save(&self, w: &mut Writer) {
+        w.bytes(&self.v);
+        w.u16(self.i);
+        w.u16(self.pc);
+        w.u8(self.sp);
+        for addr in &self.stack {
+            w.u16(*addr);
+        }
+        w.u8(self.dt);
+        w.u8(self.st);
+        w.bytes(&self.rpl);
+        w.bytes(&self.audio_pattern);
+        w.u8(self.pitch);
+        w.bool(self.halted);
+        w.bool(self.vblank);
+    }
+
+    pub fn load(r: &mut Reader) -> Result<Self, Chip8Error> {
+        let mut cpu = Self::new();
+        cpu.v.copy_from_slice(r.bytes(16)?);
+        cpu.i = r.u16()?;
+        cpu.pc = r.u16()?;
+        cpu.sp = r.u8()?;
+        if cpu.sp as usize > STACK_SIZE {
+            return Err(sav::invalid(format!("stack pointer {} is out of range", cpu.sp)));
+        }
+        for addr in cpu.stack.iter_mut() {
+            *addr = r.u16()?;
+        }
+        cpu.dt = r.u8()?;
+        cpu.st = r.u8()?;
+        cpu.rpl.copy_from_slice(r.bytes(RPL_SIZE)?);
+        cpu.audio_pattern.copy_from_slice(r.bytes(16)?);
+        cpu.pitch = r.u8()?;
+        cpu.halted = r.bool()?;
+        cpu.vblank = r.bool()?;
+        Ok(cpu)
+    }
+
+    // The last instruction was a jump to itself, a common way for programs to stop, or EXIT (00FD)
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn v(&self, reg: u8) -> u8 {
+        self.v[reg as usize]
+    }
+
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn sp(&self) -> u8 {
+        self.sp
+    }
+
+    // How long the last instruction would have taken on the COSMAC VIP, in machine cycles
+    pub fn cycles(&self) -> u32 {
+        self.cycles
+    }
+
     pub fn cycle(
         &mut self,
         memory: &mut Memory,
@@ -50,55 +155,114 @@ impl CPU {
         keyboard: &mut Keyboard,
         rng: &mut RNG,
         settings: &Settings,
-    ) {
-        let opcode = ((memory.read(self.pc) as u16) << 8) | (memory.read(self.pc + 1) as u16);
+    ) -> Result<(), Chip8Error> {
+        let opaddr = self.pc;
+        let result = self.execute(memory, display, keyboard, rng, settings);
+        if result.is_err() {
+            // Leave pc on the failing instruction so it can be inspected
+            self.pc = opaddr;
+        }
+        result
+    }
+
+    fn execute(
+        &mut self,
+        memory: &mut Memory,
+        display: &mut Display,
+        keyboard: &mut Keyboard,
+        rng: &mut RNG,
+        settings: &Settings,
+    ) -> Result<(), Chip8Error> {
+        let opcode = Self::fetch(memory, self.pc)?;
         let opaddr = self.pc;
-        self.pc += 2;
+        // Past the end of XO-CHIP memory execution continues from 0
+        self.pc = self.pc.wrapping_add(2);
+
+        let op = if Op::is_long(opcode, settings.variant) {
+            let addr = Self::fetch(memory, self.pc)?;
+            self.pc = self.pc.wrapping_add(2);
+            Op::LDL { addr }
+        } else {
+            Op::decode(opcode, settings.variant)
+        };
 
-        let op = Op::decode(opcode);
+        self.halted = false;
 
         if settings.print_opcodes {
             println!("{:#05X}: {:#06X} {}", opaddr, opcode, op);
         }
 
+        // The cost of some instructions depends on the registers before they run
+        let (v, i) = (self.v, self.i);
+
         match op {
             Op::SYS  { addr }                 => self.sys(addr),
+            Op::SCD  { nibble }               => self.scd(nibble, display),
+            Op::SCU  { nibble }               => self.scu(nibble, display),
             Op::CLS                           => self.cls(display),
-            Op::RET                           => self.ret(),
+            Op::RET                           => self.ret(opaddr)?,
+            Op::SCR                           => self.scr(display),
+            Op::SCL                           => self.scl(display),
+            Op::EXIT                          => self.exit(),
+            Op::LOW                           => self.low(display),
+            Op::HIGH                          => self.high(display),
             Op::JP   { addr }                 => self.jp(addr),
-            Op::CALL { addr }                 => self.call(addr),
-            Op::SE   { reg, byte }            => self.se(reg, byte),
-            Op::SNE  { reg, byte }            => self.sne(reg, byte),
-            Op::SER  { reg_a, reg_b }         => self.ser(reg_a, reg_b),
+            Op::CALL { addr }                 => self.call(addr, opaddr)?,
+            Op::SE   { reg, byte }            => self.se(reg, byte, memory, settings)?,
+            Op::SNE  { reg, byte }            => self.sne(reg, byte, memory, settings)?,
+            Op::SER  { reg_a, reg_b }         => self.ser(reg_a, reg_b, memory, settings)?,
+            Op::STRG { reg_a, reg_b }         => self.strg(reg_a, reg_b, memory)?,
+            Op::LDRG { reg_a, reg_b }         => self.ldrg(reg_a, reg_b, memory)?,
             Op::LD   { reg, byte }            => self.ld(reg, byte),
             Op::ADD  { reg, byte }            => self.add(reg, byte),
             Op::LDR  { reg_a, reg_b }         => self.ldr(reg_a, reg_b),
-            Op::OR   { reg_a, reg_b }         => self.or(reg_a, reg_b),
-            Op::AND  { reg_a, reg_b }         => self.and(reg_a, reg_b),
-            Op::XOR  { reg_a, reg_b }         => self.xor(reg_a, reg_b),
+            Op::OR   { reg_a, reg_b }         => self.or(reg_a, reg_b, settings),
+            Op::AND  { reg_a, reg_b }         => self.and(reg_a, reg_b, settings),
+            Op::XOR  { reg_a, reg_b }         => self.xor(reg_a, reg_b, settings),
             Op::ADDR { reg_a, reg_b }         => self.addr(reg_a, reg_b),
             Op::SUB  { reg_a, reg_b }         => self.sub(reg_a, reg_b),
             Op::SHR  { reg_a, reg_b }         => self.shr(reg_a, reg_b, settings),
             Op::SUBN { reg_a, reg_b }         => self.subn(reg_a, reg_b),
             Op::SHL  { reg_a, reg_b }         => self.shl(reg_a, reg_b, settings),
-            Op::SNER { reg_a, reg_b }         => self.sner(reg_a, reg_b),
+            Op::SNER { reg_a, reg_b }         => self.sner(reg_a, reg_b, memory, settings)?,
             Op::LDA  { addr }                 => self.lda(addr),
-            Op::JPO  { addr }                 => self.jpo(addr),
+            Op::JPO  { addr }                 => self.jpo(addr, settings),
             Op::RND  { reg, byte }            => self.rnd(reg, byte, rng),
-            Op::DRW  { reg_a, reg_b, nibble } => self.drw(reg_a, reg_b, nibble, memory, display, settings),
-            Op::SKP  { reg }                  => self.skp(reg, keyboard),
-            Op::SKNP { reg }                  => self.sknp(reg, keyboard),
+            Op::DRW  { reg_a, reg_b, nibble } => self.drw(reg_a, reg_b, nibble, memory, display, settings)?,
+            Op::SKP  { reg }                  => self.skp(reg, keyboard, memory, settings)?,
+            Op::SKNP { reg }                  => self.sknp(reg, keyboard, memory, settings)?,
+            Op::LDL  { addr }                 => self.ldl(addr),
+            Op::PLN  { nibble }               => self.pln(nibble, display),
+            Op::LDAU                          => self.ldau(memory)?,
             Op::LDDT { reg }                  => self.lddt(reg),
-            Op::LDKP { reg }                  => self.ldkp(reg, keyboard),
+            Op::LDKP { reg }                  => self.ldkp(reg, keyboard, settings.wait_key_release),
             Op::STDT { reg }                  => self.stdt(reg),
             Op::STST { reg }                  => self.stst(reg),
             Op::ADDA { reg }                  => self.adda(reg, settings),
             Op::LDSA { reg }                  => self.ldsa(reg),
-            Op::STDR { reg }                  => self.stdr(reg, memory),
-            Op::STRR { reg }                  => self.strr(reg, memory, settings),
-            Op::LDRR { reg }                  => self.ldrr(reg, memory, settings),
-            Op::INV  { opcode }               => panic!("Invalid opcode {:#06X} at {:#05X}", opcode, opaddr),
+            Op::LDHF { reg }                  => self.ldhf(reg),
+            Op::PTCH { reg }                  => self.ptch(reg),
+            Op::STDR { reg }                  => self.stdr(reg, memory)?,
+            Op::STRR { reg }                  => self.strr(reg, memory, settings)?,
+            Op::LDRR { reg }                  => self.ldrr(reg, memory, settings)?,
+            Op::STRP { reg }                  => self.strp(reg),
+            Op::LDRP { reg }                  => self.ldrp(reg),
+            Op::INV  { opcode }               => return Err(Chip8Error::InvalidOpcode { opcode, addr: opaddr }),
         }
+        self.cycles = vip::cycles(&op, &v, i, opaddr, self.pc);
+        Ok(())
+    }
+
+    // Instructions are fetched with peek, read watchpoints are only about the data the program reads
+    fn fetch(memory: &Memory, addr: u16) -> Result<u16, Chip8Error> {
+        Ok(((memory.peek(addr)? as u16) << 8) | (memory.peek(addr.wrapping_add(1))? as u16))
+    }
+
+    // Skips the next instruction, which in XO-CHIP can be the 4 byte long F000 nnnn
+    fn skip(&mut self, memory: &Memory, settings: &Settings) -> Result<(), Chip8Error> {
+        let next = Self::fetch(memory, self.pc)?;
+        self.pc = self.pc.wrapping_add(if Op::is_long(next, settings.variant) { 4 } else { 2 });
+        Ok(())
     }
 
     // Ops
@@ -107,41 +271,107 @@ impl CPU {
         // NOOP
     }
 
+    fn scd(&mut self, nibble: u8, display: &mut Display) {
+        display.scroll_down(nibble as usize);
+    }
+
+    fn scu(&mut self, nibble: u8, display: &mut Display) {
+        display.scroll_up(nibble as usize);
+    }
+
     fn cls(&mut self, display: &mut Display) {
         display.clear();
         //println!("{}", display);
     }
 
-    fn ret(&mut self) {
+    fn ret(&mut self, opaddr: u16) -> Result<(), Chip8Error> {
+        if self.sp == 0 {
+            return Err(Chip8Error::StackUnderflow { addr: opaddr });
+        }
         self.sp -= 1;
         self.pc = self.stack[self.sp as usize];
+        Ok(())
+    }
+
+    fn scr(&mut self, display: &mut Display) {
+        display.scroll_right(4);
+    }
+
+    fn scl(&mut self, display: &mut Display) {
+        display.scroll_left(4);
+    }
+
+    fn exit(&mut self) {
+        // Stay on this instruction
+        self.pc = self.pc.wrapping_sub(2);
+        self.halted = true;
+    }
+
+    fn low(&mut self, display: &mut Display) {
+        display.set_hires(false);
+    }
+
+    fn high(&mut self, display: &mut Display) {
+        display.set_hires(true);
     }
 
     fn jp(&mut self, addr: u16) {
+        self.halted = addr == self.pc.wrapping_sub(2);
         self.pc = addr;
     }
 
-    fn call(&mut self, addr: u16) {
+    fn call(&mut self, addr: u16, opaddr: u16) -> Result<(), Chip8Error> {
+        if self.sp as usize == STACK_SIZE {
+            return Err(Chip8Error::StackOverflow { addr: opaddr });
+        }
         self.stack[self.sp as usize] = self.pc;
         self.sp += 1;
         self.pc = addr;
+        Ok(())
     }
 
-    fn se(&mut self, reg: u8, byte: u8) {
+    fn se(&mut self, reg: u8, byte: u8, memory: &Memory, settings: &Settings) -> Result<(), Chip8Error> {
         if self.v[reg as usize] == byte {
-            self.pc += 2;
+            self.skip(memory, settings)?;
         }
+        Ok(())
     }
 
-    fn sne(&mut self, reg: u8, byte: u8) {
+    fn sne(&mut self, reg: u8, byte: u8, memory: &Memory, settings: &Settings) -> Result<(), Chip8Error> {
         if self.v[reg as usize] != byte {
-            self.pc += 2;
+            self.skip(memory, settings)?;
         }
+        Ok(())
     }
 
-    fn ser(&mut self, reg_a: u8, reg_b: u8) {
+    fn ser(&mut self, reg_a: u8, reg_b: u8, memory: &Memory, settings: &Settings) -> Result<(), Chip8Error> {
         if self.v[reg_a as usize] == self.v[reg_b as usize] {
-            self.pc += 2;
+            self.skip(memory, settings)?;
+        }
+        Ok(())
+    }
+
+    // Registers are stored in the order given, Vy down to Vx if y < x
+    fn strg(&mut self, reg_a: u8, reg_b: u8, memory: &mut Memory) -> Result<(), Chip8Error> {
+        for (offset, reg) in Self::range(reg_a, reg_b).into_iter().enumerate() {
+            memory.write(self.i.wrapping_add(offset as u16), self.v[reg])?;
+        }
+        Ok(())
+    }
+
+    fn ldrg(&mut self, reg_a: u8, reg_b: u8, memory: &mut Memory) -> Result<(), Chip8Error> {
+        for (offset, reg) in Self::range(reg_a, reg_b).into_iter().enumerate() {
+            self.v[reg] = memory.read(self.i.wrapping_add(offset as u16))?;
+        }
+        Ok(())
+    }
+
+    fn range(reg_a: u8, reg_b: u8) -> Vec<usize> {
+        let (a, b) = (reg_a as usize, reg_b as usize);
+        if a <= b {
+            (a..=b).collect()
+        } else {
+            (b..=a).rev().collect()
         }
     }
 
@@ -158,16 +388,25 @@ impl CPU {
         self.v[reg_a as usize] = self.v[reg_b as usize];
     }
 
-    fn or(&mut self, reg_a: u8, reg_b: u8) {
+    fn or(&mut self, reg_a: u8, reg_b: u8, settings: &Settings) {
         self.v[reg_a as usize] |= self.v[reg_b as usize];
+        if settings.vf_reset_quirk {
+            self.v[0xF] = 0;
+        }
     }
 
-    fn and(&mut self, reg_a: u8, reg_b: u8) {
+    fn and(&mut self, reg_a: u8, reg_b: u8, settings: &Settings) {
         self.v[reg_a as usize] &= self.v[reg_b as usize];
+        if settings.vf_reset_quirk {
+            self.v[0xF] = 0;
+        }
     }
 
-    fn xor(&mut self, reg_a: u8, reg_b: u8) {
+    fn xor(&mut self, reg_a: u8, reg_b: u8, settings: &Settings) {
         self.v[reg_a as usize] ^= self.v[reg_b as usize];
+        if settings.vf_reset_quirk {
+            self.v[0xF] = 0;
+        }
     }
 
     fn addr(&mut self, reg_a: u8, reg_b: u8) {
@@ -206,70 +445,125 @@ impl CPU {
         self.v[0xF] = msb;
     }
 
-    fn sner(&mut self, reg_a: u8, reg_b: u8) {
+    fn sner(&mut self, reg_a: u8, reg_b: u8, memory: &Memory, settings: &Settings) -> Result<(), Chip8Error> {
         if self.v[reg_a as usize] != self.v[reg_b as usize] {
-            self.pc += 2;
+            self.skip(memory, settings)?;
         }
+        Ok(())
     }
 
     fn lda(&mut self, addr: u16) {
         self.i = addr;
     }
 
-    fn jpo(&mut self, addr: u16) {
-        self.pc = addr + self.v[0] as u16;
+    fn jpo(&mut self, addr: u16, settings: &Settings) {
+        let reg = if settings.jump_quirk { (addr >> 8) as usize } else { 0 };
+        self.pc = addr + self.v[reg] as u16;
     }
 
     fn rnd(&mut self, reg: u8, byte: u8, rng: &mut RNG) {
         self.v[reg as usize] = rng.next() & byte;
     }
 
-    fn drw(&mut self, reg_a: u8, reg_b: u8, nibble: u8, memory: &mut Memory, display: &mut Display, settings: &Settings) {
-        let x = self.v[reg_a as usize] as usize;
-        let y = self.v[reg_b as usize] as usize;
-        let h = nibble as usize;
+    fn drw(&mut self, reg_a: u8, reg_b: u8, nibble: u8, memory: &mut Memory, display: &mut Display, settings: &Settings) -> Result<(), Chip8Error> {
+        // Wait for the next frame if a sprite was already drawn in this one
+        if settings.display_wait_quirk {
+            if !self.vblank {
+                self.pc = self.pc.wrapping_sub(2);
+                return Ok(());
+            }
+            self.vblank = false;
+        }
 
-        let mut pixel_erased = false;
+        // The sprite starts on the screen, parts past the edges are clipped or wrapped around
+        let x = self.v[reg_a as usize] as usize % display.width();
+        let y = self.v[reg_b as usize] as usize % display.height();
 
-        for offset_y in 0..h {
-            let px_y = y + offset_y;
-            if !settings.vertical_wrap && px_y >= display.height() {
-                break;
-            }
-            let byte = memory.read(self.i + offset_y as u16);
-            for offset_x in 0..8 {
-                let pixel = ((byte >> 7 - offset_x) & 1) == 1;
-                let px_x = x + offset_x;
-                pixel_erased |= display.set_pixel(px_x, px_y, pixel);
+        // SCHIP draws 16 rows for Dxy0, 16 pixels wide in high resolution mode. XO-CHIP always draws 16x16.
+        let (w, h) = if nibble == 0 && settings.variant != Variant::CHIP8 {
+            (if display.hires() || settings.variant == Variant::XOCHIP { 16 } else { 8 }, 16)
+        } else {
+            (8, nibble as usize)
+        };
+        let row_size = w / 8;
+
+        let mut rows_erased = 0;
+
+        // With more than one plane selected (XO-CHIP) the sprite data for each plane follows the previous one
+        let planes = display.planes();
+        let mut addr = self.i;
+        for plane in (0..PLANES).map(|p| 1 << p).filter(|p| planes & p != 0) {
+            for offset_y in 0..h {
+                let px_y = y + offset_y;
+                if !settings.vertical_wrap && px_y >= display.height() {
+                    break;
+                }
+                let mut pixel_erased = false;
+                for offset_x in 0..w {
+                    let byte = memory.read(addr.wrapping_add((offset_y * row_size + offset_x / 8) as u16))?;
+                    let pixel = ((byte >> (7 - offset_x % 8)) & 1) == 1;
+                    let px_x = x + offset_x;
+                    if !settings.horizontal_wrap && px_x >= display.width() {
+                        break;
+                    }
+                    pixel_erased |= display.set_pixel(px_x, px_y, plane, pixel);
+                }
+                if pixel_erased {
+                    rows_erased += 1;
+                }
             }
+            addr = addr.wrapping_add((h * row_size) as u16);
         }
 
-        self.v[0xF] = if pixel_erased { 1 } else { 0 };
-
-        //println!("{}", display);
+        // SCHIP in high resolution mode sets VF to the number of rows with erased pixels
+        self.v[0xF] = if settings.variant == Variant::SCHIP && display.hires() {
+            rows_erased
+        } else if rows_erased > 0 {
+            1
+        } else {
+            0
+        };
+        Ok(())
     }
 
-    fn skp(&mut self, reg: u8, keyboard: &mut Keyboard) {
+    fn skp(&mut self, reg: u8, keyboard: &mut Keyboard, memory: &Memory, settings: &Settings) -> Result<(), Chip8Error> {
         if keyboard.get_key(self.v[reg as usize]) {
-            self.pc += 2;
+            self.skip(memory, settings)?;
         }
+        Ok(())
     }
 
-    fn sknp(&mut self, reg: u8, keyboard: &mut Keyboard) {
+    fn sknp(&mut self, reg: u8, keyboard: &mut Keyboard, memory: &Memory, settings: &Settings) -> Result<(), Chip8Error> {
         if !keyboard.get_key(self.v[reg as usize]) {
-            self.pc += 2;
+            self.skip(memory, settings)?;
+        }
+        Ok(())
+    }
+
+    fn ldl(&mut self, addr: u16) {
+        self.i = addr;
+    }
+
+    fn pln(&mut self, nibble: u8, display: &mut Display) {
+        display.select_planes(nibble);
+    }
+
+    fn ldau(&mut self, memory: &mut Memory) -> Result<(), Chip8Error> {
+        for i in 0..self.audio_pattern.len() {
+            self.audio_pattern[i] = memory.read(self.i.wrapping_add(i as u16))?;
         }
+        Ok(())
     }
 
     fn lddt(&mut self, reg: u8) {
         self.v[reg as usize] = self.dt;
     }
 
-    fn ldkp(&mut self, reg: u8, keyboard: &mut Keyboard) {
-        if let Some(key) = keyboard.wait_keypress() {
+    fn ldkp(&mut self, reg: u8, keyboard: &mut Keyboard, release: bool) {
+        if let Some(key) = keyboard.wait_keypress(release) {
             self.v[reg as usize] = key;
         } else {
-            self.pc -= 2;
+            self.pc = self.pc.wrapping_sub(2);
         }
     }
 
@@ -293,29 +587,863 @@ impl CPU {
         self.i = Memory::sprite_address(self.v[reg as usize]);
     }
 
-    fn stdr(&mut self, reg: u8, memory: &mut Memory) {
-        memory.write(self.i,      self.v[reg as usize] / 100);
-        memory.write(self.i + 1, (self.v[reg as usize] / 10) % 10);
-        memory.write(self.i + 2,  self.v[reg as usize] % 10)
+    fn ldhf(&mut self, reg: u8) {
+        self.i = Memory::big_sprite_address(self.v[reg as usize]);
     }
 
-    fn strr(&mut self, reg: u8, memory: &mut Memory, settings: &Settings) {
+    fn ptch(&mut self, reg: u8) {
+        self.pitch = self.v[reg as usize];
+    }
+
+    fn stdr(&mut self, reg: u8, memory: &mut Memory) -> Result<(), Chip8Error> {
+        memory.write(self.i,                   self.v[reg as usize] / 100)?;
+        memory.write(self.i.wrapping_add(1), (self.v[reg as usize] / 10) % 10)?;
+        memory.write(self.i.wrapping_add(2),  self.v[reg as usize] % 10)
+    }
+
+    fn strr(&mut self, reg: u8, memory: &mut Memory, settings: &Settings) -> Result<(), Chip8Error> {
         for i in 0..=reg {
-            let addr = self.i + i as u16;
-            memory.write(addr, self.v[i as usize]);
-        }
-        if settings.load_store_quirk {
-            self.i += reg as u16 + 1;
+            let addr = self.i.wrapping_add(i as u16);
+            memory.write(addr, self.v[i as usize])?;
         }
+        self.increment_i(reg, settings);
+        Ok(())
     }
 
-    fn ldrr(&mut self, reg: u8, memory: &mut Memory, settings: &Settings) {
+    fn ldrr(&mut self, reg: u8, memory: &mut Memory, settings: &Settings) -> Result<(), Chip8Error> {
         for i in 0..=reg {
-            let addr = self.i + i as u16;
-            self.v[i as usize] = memory.read(addr);
+            let addr = self.i.wrapping_add(i as u16);
+            self.v[i as usize] = memory.read(addr)?;
         }
+        self.increment_i(reg, settings);
+        Ok(())
+    }
+
+    // After STRR and LDRR with load_store_quirk
+    fn increment_i(&mut self, reg: u8, settings: &Settings) {
         if settings.load_store_quirk {
-            self.i += reg as u16 + 1;
+            let n = if settings.load_store_x_quirk { reg as u16 } else { reg as u16 + 1 };
+            self.i = self.i.wrapping_add(n);
+        }
+    }
+
+    fn strp(&mut self, reg: u8) {
+        for i in 0..=reg as usize {
+            self.rpl[i] = self.v[i];
+        }
+    }
+
+    fn ldrp(&mut self, reg: u8) {
+        for i in 0..=reg as usize {
+            self.v[i] = self.rpl[i];
+        }
+    }
+}
+
+// Register dump shown when execution stops on an error
+impl fmt::Display for CPU {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "PC: {:#05X}  I: {:#05X}  SP: {}  DT: {}  ST: {}", self.pc, self.i, self.sp, self.dt, self.st)?;
+        for (reg, value) in self.v.iter().enumerate() {
+            write!(f, "V{:X}: {:#04X}{}", reg, value, if reg % 8 == 7 { "\n" } else { "  " })?;
+        }
+        write!(f, "Stack:")?;
+        for addr in &self.stack[..self.sp as usize] {
+            write!(f, " {:#05X}", addr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VARIANTS: [Variant; 3] = [Variant::CHIP8, Variant::SCHIP, Variant::XOCHIP];
+
+    struct Machine {
+        cpu: CPU,
+        memory: Memory,
+        display: Display,
+        keyboard: Keyboard,
+        rng: RNG,
+        settings: Settings,
+    }
+
+    impl Machine {
+        // The program is loaded at 0x200, where the CPU starts
+        fn new(settings: Settings, program: &[u16]) -> Self {
+            let mut machine = Self {
+                cpu: CPU::new(),
+                memory: Memory::new(settings.variant.memory_size()),
+                display: Display::new(),
+                keyboard: Keyboard::new(),
+                rng: RNG::new(settings.rng_seed),
+                settings,
+            };
+            for (i, opcode) in program.iter().enumerate() {
+                machine.poke(0x200 + 2 * i as u16, &opcode.to_be_bytes());
+            }
+            machine
+        }
+
+        fn poke(&mut self, addr: u16, bytes: &[u8]) {
+            for (i, byte) in bytes.iter().enumerate() {
+                self.memory.write(addr.wrapping_add(i as u16), *byte).unwrap();
+            }
+        }
+
+        fn peek(&self, addr: u16, len: u16) -> Vec<u8> {
+            (addr..addr + len).map(|addr| self.memory.peek(addr).unwrap()).collect()
+        }
+
+        fn step(&mut self) -> Result<(), Chip8Error> {
+            self.cpu.cycle(&mut self.memory, &mut self.display, &mut self.keyboard, &mut self.rng, &self.settings)
+        }
+
+        fn run(&mut self, steps: usize) {
+            for _ in 0..steps {
+                self.step().unwrap();
+            }
+        }
+
+        fn row(&self, y: usize) -> String {
+            (0..self.display.width()).map(|x| if self.display.pixel(x, y) { '#' } else { '.' }).collect()
+        }
+    }
+
+    // Every combination of the quirks that change what instructions compute, for one variant. Display wait and
+    // wrapping change when and where sprites are drawn and have tests of their own.
+    fn quirks(variant: Variant) -> Vec<Settings> {
+        (0..64).map(|bits| {
+            let mut settings = Settings::new();
+            settings.variant = variant;
+            settings.load_store_quirk = bits & 1 != 0;
+            settings.shift_quirk = bits & 2 != 0;
+            settings.address_overflow_quirk = bits & 4 != 0;
+            settings.vf_reset_quirk = bits & 8 != 0;
+            settings.load_store_x_quirk = bits & 16 != 0;
+            settings.jump_quirk = bits & 32 != 0;
+            settings
+        }).collect()
+    }
+
+    // Every combination of the quirks for every variant
+    fn all_settings() -> Vec<Settings> {
+        VARIANTS.iter().flat_map(|variant| quirks(*variant)).collect()
+    }
+
+    fn name(settings: &Settings) -> String {
+        format!("{} load_store={} shift={} address_overflow={} vf_reset={} load_store_x={} jump={}",
+            settings.variant.name(), settings.load_store_quirk, settings.shift_quirk, settings.address_overflow_quirk,
+            settings.vf_reset_quirk, settings.load_store_x_quirk, settings.jump_quirk)
+    }
+
+    #[test]
+    fn sys_does_nothing() {
+        for settings in all_settings() {
+            let mut m = Machine::new(settings, &[0x0123]);
+            m.run(1);
+            assert_eq!(m.cpu.pc, 0x202);
+            assert_eq!(m.cpu.v, [0; 16]);
+            assert_eq!(m.cpu.sp, 0);
+        }
+    }
+
+    #[test]
+    fn cls_clears_display() {
+        for settings in all_settings() {
+            let mut m = Machine::new(settings, &[0x00E0]);
+            m.display.set_pixel(3, 4, 1, true);
+            m.run(1);
+            assert!(!m.display.pixel(3, 4));
+            assert_eq!(m.cpu.pc, 0x202);
+        }
+    }
+
+    #[test]
+    fn call_and_ret() {
+        for settings in all_settings() {
+            let mut m = Machine::new(settings, &[0x2206, 0x0000, 0x0000, 0x00EE]);
+            m.run(1);
+            assert_eq!(m.cpu.pc, 0x206);
+            assert_eq!(m.cpu.sp, 1);
+            assert_eq!(m.cpu.stack[0], 0x202);
+            m.run(1);
+            assert_eq!(m.cpu.pc, 0x202);
+            assert_eq!(m.cpu.sp, 0);
+        }
+    }
+
+    #[test]
+    fn ret_with_empty_stack_underflows() {
+        for settings in all_settings() {
+            let mut m = Machine::new(settings, &[0x00EE]);
+            assert!(matches!(m.step(), Err(Chip8Error::StackUnderflow { addr: 0x200 })));
+            assert_eq!(m.cpu.pc, 0x200);
+        }
+    }
+
+    #[test]
+    fn call_with_full_stack_overflows() {
+        for settings in all_settings() {
+            let mut m = Machine::new(settings, &[0x2200]);
+            m.run(STACK_SIZE);
+            assert_eq!(m.cpu.sp as usize, STACK_SIZE);
+            assert!(matches!(m.step(), Err(Chip8Error::StackOverflow { addr: 0x200 })));
+            assert_eq!(m.cpu.pc, 0x200);
+            assert_eq!(m.cpu.sp as usize, STACK_SIZE);
+        }
+    }
+
+    #[test]
+    fn jp() {
+        for settings in all_settings() {
+            let mut m = Machine::new(settings, &[0x1ABC]);
+            m.run(1);
+            assert_eq!(m.cpu.pc, 0xABC);
+            assert!(!m.cpu.halted());
+        }
+    }
+
+    #[test]
+    fn jp_to_itself_halts() {
+        for settings in all_settings() {
+            let mut m = Machine::new(settings, &[0x1200]);
+            m.run(1);
+            assert_eq!(m.cpu.pc, 0x200);
+            assert!(m.cpu.halted());
+        }
+    }
+
+    #[test]
+    fn skips() {
+        // (opcode, V1, V2, skips)
+        let cases = [
+            (0x3142, 0x42, 0x00, true),   // SE V1 0x42
+            (0x3142, 0x41, 0x00, false),
+            (0x4142, 0x42, 0x00, false),  // SNE V1 0x42
+            (0x4142, 0x41, 0x00, true),
+            (0x5120, 0x17, 0x17, true),   // SER V1 V2
+            (0x5120, 0x17, 0x18, false),
+            (0x9120, 0x17, 0x17, false),  // SNER V1 V2
+            (0x9120, 0x17, 0x18, true),
+        ];
+        for settings in all_settings() {
+            for (opcode, v1, v2, skips) in cases {
+                let mut m = Machine::new(settings.clone(), &[opcode]);
+                m.cpu.v[1] = v1;
+                m.cpu.v[2] = v2;
+                m.run(1);
+                assert_eq!(m.cpu.pc, if skips { 0x204 } else { 0x202 }, "{:#06X} {}", opcode, name(&settings));
+            }
+        }
+    }
+
+    #[test]
+    fn skip_over_long_instruction() {
+        for settings in all_settings() {
+            let variant = settings.variant;
+            let mut m = Machine::new(settings, &[0x3000, 0xF000, 0x1234]);
+            m.run(1);
+            // F000 nnnn is only one 4 byte instruction in XO-CHIP
+            assert_eq!(m.cpu.pc, if variant == Variant::XOCHIP { 0x206 } else { 0x204 });
+        }
+    }
+
+    #[test]
+    fn skp_and_sknp() {
+        for settings in all_settings() {
+            for held in [false, true] {
+                let mut keys = [false; 16];
+                keys[0xA] = held;
+
+                let mut m = Machine::new(settings.clone(), &[0xE39E]);
+                m.cpu.v[3] = 0xA;
+                m.keyboard.set_keys(keys);
+                m.run(1);
+                assert_eq!(m.cpu.pc, if held { 0x204 } else { 0x202 });
+
+                let mut m = Machine::new(settings.clone(), &[0xE3A1]);
+                m.cpu.v[3] = 0xA;
+                m.keyboard.set_keys(keys);
+                m.run(1);
+                assert_eq!(m.cpu.pc, if held { 0x202 } else { 0x204 });
+            }
+        }
+    }
+
+    #[test]
+    fn ld_and_add() {
+        for settings in all_settings() {
+            let mut m = Machine::new(settings, &[0x6AFE, 0x7A03, 0x7B01]);
+            m.cpu.v[0xF] = 0x55;
+            m.run(1);
+            assert_eq!(m.cpu.v[0xA], 0xFE);
+            // ADD wraps around without touching VF
+            m.run(1);
+            assert_eq!(m.cpu.v[0xA], 0x01);
+            assert_eq!(m.cpu.v[0xF], 0x55);
+            m.run(1);
+            assert_eq!(m.cpu.v[0xB], 0x01);
+        }
+    }
+
+    #[test]
+    fn register_logic_follows_vf_reset_quirk() {
+        // (opcode, Vx after, resets VF) with Vx = 0b1100 and Vy = 0b1010
+        let cases = [(0x8120, 0b1010, false), (0x8121, 0b1110, true), (0x8122, 0b1000, true), (0x8123, 0b0110, true)];
+        for settings in all_settings() {
+            for (opcode, result, resets) in cases {
+                let mut m = Machine::new(settings.clone(), &[opcode]);
+                m.cpu.v[1] = 0b1100;
+                m.cpu.v[2] = 0b1010;
+                m.cpu.v[0xF] = 0x55;
+                m.run(1);
+                assert_eq!(m.cpu.v[1], result, "{:#06X} {}", opcode, name(&settings));
+                assert_eq!(m.cpu.v[2], 0b1010);
+                assert_eq!(m.cpu.v[0xF], if resets && settings.vf_reset_quirk { 0 } else { 0x55 });
+            }
+        }
+    }
+
+    #[test]
+    fn addr_sets_carry() {
+        // (Vx, Vy, sum, VF)
+        let cases = [(0x10, 0x20, 0x30, 0), (0xFF, 0x01, 0x00, 1), (0xF0, 0xF0, 0xE0, 1), (0x00, 0x00, 0x00, 0)];
+        for settings in all_settings() {
+            for (x, y, sum, flag) in cases {
+                let mut m = Machine::new(settings.clone(), &[0x8124]);
+                m.cpu.v[1] = x;
+                m.cpu.v[2] = y;
+                m.run(1);
+                assert_eq!((m.cpu.v[1], m.cpu.v[0xF]), (sum, flag));
+            }
+        }
+    }
+
+    #[test]
+    fn sub_and_subn_set_no_borrow() {
+        // (Vx, Vy, Vx - Vy, VF) with VF set when there's no borrow, including when both are equal
+        let cases = [(0x30, 0x10, 0x20, 1), (0x10, 0x30, 0xE0, 0), (0x42, 0x42, 0x00, 1)];
+        for settings in all_settings() {
+            for (x, y, dif, flag) in cases {
+                let mut m = Machine::new(settings.clone(), &[0x8125]);
+                m.cpu.v[1] = x;
+                m.cpu.v[2] = y;
+                m.run(1);
+                assert_eq!((m.cpu.v[1], m.cpu.v[0xF]), (dif, flag), "SUB {:#X} {:#X}", x, y);
+
+                // SUBN computes Vy - Vx
+                let mut m = Machine::new(settings.clone(), &[0x8127]);
+                m.cpu.v[1] = y;
+                m.cpu.v[2] = x;
+                m.run(1);
+                assert_eq!((m.cpu.v[1], m.cpu.v[0xF]), (dif, flag), "SUBN {:#X} {:#X}", y, x);
+            }
+        }
+    }
+
+    #[test]
+    fn flag_wins_when_vf_is_the_target() {
+        // (opcode, VF, Vy, VF after)
+        let cases = [(0x8F14, 0xFF, 0x01, 1), (0x8F15, 0x01, 0x02, 0), (0x8F17, 0x01, 0x02, 1)];
+        for settings in all_settings() {
+            for (opcode, f, y, flag) in cases {
+                let mut m = Machine::new(settings.clone(), &[opcode]);
+                m.cpu.v[0xF] = f;
+                m.cpu.v[1] = y;
+                m.run(1);
+                assert_eq!(m.cpu.v[0xF], flag, "{:#06X}", opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn shifts_follow_shift_quirk() {
+        for settings in all_settings() {
+            let quirk = settings.shift_quirk;
+
+            // SHR V1 V2
+            let mut m = Machine::new(settings.clone(), &[0x8126]);
+            m.cpu.v[1] = 0b0000_0110;
+            m.cpu.v[2] = 0b0000_0011;
+            m.run(1);
+            if quirk {
+                assert_eq!((m.cpu.v[1], m.cpu.v[0xF]), (0b0000_0011, 0), "{}", name(&settings));
+            } else {
+                assert_eq!((m.cpu.v[1], m.cpu.v[0xF]), (0b0000_0001, 1), "{}", name(&settings));
+            }
+            assert_eq!(m.cpu.v[2], 0b0000_0011);
+
+            // SHL V1 V2
+            let mut m = Machine::new(settings.clone(), &[0x812E]);
+            m.cpu.v[1] = 0b0100_0001;
+            m.cpu.v[2] = 0b1000_0001;
+            m.run(1);
+            if quirk {
+                assert_eq!((m.cpu.v[1], m.cpu.v[0xF]), (0b1000_0010, 0), "{}", name(&settings));
+            } else {
+                assert_eq!((m.cpu.v[1], m.cpu.v[0xF]), (0b0000_0010, 1), "{}", name(&settings));
+            }
+            assert_eq!(m.cpu.v[2], 0b1000_0001);
+        }
+    }
+
+    #[test]
+    fn lda_and_jpo_follows_jump_quirk() {
+        for settings in all_settings() {
+            let quirk = settings.jump_quirk;
+            let mut m = Machine::new(settings, &[0xA123, 0xB300]);
+            m.cpu.v[0] = 0x12;
+            m.cpu.v[3] = 0x34;
+            m.run(1);
+            assert_eq!(m.cpu.i, 0x123);
+            m.run(1);
+            assert_eq!(m.cpu.pc, if quirk { 0x334 } else { 0x312 });
+        }
+    }
+
+    #[test]
+    fn rnd_is_masked_and_seeded() {
+        for mut settings in all_settings() {
+            settings.rng_seed = 1234;
+            let mut m = Machine::new(settings, &[0xC30F, 0xC400]);
+            m.run(2);
+            let mut rng = RNG::new(1234);
+            assert_eq!(m.cpu.v[3], rng.next() & 0x0F);
+            assert_eq!(m.cpu.v[4], 0);
+        }
+    }
+
+    #[test]
+    fn drw_draws_and_detects_collisions() {
+        for settings in all_settings() {
+            // The font digit 0 at (1, 2), twice
+            let mut m = Machine::new(settings, &[0xF029, 0xD125, 0xD125]);
+            m.cpu.v[0] = 0;
+            m.cpu.v[1] = 1;
+            m.cpu.v[2] = 2;
+            m.run(2);
+            assert_eq!(m.cpu.v[0xF], 0);
+            assert_eq!(&m.row(2)[..8], ".####...");
+            assert_eq!(&m.row(3)[..8], ".#..#...");
+            assert_eq!(&m.row(6)[..8], ".####...");
+            assert_eq!(m.cpu.i, Memory::sprite_address(0));
+            m.run(1);
+            assert_eq!(m.cpu.v[0xF], 1);
+            assert!((0..32).all(|y| !m.row(y).contains('#')));
+        }
+    }
+
+    #[test]
+    fn drw_wraps_or_clips_horizontally() {
+        for settings in all_settings() {
+            for wrap in [false, true] {
+                let mut settings = settings.clone();
+                settings.horizontal_wrap = wrap;
+                let mut m = Machine::new(settings, &[0xD121]);
+                m.poke(0x300, &[0b1111_0000]);
+                m.cpu.i = 0x300;
+                m.cpu.v[1] = 62;
+                m.run(1);
+                let row = m.row(0);
+                assert_eq!(&row[..2], if wrap { "##" } else { ".." });
+                assert_eq!(&row[62..], "##");
+            }
+        }
+    }
+
+    #[test]
+    fn drw_starts_on_the_screen() {
+        for settings in all_settings() {
+            // (10 + 64, 5 + 32) in low resolution
+            let mut m = Machine::new(settings, &[0xD121]);
+            m.poke(0x300, &[0x80]);
+            m.cpu.i = 0x300;
+            m.cpu.v[1] = 74;
+            m.cpu.v[2] = 37;
+            m.run(1);
+            assert!(m.display.pixel(10, 5));
+        }
+    }
+
+    #[test]
+    fn drw_follows_display_wait_quirk() {
+        for settings in all_settings() {
+            for wait in [false, true] {
+                let mut settings = settings.clone();
+                settings.display_wait_quirk = wait;
+                let mut m = Machine::new(settings, &[0xD001, 0xD111]);
+                m.poke(0x300, &[0x80]);
+                m.cpu.i = 0x300;
+                m.cpu.v[1] = 1;
+                m.run(3);
+                // The second sprite waits for the next frame
+                assert_eq!(m.cpu.pc, if wait { 0x202 } else { 0x206 });
+                assert!(m.display.pixel(0, 0));
+                assert_eq!(m.display.pixel(1, 1), !wait);
+                m.cpu.vblank();
+                m.run(1);
+                assert!(m.display.pixel(1, 1));
+            }
+        }
+    }
+
+    #[test]
+    fn drw_wraps_or_clips_vertically() {
+        for settings in all_settings() {
+            for wrap in [false, true] {
+                let mut settings = settings.clone();
+                settings.vertical_wrap = wrap;
+                let mut m = Machine::new(settings, &[0xD124]);
+                m.poke(0x300, &[0x80; 4]);
+                m.cpu.i = 0x300;
+                m.cpu.v[2] = 30;
+                m.run(1);
+                assert!(m.display.pixel(0, 30) && m.display.pixel(0, 31));
+                assert_eq!(m.display.pixel(0, 0), wrap);
+                assert_eq!(m.display.pixel(0, 1), wrap);
+            }
+        }
+    }
+
+    #[test]
+    fn drw_large_sprites() {
+        for settings in all_settings() {
+            let variant = settings.variant;
+            for hires in [false, true] {
+                let mut m = Machine::new(settings.clone(), &[0xD120, 0xD120]);
+                m.display.set_hires(hires);
+                m.poke(0x300, &[0xFF; 32]);
+                m.cpu.i = 0x300;
+                m.run(1);
+                let count = |m: &Machine| (0..m.display.height()).map(|y| m.row(y).matches('#').count()).sum::<usize>();
+                let expected = match variant {
+                    // Dxy0 draws nothing
+                    Variant::CHIP8 => 0,
+                    // 8x16 in low resolution and 16x16 in high resolution
+                    Variant::SCHIP => if hires { 256 } else { 128 },
+                    // Always 16x16
+                    Variant::XOCHIP => 256,
+                };
+                assert_eq!(count(&m), expected, "{:?} hires={}", variant, hires);
+                assert_eq!(m.cpu.v[0xF], 0);
+
+                m.run(1);
+                assert_eq!(count(&m), 0);
+                // SCHIP in high resolution counts the rows with collisions
+                let flag = match variant {
+                    Variant::CHIP8 => 0,
+                    Variant::SCHIP if hires => 16,
+                    _ => 1,
+                };
+                assert_eq!(m.cpu.v[0xF], flag, "{:?} hires={}", variant, hires);
+            }
+        }
+    }
+
+    #[test]
+    fn drw_to_both_planes() {
+        for settings in quirks(Variant::XOCHIP) {
+            // PLN 3, sprite data for plane 1 followed by plane 2
+            let mut m = Machine::new(settings, &[0xF301, 0xD012]);
+            m.poke(0x300, &[0x80, 0x00, 0xC0, 0x80]);
+            m.cpu.i = 0x300;
+            m.run(2);
+            assert_eq!(m.display.planes(), 3);
+            assert_eq!(m.display.color(0, 0), 3);
+            assert_eq!(m.display.color(1, 0), 2);
+            assert_eq!(m.display.color(0, 1), 2);
+            assert_eq!(m.display.color(1, 1), 0);
+        }
+    }
+
+    #[test]
+    fn timers() {
+        for settings in all_settings() {
+            let mut m = Machine::new(settings, &[0xF115, 0xF218, 0xF307]);
+            m.cpu.v[1] = 10;
+            m.cpu.v[2] = 2;
+            m.run(2);
+            assert_eq!((m.cpu.dt, m.cpu.st), (10, 2));
+            assert!(m.cpu.beep());
+            m.cpu.cycle_dt();
+            assert!(m.cpu.cycle_st());
+            assert!(!m.cpu.cycle_st());
+            assert!(!m.cpu.beep());
+            m.run(1);
+            assert_eq!(m.cpu.v[3], 9);
+        }
+    }
+
+    #[test]
+    fn ldkp_waits_for_a_key() {
+        for settings in all_settings() {
+            let mut m = Machine::new(settings, &[0xF30A]);
+            m.run(2);
+            assert_eq!(m.cpu.pc, 0x200);
+            assert!(m.keyboard.waiting());
+            m.keyboard.push_keypress(0x7);
+            m.run(1);
+            assert_eq!(m.cpu.pc, 0x202);
+            assert_eq!(m.cpu.v[3], 0x7);
+            assert!(!m.keyboard.waiting());
+        }
+    }
+
+    #[test]
+    fn ldkp_finishes_on_press_or_release() {
+        for release in [false, true] {
+            let mut settings = Settings::new();
+            settings.wait_key_release = release;
+            let mut m = Machine::new(settings, &[0xF30A]);
+            m.run(1);
+            let mut keys = [false; 16];
+            keys[0xA] = true;
+            m.keyboard.set_keys(keys);
+            m.run(1);
+            assert_eq!(m.cpu.pc, if release { 0x200 } else { 0x202 });
+            assert_eq!(m.keyboard.beep(), release);
+            m.keyboard.set_keys([false; 16]);
+            if release {
+                m.run(1);
+            }
+            assert_eq!(m.cpu.pc, 0x202);
+            assert_eq!(m.cpu.v[3], 0xA);
+            assert!(!m.keyboard.beep());
+        }
+    }
+
+    #[test]
+    fn ldkp_ignores_keys_held_before() {
+        let mut m = Machine::new(Settings::new(), &[0xF30A]);
+        let mut keys = [false; 16];
+        keys[0x1] = true;
+        m.keyboard.set_keys(keys);
+        m.run(2);
+        m.keyboard.set_keys(keys);
+        m.run(1);
+        assert_eq!(m.cpu.pc, 0x200);
+        keys[0x2] = true;
+        m.keyboard.set_keys(keys);
+        keys[0x2] = false;
+        m.keyboard.set_keys(keys);
+        m.run(1);
+        assert_eq!(m.cpu.pc, 0x202);
+        assert_eq!(m.cpu.v[3], 0x2);
+    }
+
+    #[test]
+    fn adda_follows_address_overflow_quirk() {
+        for settings in all_settings() {
+            let quirk = settings.address_overflow_quirk;
+            let mut m = Machine::new(settings, &[0xF11E, 0xF21E]);
+            m.cpu.i = 0xFFF0;
+            m.cpu.v[1] = 0x0F;
+            m.cpu.v[2] = 0x02;
+            m.cpu.v[0xF] = 0x55;
+            m.run(1);
+            assert_eq!(m.cpu.i, 0xFFFF);
+            assert_eq!(m.cpu.v[0xF], if quirk { 0 } else { 0x55 });
+            m.run(1);
+            assert_eq!(m.cpu.i, 0x0001);
+            assert_eq!(m.cpu.v[0xF], if quirk { 1 } else { 0x55 });
+        }
+    }
+
+    #[test]
+    fn font_addresses() {
+        for settings in all_settings() {
+            let variant = settings.variant;
+            let mut m = Machine::new(settings, &[0xF129, 0xF130]);
+            m.cpu.v[1] = 0xA;
+            m.run(1);
+            assert_eq!(m.peek(m.cpu.i, 5), [0xF0, 0x90, 0xF0, 0x90, 0x90]);
+            if variant != Variant::CHIP8 {
+                m.run(1);
+                assert_eq!(m.cpu.i, Memory::big_sprite_address(0xA));
+                assert_eq!(m.peek(m.cpu.i, 10).len(), 10);
+            } else {
+                assert!(matches!(m.step(), Err(Chip8Error::InvalidOpcode { opcode: 0xF130, addr: 0x202 })));
+            }
+        }
+    }
+
+    #[test]
+    fn stdr_stores_bcd() {
+        for settings in all_settings() {
+            for (value, digits) in [(0, [0, 0, 0]), (7, [0, 0, 7]), (42, [0, 4, 2]), (255, [2, 5, 5])] {
+                let mut m = Machine::new(settings.clone(), &[0xF533]);
+                m.cpu.v[5] = value;
+                m.cpu.i = 0x300;
+                m.run(1);
+                assert_eq!(m.peek(0x300, 3), digits);
+                assert_eq!(m.cpu.i, 0x300);
+            }
+        }
+    }
+
+    #[test]
+    fn strr_and_ldrr_follow_load_store_quirk() {
+        for settings in all_settings() {
+            let i = match (settings.load_store_quirk, settings.load_store_x_quirk) {
+                (false, _)    => 0x300,
+                (true, false) => 0x303,
+                (true, true)  => 0x302,
+            };
+
+            let mut m = Machine::new(settings.clone(), &[0xF255]);
+            m.cpu.v[..4].copy_from_slice(&[1, 2, 3, 4]);
+            m.cpu.i = 0x300;
+            m.run(1);
+            assert_eq!(m.peek(0x300, 4), [1, 2, 3, 0]);
+            assert_eq!(m.cpu.i, i, "{}", name(&settings));
+
+            let mut m = Machine::new(settings.clone(), &[0xF265]);
+            m.poke(0x300, &[5, 6, 7, 8]);
+            m.cpu.i = 0x300;
+            m.run(1);
+            assert_eq!(m.cpu.v[..4], [5, 6, 7, 0]);
+            assert_eq!(m.cpu.i, i, "{}", name(&settings));
+        }
+    }
+
+    #[test]
+    fn pc_wraps_around_at_the_end_of_memory() {
+        let mut settings = Settings::new();
+        settings.variant = Variant::XOCHIP;
+        let mut m = Machine::new(settings, &[]);
+
+        m.cpu.pc = 0xFFFE;
+        m.poke(0xFFFE, &[0x60, 0x05]); // LD V0, 5
+        m.run(1);
+        assert_eq!(m.cpu.v[0], 5);
+        assert_eq!(m.cpu.pc, 0x0000);
+
+        m.cpu.pc = 0xFFFE;
+        m.poke(0xFFFE, &[0x30, 0x05]); // SE V0, 5
+        m.run(1);
+        assert_eq!(m.cpu.pc, 0x0002);
+
+        m.cpu.pc = 0xFFFE;
+        m.poke(0xFFFE, &[0xF0, 0x00, 0x12, 0x34]); // LDL 0x1234, the address at 0
+        m.run(1);
+        assert_eq!(m.cpu.i, 0x1234);
+        assert_eq!(m.cpu.pc, 0x0002);
+
+        m.cpu.pc = 0xFFFE;
+        m.poke(0xFFFE, &[0xF1, 0x0A]); // LDKP V1
+        m.run(1);
+        assert_eq!(m.cpu.pc, 0xFFFE);
+
+        m.poke(0xFFFE, &[0x00, 0xFD]); // EXIT
+        m.run(1);
+        assert_eq!(m.cpu.pc, 0xFFFE);
+        assert!(m.cpu.halted);
+    }
+
+    #[test]
+    fn strr_out_of_bounds() {
+        for settings in quirks(Variant::CHIP8) {
+            let mut m = Machine::new(settings, &[0xF155]);
+            m.cpu.i = 0xFFF;
+            assert!(matches!(m.step(), Err(Chip8Error::MemoryOutOfBounds { addr: 0x1000 })));
+            assert_eq!(m.cpu.pc, 0x200);
+        }
+    }
+
+    #[test]
+    fn strg_and_ldrg_in_either_order() {
+        for settings in quirks(Variant::XOCHIP) {
+            let mut m = Machine::new(settings.clone(), &[0x5132, 0x5312]);
+            m.cpu.v[1..4].copy_from_slice(&[1, 2, 3]);
+            m.cpu.i = 0x300;
+            m.run(1);
+            assert_eq!(m.peek(0x300, 4), [1, 2, 3, 0]);
+            m.cpu.i = 0x310;
+            m.run(1);
+            assert_eq!(m.peek(0x310, 3), [3, 2, 1]);
+            assert_eq!(m.cpu.i, 0x310);
+
+            let mut m = Machine::new(settings.clone(), &[0x5133, 0x5313]);
+            m.poke(0x300, &[4, 5, 6]);
+            m.cpu.i = 0x300;
+            m.run(1);
+            assert_eq!(m.cpu.v[1..4], [4, 5, 6]);
+            m.run(1);
+            assert_eq!(m.cpu.v[1..4], [6, 5, 4]);
+            assert_eq!(m.cpu.i, 0x300);
+        }
+    }
+
+    #[test]
+    fn strp_and_ldrp() {
+        for settings in quirks(Variant::SCHIP) {
+            let mut m = Machine::new(settings, &[0xF275, 0xF385]);
+            m.cpu.v[..4].copy_from_slice(&[1, 2, 3, 4]);
+            m.run(1);
+            m.cpu.v[..4].copy_from_slice(&[9, 9, 9, 9]);
+            m.run(1);
+            assert_eq!(m.cpu.v[..4], [1, 2, 3, 0]);
+        }
+    }
+
+    #[test]
+    fn scrolling() {
+        for settings in quirks(Variant::XOCHIP) {
+            // SCD 2, SCU 1, SCR, SCL
+            let mut m = Machine::new(settings, &[0x00C2, 0x00D1, 0x00FB, 0x00FC]);
+            m.display.set_pixel(10, 10, 1, true);
+            m.run(1);
+            assert!(m.display.pixel(10, 12));
+            m.run(1);
+            assert!(m.display.pixel(10, 11));
+            m.run(1);
+            assert!(m.display.pixel(14, 11));
+            m.run(1);
+            assert!(m.display.pixel(10, 11));
+            assert_eq!((0..32).map(|y| m.row(y).matches('#').count()).sum::<usize>(), 1);
+        }
+    }
+
+    #[test]
+    fn resolution_and_exit() {
+        for settings in quirks(Variant::SCHIP) {
+            let mut m = Machine::new(settings, &[0x00FF, 0x00FE, 0x00FD]);
+            m.run(1);
+            assert!(m.display.hires());
+            m.run(1);
+            assert!(!m.display.hires());
+            m.run(1);
+            assert!(m.cpu.halted());
+            assert_eq!(m.cpu.pc, 0x204);
+        }
+    }
+
+    #[test]
+    fn xochip_audio_and_long_load() {
+        for settings in quirks(Variant::XOCHIP) {
+            let mut m = Machine::new(settings, &[0xF000, 0x0300, 0xF002, 0xF33A]);
+            let pattern: Vec<u8> = (0..16).collect();
+            m.poke(0x300, &pattern);
+            m.cpu.v[3] = 100;
+            m.run(1);
+            assert_eq!((m.cpu.i, m.cpu.pc), (0x300, 0x204));
+            m.run(2);
+            assert_eq!(m.cpu.audio().0[..], pattern[..]);
+            assert_eq!(m.cpu.audio().1, 100);
+        }
+    }
+
+    #[test]
+    fn invalid_opcodes_are_errors() {
+        for settings in all_settings() {
+            for opcode in [0x5121, 0x812F, 0x9121, 0xE1FF, 0xF1FF] {
+                let mut m = Machine::new(settings.clone(), &[opcode]);
+                assert!(matches!(m.step(), Err(Chip8Error::InvalidOpcode { opcode: o, addr: 0x200 }) if o == opcode));
+                assert_eq!(m.cpu.pc, 0x200);
+            }
         }
     }
 }
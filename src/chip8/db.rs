@@ -0,0 +1,73 @@
+use std::fs;
+use std::path::Path;
+use super::cfg;
+use super::set::Profile;
+use super::sha::sha1_hex;
+
+// Settings for the bundled ROMs, see roms.toml for the format
+const BUILTIN: &str = include_str!("roms.toml");
+
+struct Entry {
+    sha1: String,
+    name: Option<String>,
+    profile: Profile,
+}
+
+// ROM compatibility database. Maps the SHA-1 of a ROM to the settings it needs.
+#[derive(Default)]
+pub struct Database {
+    entries: Vec<Entry>,
+}
+
+impl Database {
+    pub fn new() -> Self {
+        Self { entries: Vec::new() }
+    }
+
+    pub fn builtin() -> Self {
+        let mut database = Self::new();
+        database.parse(BUILTIN).expect("Invalid built-in ROM database");
+        database
+    }
+
+    // Add entries from the text of a database file. Entries added later take precedence.
+    pub fn parse(&mut self, text: &str) -> Result<(), String> {
+        let sections = cfg::parse(text)?;
+
+        if let Some(entry) = sections[0].entries.first() {
+            return Err(format!("line {}: expected a [sha1] section before '{}'", entry.line, entry.key));
+        }
+
+        let mut entries = Vec::new();
+        for section in &sections[1..] {
+            let sha1 = section.name.as_ref().unwrap().to_lowercase();
+            if sha1.len() != 40 || !sha1.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("line {}: '{}' is not a SHA-1 hash", section.line, sha1));
+            }
+
+            // name is only informative, everything else is a setting
+            let name = section.entries.iter().find(|e| e.key == "name").map(|e| e.value.clone());
+            let profile = Profile::from_entries(section.entries.iter().filter(|e| e.key != "name"))?;
+
+            entries.push(Entry { sha1, name, profile });
+        }
+
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        self.parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // Returns the name and settings of the ROM if it's in the database
+    pub fn lookup(&self, rom: &[u8]) -> Option<(Option<&str>, &Profile)> {
+        let sha1 = sha1_hex(rom);
+        self.entries.iter()
+            .rev()
+            .find(|entry| entry.sha1 == sha1)
+            .map(|entry| (entry.name.as_deref(), &entry.profile))
+    }
+}
@@ -0,0 +1,708 @@
+use std::fmt;
+use super::cpu::CPU;
+use super::mem::{Access, Memory};
+use super::op::Op;
+use super::set::{self, Variant};
+
+const HELP: &str = "\
+Commands:
+  c, continue              Resume execution
+  p, pause                 Pause execution
+  s, step [N]              Execute N instructions (default: 1)
+  n, next                  Step over a CALL, executing the whole subroutine
+  f, finish                Run until the current subroutine returns
+  b, break <ADDR>          Break when pc reaches ADDR
+  b, break <ADDR> if <REG> <OP> <VALUE>
+                           Break when pc reaches ADDR and the condition holds
+  b, break if <REG> <OP> <VALUE>
+                           Break when the condition becomes true, e.g. break if V3 == 0x10
+  d, delete [N]            Delete breakpoint N or all breakpoints
+  bl, breakpoints          List breakpoints
+  w, watch <ADDR>[..<END>] [r|w|rw] [log]
+                           Pause when the CPU reads (r), writes (w, default) or accesses (rw) memory from ADDR
+                           to END inclusive, or only print the access with log
+  uw, unwatch [N]          Delete watchpoint N or all watchpoints
+  wl, watchpoints          List watchpoints
+  r, regs                  Show the registers, timers and call stack
+  l, list [ADDR]           Disassemble around ADDR (default: pc)
+  x, mem <ADDR> [LEN]      Dump LEN bytes of memory (default: 16)
+  q, quit                  Quit the emulator
+  h, help                  Show this help
+REG is V0 to VF or I, OP is one of == != < <= > >=, numbers are decimal or 0x hexadecimal.
+";
+
+// Instructions shown before and after pc
+const LIST_BEFORE: u16 = 4;
+const LIST_AFTER: u16 = 6;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Register {
+    V(u8),
+    I,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+struct Condition {
+    register: Register,
+    comparison: Comparison,
+    value: u16,
+}
+
+impl Condition {
+    fn parse(words: &[&str]) -> Result<Self, String> {
+        let (register, comparison, value) = match words {
+            [register, comparison, value] => (register, comparison, value),
+            _ => return Err("expected a condition like 'V3 == 0x10'".to_string()),
+        };
+        let register = match register.to_uppercase().as_str() {
+            "I" => Register::I,
+            name => match name.strip_prefix('V').and_then(|reg| u8::from_str_radix(reg, 16).ok()) {
+                Some(reg) if name.len() == 2 => Register::V(reg),
+                _ => return Err(format!("unknown register '{}'", register)),
+            },
+        };
+        let comparison = match *comparison {
+            "==" => Comparison::Eq,
+            "!=" => Comparison::Ne,
+            "<"  => Comparison::Lt,
+            "<=" => Comparison::Le,
+            ">"  => Comparison::Gt,
+            ">=" => Comparison::Ge,
+            _ => return Err(format!("unknown comparison '{}'", comparison)),
+        };
+        Ok(Self { register, comparison, value: set::parse_number(value)? })
+    }
+
+    fn holds(&self, cpu: &CPU) -> bool {
+        let current = match self.register {
+            Register::V(reg) => cpu.v(reg) as u16,
+            Register::I => cpu.i(),
+        };
+        match self.comparison {
+            Comparison::Eq => current == self.value,
+            Comparison::Ne => current != self.value,
+            Comparison::Lt => current < self.value,
+            Comparison::Le => current <= self.value,
+            Comparison::Gt => current > self.value,
+            Comparison::Ge => current >= self.value,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.register {
+            Register::V(reg) => write!(f, "V{:X}", reg)?,
+            Register::I => write!(f, "I")?,
+        }
+        let comparison = match self.comparison {
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        };
+        write!(f, " {} {:#X}", comparison, self.value)
+    }
+}
+
+struct Breakpoint {
+    addr: Option<u16>,
+    condition: Option<Condition>,
+    // Without an address the breakpoint only triggers when the condition changes from false to true,
+    // otherwise execution couldn't continue past it
+    held: bool,
+}
+
+impl Breakpoint {
+    fn check(&mut self, cpu: &CPU) -> bool {
+        let holds = match &self.condition {
+            Some(condition) => condition.holds(cpu),
+            None => true,
+        };
+        match self.addr {
+            Some(addr) => addr == cpu.pc() && holds,
+            None => {
+                let triggered = holds && !self.held;
+                self.held = holds;
+                triggered
+            }
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.addr, &self.condition) {
+            (Some(addr), Some(condition)) => write!(f, "{:#05X} if {}", addr, condition),
+            (Some(addr), None) => write!(f, "{:#05X}", addr),
+            (None, Some(condition)) => write!(f, "if {}", condition),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+struct Watchpoint {
+    start: u16,
+    end: u16,
+    read: bool,
+    write: bool,
+    // Print accesses instead of pausing
+    log: bool,
+}
+
+impl Watchpoint {
+    fn parse(args: &[&str]) -> Result<Self, String> {
+        let (range, options) = match args.split_first() {
+            Some((range, options)) => (range, options),
+            None => return Err("expected an address or range".to_string()),
+        };
+        let (start, end) = match range.find("..") {
+            Some(i) => (set::parse_number(&range[..i])?, set::parse_number(&range[i + 2..])?),
+            None => {
+                let addr = set::parse_number(range)?;
+                (addr, addr)
+            }
+        };
+        if end < start {
+            return Err(format!("range {} ends before it starts", range));
+        }
+        let mut watchpoint = Self { start, end, read: false, write: true, log: false };
+        for option in options {
+            match *option {
+                "r"   => { watchpoint.read = true; watchpoint.write = false; }
+                "w"   => { watchpoint.read = false; watchpoint.write = true; }
+                "rw"  => { watchpoint.read = true; watchpoint.write = true; }
+                "log" => watchpoint.log = true,
+                _ => return Err(format!("unknown watchpoint option '{}'", option)),
+            }
+        }
+        Ok(watchpoint)
+    }
+
+    // Describes the access if it's watched
+    fn check(&self, pc: u16, access: Access) -> Option<String> {
+        match access {
+            Access::Read { addr, value } if self.read && (self.start..=self.end).contains(&addr) =>
+                Some(format!("read {:#05X} at pc {:#05X}: {:#04X}", addr, pc, value)),
+            Access::Write { addr, old, new } if self.write && (self.start..=self.end).contains(&addr) =>
+                Some(format!("write {:#05X} at pc {:#05X}: {:#04X} -> {:#04X}", addr, pc, old, new)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#05X}", self.start)?;
+        if self.end != self.start {
+            write!(f, "..{:#05X}", self.end)?;
+        }
+        let access = match (self.read, self.write) {
+            (true, true) => "rw",
+            (true, false) => "r",
+            _ => "w",
+        };
+        write!(f, " {}{}", access, if self.log { " log" } else { "" })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Running,
+    Paused,
+    // Instructions left to execute
+    Step(u32),
+    // Until the stack pointer is back at this depth, after a CALL or at the RET of the current subroutine
+    StepOver(u8),
+    StepOut(u8),
+}
+
+pub struct Debugger {
+    mode: Mode,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    // Execute the instruction at pc even if there's a breakpoint on it, set when execution is resumed
+    skip_breakpoint: bool,
+    quit: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            mode: Mode::Running,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            skip_breakpoint: false,
+            quit: false,
+        }
+    }
+
+    pub fn running(&self) -> bool {
+        self.mode != Mode::Paused
+    }
+
+    pub fn quit(&self) -> bool {
+        self.quit
+    }
+
+    pub fn pause(&mut self) {
+        self.mode = Mode::Paused;
+    }
+
+    pub fn resume(&mut self) {
+        self.set_mode(Mode::Running);
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.skip_breakpoint = true;
+    }
+
+    // Called before every instruction, pauses and returns the reason when a breakpoint triggers
+    pub fn check_breakpoints(&mut self, cpu: &CPU) -> Option<String> {
+        let skip = self.skip_breakpoint;
+        self.skip_breakpoint = false;
+        let mut hit = None;
+        // Every breakpoint is checked so conditions keep track of their last value
+        for (n, breakpoint) in self.breakpoints.iter_mut().enumerate() {
+            if breakpoint.check(cpu) && hit.is_none() {
+                hit = Some(n);
+            }
+        }
+        match hit {
+            Some(n) if !skip => {
+                self.mode = Mode::Paused;
+                Some(format!("Breakpoint {} ({}) at {:#05X}", n + 1, self.breakpoints[n], cpu.pc()))
+            }
+            _ => None,
+        }
+    }
+
+    // Memory accesses only need to be recorded while there are watchpoints
+    pub fn has_watchpoints(&self) -> bool {
+        !self.watchpoints.is_empty()
+    }
+
+    // Called after every instruction with the memory accesses it made and its address, pauses unless all
+    // watchpoints that triggered only log. Returns the accesses to report.
+    pub fn check_watchpoints(&mut self, pc: u16, accesses: &[Access]) -> Option<String> {
+        let mut report = String::new();
+        for access in accesses {
+            for (n, watchpoint) in self.watchpoints.iter().enumerate() {
+                if let Some(hit) = watchpoint.check(pc, *access) {
+                    report += &format!("Watchpoint {} ({}): {}\n", n + 1, watchpoint, hit);
+                    if !watchpoint.log {
+                        self.mode = Mode::Paused;
+                    }
+                }
+            }
+        }
+        if report.is_empty() { None } else { Some(report) }
+    }
+
+    // Called after every instruction, returns whether a step finished and execution paused
+    pub fn after_step(&mut self, cpu: &CPU) -> bool {
+        let done = match self.mode {
+            Mode::Step(n) => {
+                self.mode = Mode::Step(n - 1);
+                n == 1
+            }
+            Mode::StepOver(sp) => cpu.sp() <= sp,
+            Mode::StepOut(sp) => cpu.sp() < sp,
+            Mode::Running | Mode::Paused => false,
+        };
+        if done {
+            self.mode = Mode::Paused;
+        }
+        done
+    }
+
+    // Executes one command line and returns what to print
+    pub fn command(&mut self, line: &str, cpu: &CPU, memory: &Memory, variant: Variant) -> String {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return String::new(),
+        };
+        let result = match command {
+            "c" | "continue"        => {
+                self.resume();
+                Ok(String::new())
+            }
+            "p" | "pause"           => {
+                self.pause();
+                Ok(self.view(cpu, memory, variant))
+            }
+            "s" | "step"            => self.step(args),
+            "n" | "next"            => self.next(cpu, memory, variant),
+            "f" | "finish"          => self.finish(cpu),
+            "b" | "break"           => self.add_breakpoint(args),
+            "d" | "delete"          => self.delete_breakpoint(args),
+            "bl" | "breakpoints"    => Ok(self.list_breakpoints()),
+            "w" | "watch"           => self.add_watchpoint(args),
+            "uw" | "unwatch"        => self.delete_watchpoint(args),
+            "wl" | "watchpoints"    => Ok(self.list_watchpoints()),
+            "r" | "regs"            => Ok(format!("{}\n", cpu)),
+            "l" | "list"            => Self::list_command(args, cpu, memory, variant, &self.breakpoints),
+            "x" | "mem"             => Self::dump(args, memory),
+            "q" | "quit"            => {
+                self.quit = true;
+                Ok(String::new())
+            }
+            "h" | "help"            => Ok(HELP.to_string()),
+            _ => Err(format!("unknown command '{}', type 'help' for a list", command)),
+        };
+        result.unwrap_or_else(|error| format!("error: {}\n", error))
+    }
+
+    fn step(&mut self, args: &[&str]) -> Result<String, String> {
+        let n = match args {
+            [] => 1,
+            [n] => set::parse_number(n)?,
+            _ => return Err("expected the number of instructions".to_string()),
+        };
+        if n > 0 {
+            self.set_mode(Mode::Step(n));
+        }
+        Ok(String::new())
+    }
+
+    fn next(&mut self, cpu: &CPU, memory: &Memory, variant: Variant) -> Result<String, String> {
+        let op = Self::fetch(memory, cpu.pc()).map(|opcode| Op::decode(opcode, variant));
+        let call = matches!(op, Some(Op::CALL { .. }));
+        self.set_mode(if call { Mode::StepOver(cpu.sp()) } else { Mode::Step(1) });
+        Ok(String::new())
+    }
+
+    fn finish(&mut self, cpu: &CPU) -> Result<String, String> {
+        if cpu.sp() == 0 {
+            return Err("not in a subroutine".to_string());
+        }
+        self.set_mode(Mode::StepOut(cpu.sp()));
+        Ok(String::new())
+    }
+
+    fn add_breakpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        let (addr, condition) = match args {
+            ["if", condition @ ..] => (None, condition),
+            [addr] => (Some(set::parse_number(addr)?), &[][..]),
+            [addr, "if", condition @ ..] => (Some(set::parse_number(addr)?), condition),
+            _ => return Err("expected an address and/or a condition".to_string()),
+        };
+        let condition = match condition {
+            [] => None,
+            condition => Some(Condition::parse(condition)?),
+        };
+        self.breakpoints.push(Breakpoint { addr, condition, held: false });
+        Ok(format!("Breakpoint {} ({})\n", self.breakpoints.len(), self.breakpoints.last().unwrap()))
+    }
+
+    fn delete_breakpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        match args {
+            [] => self.breakpoints.clear(),
+            [n] => {
+                let n: usize = set::parse_number(n)?;
+                if n == 0 || n > self.breakpoints.len() {
+                    return Err(format!("no breakpoint {}", n));
+                }
+                self.breakpoints.remove(n - 1);
+            }
+            _ => return Err("expected a breakpoint number".to_string()),
+        }
+        Ok(String::new())
+    }
+
+    fn list_breakpoints(&self) -> String {
+        if self.breakpoints.is_empty() {
+            return "No breakpoints\n".to_string();
+        }
+        let mut list = String::new();
+        for (n, breakpoint) in self.breakpoints.iter().enumerate() {
+            list += &format!("{}: {}\n", n + 1, breakpoint);
+        }
+        list
+    }
+
+    fn add_watchpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        self.watchpoints.push(Watchpoint::parse(args)?);
+        Ok(format!("Watchpoint {} ({})\n", self.watchpoints.len(), self.watchpoints.last().unwrap()))
+    }
+
+    fn delete_watchpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        match args {
+            [] => self.watchpoints.clear(),
+            [n] => {
+                let n: usize = set::parse_number(n)?;
+                if n == 0 || n > self.watchpoints.len() {
+                    return Err(format!("no watchpoint {}", n));
+                }
+                self.watchpoints.remove(n - 1);
+            }
+            _ => return Err("expected a watchpoint number".to_string()),
+        }
+        Ok(String::new())
+    }
+
+    fn list_watchpoints(&self) -> String {
+        if self.watchpoints.is_empty() {
+            return "No watchpoints\n".to_string();
+        }
+        let mut list = String::new();
+        for (n, watchpoint) in self.watchpoints.iter().enumerate() {
+            list += &format!("{}: {}\n", n + 1, watchpoint);
+        }
+        list
+    }
+
+    fn list_command(args: &[&str], cpu: &CPU, memory: &Memory, variant: Variant, breakpoints: &[Breakpoint])
+        -> Result<String, String> {
+        let addr = match args {
+            [] => cpu.pc(),
+            [addr] => set::parse_number(addr)?,
+            _ => return Err("expected an address".to_string()),
+        };
+        Ok(Self::list(addr, cpu, memory, variant, breakpoints))
+    }
+
+    // Disassembly around addr, marking pc with '>' and breakpoints with '*'
+    fn list(addr: u16, cpu: &CPU, memory: &Memory, variant: Variant, breakpoints: &[Breakpoint]) -> String {
+        let mut list = String::new();
+        let mut addr = addr.saturating_sub(LIST_BEFORE * 2);
+        for _ in 0..LIST_BEFORE + 1 + LIST_AFTER {
+            let opcode = match Self::fetch(memory, addr) {
+                Some(opcode) => opcode,
+                None => break,
+            };
+            let pc = if addr == cpu.pc() { '>' } else { ' ' };
+            let breakpoint = if breakpoints.iter().any(|b| b.addr == Some(addr)) { '*' } else { ' ' };
+            let (op, len) = match Self::fetch(memory, addr.wrapping_add(2)) {
+                Some(long_addr) if Op::is_long(opcode, variant) => (Op::LDL { addr: long_addr }, 4),
+                _ => (Op::decode(opcode, variant), 2),
+            };
+            let bytes = if len == 4 {
+                format!("{:04X} {:04X}", opcode, Self::fetch(memory, addr.wrapping_add(2)).unwrap_or(0))
+            } else {
+                format!("{:04X}", opcode)
+            };
+            list += &format!("{}{} {:#05X}  {:<9}  {}\n", pc, breakpoint, addr, bytes, op);
+            addr = match addr.checked_add(len) {
+                Some(addr) => addr,
+                None => break,
+            };
+        }
+        list
+    }
+
+    fn dump(args: &[&str], memory: &Memory) -> Result<String, String> {
+        let (addr, len): (u16, u16) = match args {
+            [addr] => (set::parse_number(addr)?, 16),
+            [addr, len] => (set::parse_number(addr)?, set::parse_number(len)?),
+            _ => return Err("expected an address and optionally a length".to_string()),
+        };
+        let mut dump = String::new();
+        for row in (0..len).step_by(16) {
+            let start = addr.wrapping_add(row);
+            dump += &format!("{:#05X}:", start);
+            for offset in row..len.min(row + 16) {
+                let byte = memory.peek(addr.wrapping_add(offset)).map_err(|error| error.to_string())?;
+                dump += &format!(" {:02X}", byte);
+            }
+            dump += "\n";
+        }
+        Ok(dump)
+    }
+
+    // Registers followed by the disassembly around pc, shown whenever execution pauses
+    pub fn view(&self, cpu: &CPU, memory: &Memory, variant: Variant) -> String {
+        format!("{}\n{}", cpu, Self::list(cpu.pc(), cpu, memory, variant, &self.breakpoints))
+    }
+
+    fn fetch(memory: &Memory, addr: u16) -> Option<u16> {
+        let high = memory.peek(addr).ok()?;
+        let low = memory.peek(addr.checked_add(1)?).ok()?;
+        Some(((high as u16) << 8) | low as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::frontend::Headless;
+    use super::super::set::Settings;
+    use super::super::CHIP8;
+
+    // LD V0, 5; CALL 0x208; ADD V0, 1; JP 0x206; LD V1, 3; RET
+    const PROGRAM: [u16; 6] = [0x6005, 0x2208, 0x7001, 0x1206, 0x6103, 0x00EE];
+
+    fn chip8(program: &[u16]) -> CHIP8<Headless> {
+        let mut chip8 = CHIP8::new(Headless, Settings::new());
+        for (i, opcode) in program.iter().enumerate() {
+            let [high, low] = opcode.to_be_bytes();
+            chip8.memory.write(0x200 + 2 * i as u16, high).unwrap();
+            chip8.memory.write(0x201 + 2 * i as u16, low).unwrap();
+        }
+        chip8
+    }
+
+    // Runs frames like CHIP8::run until execution pauses, at most 10. Returns what the debugger reported.
+    fn run(chip8: &mut CHIP8<Headless>) -> String {
+        let mut output = String::new();
+        for _ in 0..10 {
+            if !chip8.debugger.running() {
+                break;
+            }
+            chip8.run_frame_debugged(true, &mut output);
+        }
+        output
+    }
+
+    #[test]
+    fn breakpoints_are_added_listed_and_deleted() {
+        let mut chip8 = chip8(&PROGRAM);
+        assert_eq!(chip8.debug("bl"), "No breakpoints\n");
+        assert_eq!(chip8.debug("b 0x204"), "Breakpoint 1 (0x204)\n");
+        assert_eq!(chip8.debug("break 518 if v0 >= 6"), "Breakpoint 2 (0x206 if V0 >= 0x6)\n");
+        assert_eq!(chip8.debug("b if I == 0"), "Breakpoint 3 (if I == 0x0)\n");
+        assert_eq!(chip8.debug("breakpoints"), "1: 0x204\n2: 0x206 if V0 >= 0x6\n3: if I == 0x0\n");
+        assert_eq!(chip8.debug("d 2"), "");
+        assert_eq!(chip8.debug("bl"), "1: 0x204\n2: if I == 0x0\n");
+        assert_eq!(chip8.debug("delete"), "");
+        assert_eq!(chip8.debug("bl"), "No breakpoints\n");
+    }
+
+    #[test]
+    fn breakpoints_pause_execution() {
+        let mut chip8 = chip8(&PROGRAM);
+        chip8.debug("b 0x204");
+        chip8.debug("b if V0 == 6");
+        assert!(run(&mut chip8).starts_with("\nBreakpoint 1 (0x204) at 0x204\nPC: 0x204"));
+        assert!(!chip8.debugger.running());
+        assert_eq!(chip8.cpu.v(1), 3);
+
+        // Continuing executes the instruction the breakpoint is on
+        chip8.debug("c");
+        assert!(run(&mut chip8).starts_with("\nBreakpoint 2 (if V0 == 0x6) at 0x206\nPC: 0x206"));
+        // The condition stays true, it only triggered when it became true
+        chip8.debug("continue");
+        assert_eq!(run(&mut chip8), "");
+        assert!(chip8.debugger.running());
+    }
+
+    #[test]
+    fn step_next_and_finish() {
+        let mut chip8 = chip8(&PROGRAM);
+        chip8.debug("pause");
+        chip8.debug("s 2");
+        assert!(run(&mut chip8).starts_with("PC: 0x208  I: 0x000  SP: 1"));
+
+        chip8.debug("finish");
+        assert!(run(&mut chip8).starts_with("PC: 0x204  I: 0x000  SP: 0"));
+        assert!(!chip8.debugger.running());
+
+        let mut chip8 = self::chip8(&PROGRAM);
+        chip8.debug("p");
+        chip8.debug("n");
+        run(&mut chip8);
+        assert_eq!(chip8.cpu.pc(), 0x202);
+        // Over the whole subroutine
+        chip8.debug("next");
+        run(&mut chip8);
+        assert_eq!((chip8.cpu.pc(), chip8.cpu.v(1)), (0x204, 3));
+        chip8.debug("step 0");
+        assert_eq!(run(&mut chip8), "");
+        assert_eq!(chip8.cpu.pc(), 0x204);
+    }
+
+    #[test]
+    fn list_marks_pc_and_breakpoints() {
+        let mut chip8 = chip8(&PROGRAM);
+        chip8.debug("b 0x202");
+        assert_eq!(chip8.debug("l"), chip8.debug("l 0x200"));
+        assert_eq!(chip8.debug("list 0x208"), "\
+>  0x200  6005       LD V0 0x05
+ * 0x202  2208       CALL 0x208
+   0x204  7001       ADD V0 0x01
+   0x206  1206       JP 0x206
+   0x208  6103       LD V1 0x03
+   0x20A  00EE       RET
+   0x20C  0000       SYS 0x000
+   0x20E  0000       SYS 0x000
+   0x210  0000       SYS 0x000
+   0x212  0000       SYS 0x000
+   0x214  0000       SYS 0x000
+");
+        // Stops at the end of memory
+        assert_eq!(chip8.debug("l 0xFFE").lines().last(), Some("   0xFFE  0000       SYS 0x000"));
+    }
+
+    #[test]
+    fn malformed_commands_are_rejected() {
+        let mut chip8 = chip8(&PROGRAM);
+        let errors = [
+            ("jump", "unknown command 'jump', type 'help' for a list"),
+            ("b", "expected an address and/or a condition"),
+            ("b 0x20G", "expected a number, got '0x20G'"),
+            ("b 0x10000", "number 0x10000 is too large"),
+            ("b if V3", "expected a condition like 'V3 == 0x10'"),
+            ("b if VG == 1", "unknown register 'VG'"),
+            ("b if V10 == 1", "unknown register 'V10'"),
+            ("b 0x200 if V3 =< 1", "unknown comparison '=<'"),
+            ("d 1", "no breakpoint 1"),
+            ("d 1 2", "expected a breakpoint number"),
+            ("s 1 2", "expected the number of instructions"),
+            ("f", "not in a subroutine"),
+            ("w", "expected an address or range"),
+            ("w 0x301..0x300", "range 0x301..0x300 ends before it starts"),
+            ("w 0x300 x", "unknown watchpoint option 'x'"),
+            ("uw 0", "no watchpoint 0"),
+            ("l 0x200 0x300", "expected an address"),
+            ("x", "expected an address and optionally a length"),
+            ("x 0xFFF 2", "Memory access out of bounds at 0x1000"),
+        ];
+        for (command, error) in errors {
+            assert_eq!(chip8.debug(command), format!("error: {}\n", error), "{}", command);
+        }
+        assert_eq!(chip8.debug("  "), "");
+        assert_eq!(chip8.debug("bl"), "No breakpoints\n");
+        assert_eq!(chip8.debug("wl"), "No watchpoints\n");
+    }
+
+    #[test]
+    fn watchpoints_pause_or_log() {
+        // LD I, 0x300; LD V0, 7; LD [I], V0; LD I, 0x300; LD V0, [I]; JP 0x20A
+        let mut chip8 = chip8(&[0xA300, 0x6007, 0xF055, 0xA300, 0xF065, 0x120A]);
+        assert_eq!(chip8.debug("w 0x300"), "Watchpoint 1 (0x300 w)\n");
+        assert!(run(&mut chip8).starts_with("\nWatchpoint 1 (0x300 w): write 0x300 at pc 0x204: 0x00 -> 0x07\nPC: 0x206"));
+        assert!(!chip8.debugger.running());
+
+        chip8.debug("uw");
+        assert_eq!(chip8.debug("watch 0x2FF..0x301 r log"), "Watchpoint 1 (0x2FF..0x301 r log)\n");
+        assert_eq!(chip8.debug("wl"), "1: 0x2FF..0x301 r log\n");
+        chip8.debug("c");
+        assert_eq!(run(&mut chip8), "\nWatchpoint 1 (0x2FF..0x301 r log): read 0x300 at pc 0x208: 0x07\n");
+        assert!(chip8.debugger.running());
+        assert_eq!(chip8.debug("x 0x300 2"), "0x300: 07 00\n");
+    }
+
+    #[test]
+    fn fetching_instructions_is_not_a_read() {
+        // LD I, 0x200; LD V0, [I]; JP 0x204
+        let mut chip8 = chip8(&[0xA200, 0xF065, 0x1204]);
+        chip8.debug("w 0x200..0x205 r");
+        assert!(run(&mut chip8).starts_with("\nWatchpoint 1 (0x200..0x205 r): read 0x200 at pc 0x202: 0xA2\nPC: 0x204"));
+        // Only the jump runs from now on
+        chip8.debug("c");
+        assert_eq!(run(&mut chip8), "");
+        assert!(chip8.debugger.running());
+    }
+}
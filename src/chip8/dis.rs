@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+use super::op::Op;
+use super::set::{Settings, Variant};
+
+// Data bytes per db line, sprites get one line per row
+const DATA_PER_LINE: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Byte {
+    Unknown,
+    // First byte of an instruction of the given length
+    Code(u16),
+    // Part of an instruction started before
+    Operand,
+}
+
+// Kinds of labels, the most important one is used when an address is referenced in several ways
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Label {
+    Data,
+    Sprite,
+    Jump,
+    Call,
+}
+
+struct Analysis<'a> {
+    rom: &'a [u8],
+    rom_addr: u16,
+    variant: Variant,
+    bytes: Vec<Byte>,
+    labels: BTreeMap<u16, Label>,
+}
+
+impl<'a> Analysis<'a> {
+    fn contains(&self, addr: u16) -> bool {
+        addr >= self.rom_addr && ((addr - self.rom_addr) as usize) < self.rom.len()
+    }
+
+    fn word(&self, addr: u16) -> Option<u16> {
+        let i = addr.checked_sub(self.rom_addr)? as usize;
+        self.rom.get(i..i + 2).map(|word| ((word[0] as u16) << 8) | word[1] as u16)
+    }
+
+    // The instruction at addr and its length, None past the end of the ROM
+    fn decode(&self, addr: u16) -> Option<(Op, u16)> {
+        let opcode = self.word(addr)?;
+        if Op::is_long(opcode, self.variant) {
+            let long_addr = self.word(addr.checked_add(2)?)?;
+            Some((Op::LDL { addr: long_addr }, 4))
+        } else {
+            Some((Op::decode(opcode, self.variant), 2))
+        }
+    }
+
+    fn label(&mut self, addr: u16, label: Label) {
+        let entry = self.labels.entry(addr).or_insert(label);
+        *entry = (*entry).max(label);
+    }
+
+    // Whether I is set to point at a sprite, that is whether it's used by DRW before anything else uses or
+    // replaces it, looking at the instructions that follow up to the next jump
+    fn reference(&self, mut addr: u16) -> Label {
+        for _ in 0..8 {
+            match self.decode(addr) {
+                Some((Op::DRW { .. }, _)) => return Label::Sprite,
+                Some((Op::STRG { .. }, _)) | Some((Op::LDRG { .. }, _)) | Some((Op::LDAU, _)) |
+                Some((Op::ADDA { .. }, _)) | Some((Op::STDR { .. }, _)) | Some((Op::STRR { .. }, _)) |
+                Some((Op::LDRR { .. }, _)) | Some((Op::LDA { .. }, _)) | Some((Op::LDL { .. }, _)) |
+                Some((Op::JP { .. }, _)) | Some((Op::CALL { .. }, _)) | Some((Op::JPO { .. }, _)) |
+                Some((Op::RET, _)) | Some((Op::EXIT, _)) | None => return Label::Data,
+                Some((_, len)) => addr = addr.wrapping_add(len),
+            }
+        }
+        Label::Data
+    }
+
+    // Follows every path through the code from the entry point
+    fn trace(&mut self) {
+        let mut pending = vec![self.rom_addr];
+        while let Some(addr) = pending.pop() {
+            if !self.contains(addr) || self.bytes[(addr - self.rom_addr) as usize] != Byte::Unknown {
+                continue;
+            }
+            let (op, len) = match self.decode(addr) {
+                Some((Op::INV { .. }, _)) | None => continue,
+                Some(decoded) => decoded,
+            };
+            let i = (addr - self.rom_addr) as usize;
+            if self.bytes[i + 1..i + len as usize].iter().any(|byte| *byte != Byte::Unknown) {
+                continue;
+            }
+            self.bytes[i] = Byte::Code(len);
+            for byte in &mut self.bytes[i + 1..i + len as usize] {
+                *byte = Byte::Operand;
+            }
+
+            let next = addr.wrapping_add(len);
+            match op {
+                Op::JP { addr: target } => {
+                    self.label(target, Label::Jump);
+                    pending.push(target);
+                }
+                Op::CALL { addr: target } => {
+                    self.label(target, Label::Call);
+                    pending.push(target);
+                    pending.push(next);
+                }
+                // Usually a jump table, whose first entry is at the target
+                Op::JPO { addr: target } => {
+                    self.label(target, Label::Jump);
+                    pending.push(target);
+                }
+                Op::RET | Op::EXIT => {}
+                Op::SE { .. } | Op::SNE { .. } | Op::SER { .. } | Op::SNER { .. } | Op::SKP { .. } | Op::SKNP { .. } => {
+                    // The skipped instruction can be the 4 byte long F000 nnnn
+                    let skipped = match self.decode(next) {
+                        Some((_, skipped_len)) => skipped_len,
+                        None => 2,
+                    };
+                    pending.push(next);
+                    pending.push(next.wrapping_add(skipped));
+                }
+                Op::LDA { addr: target } | Op::LDL { addr: target } => {
+                    let label = self.reference(next);
+                    self.label(target, label);
+                    pending.push(next);
+                }
+                _ => pending.push(next),
+            }
+        }
+
+        // Labels are only useful where a line starts, anything else is shown as a number
+        let bytes = &self.bytes;
+        let rom_addr = self.rom_addr;
+        let rom_len = self.rom.len();
+        self.labels.retain(|addr, _| {
+            *addr >= rom_addr && ((*addr - rom_addr) as usize) < rom_len && bytes[(*addr - rom_addr) as usize] != Byte::Operand
+        });
+        // Sprites are only recognized outside the code, other references into data get plain data labels
+        for (addr, label) in self.labels.iter_mut() {
+            if *label == Label::Sprite && self.bytes[(*addr - self.rom_addr) as usize] != Byte::Unknown {
+                *label = Label::Data;
+            }
+        }
+    }
+
+    fn label_name(&self, addr: u16) -> Option<String> {
+        let prefix = match self.labels.get(&addr)? {
+            Label::Data => "data",
+            Label::Sprite => "sprite",
+            Label::Jump => "loc",
+            Label::Call => "sub",
+        };
+        Some(format!("{}_{:03X}", prefix, addr))
+    }
+
+    fn operand(&self, addr: u16) -> String {
+        self.label_name(addr).unwrap_or_else(|| format!("{:#05X}", addr))
+    }
+
+    fn instruction(&self, op: &Op) -> String {
+        match *op {
+            Op::JP   { addr } => format!("{} {}", op.name(), self.operand(addr)),
+            Op::CALL { addr } => format!("{} {}", op.name(), self.operand(addr)),
+            Op::LDA  { addr } => format!("{} {}", op.name(), self.operand(addr)),
+            Op::JPO  { addr } => format!("{} {}", op.name(), self.operand(addr)),
+            Op::LDL  { addr } => format!("{} {}", op.name(), self.label_name(addr).unwrap_or_else(|| format!("{:#06X}", addr))),
+            _ => op.to_string(),
+        }
+    }
+
+    fn listing(&self) -> String {
+        let mut listing = String::new();
+        listing += &format!("; {} bytes at {:#05X}, {}\n", self.rom.len(), self.rom_addr, self.variant.name());
+
+        let mut i = 0;
+        while i < self.rom.len() {
+            let addr = self.rom_addr + i as u16;
+            if let Some(name) = self.label_name(addr) {
+                listing += &format!("\n{}:\n", name);
+            }
+
+            if let Byte::Code(len) = self.bytes[i] {
+                let (op, _) = self.decode(addr).unwrap();
+                let hex: Vec<String> = self.rom[i..i + len as usize].chunks(2)
+                    .map(|word| word.iter().map(|byte| format!("{:02X}", byte)).collect())
+                    .collect();
+                listing += &line(&self.instruction(&op), addr, &hex.join(" "));
+                i += len as usize;
+                continue;
+            }
+
+            // Data runs until the next label or instruction
+            let mut end = i + 1;
+            while end < self.rom.len() && self.bytes[end] == Byte::Unknown && !self.labels.contains_key(&(self.rom_addr + end as u16)) {
+                end += 1;
+            }
+            if self.labels.get(&addr) == Some(&Label::Sprite) {
+                for (row, byte) in self.rom[i..end].iter().enumerate() {
+                    let pixels: String = (0..8).map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' }).collect();
+                    listing += &line(&format!("db {:#010b}", byte), addr + row as u16, &pixels);
+                }
+            } else {
+                for (n, chunk) in self.rom[i..end].chunks(DATA_PER_LINE).enumerate() {
+                    let bytes: Vec<String> = chunk.iter().map(|byte| format!("{:#04X}", byte)).collect();
+                    listing += &line(&format!("db {}", bytes.join(" ")), addr + (n * DATA_PER_LINE) as u16, "");
+                }
+            }
+            i = end;
+        }
+        listing
+    }
+}
+
+// Assembly on the left, address and raw bytes in a comment on the right
+fn line(text: &str, addr: u16, comment: &str) -> String {
+    format!("    {:<32} ; {:#05X}  {}\n", text, addr, comment).trim_end().to_string() + "\n"
+}
+
+// Static disassembly of a ROM loaded at Settings::rom_addr, code is separated from data by following
+// every jump, call and skip from the entry point
+pub fn disassemble(rom: &[u8], settings: &Settings) -> String {
+    let mut analysis = Analysis {
+        rom,
+        rom_addr: settings.rom_addr,
+        variant: settings.variant,
+        bytes: vec![Byte::Unknown; rom.len()],
+        labels: BTreeMap::new(),
+    };
+    analysis.trace();
+    analysis.listing()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(rom: &[u8], variant: Variant) -> String {
+        let mut settings = Settings::new();
+        settings.variant = variant;
+        disassemble(rom, &settings)
+    }
+
+    #[test]
+    fn code_calls_and_sprites() {
+        let rom = [0xA2, 0x0B, 0xD0, 0x11, 0x22, 0x08, 0x12, 0x06, 0x00, 0xEE, 0x42, 0x81, 0x60, 0x05, 0x01];
+        assert_eq!(listing(&rom, Variant::CHIP8), "\
+; 15 bytes at 0x200, chip8
+    LDA sprite_20B                   ; 0x200  A20B
+    DRW V0 V1 1                      ; 0x202  D011
+    CALL sub_208                     ; 0x204  2208
+
+loc_206:
+    JP loc_206                       ; 0x206  1206
+
+sub_208:
+    RET                              ; 0x208  00EE
+    db 0x42                          ; 0x20A
+
+sprite_20B:
+    db 0b10000001                    ; 0x20B  #......#
+    db 0b01100000                    ; 0x20C  .##.....
+    db 0b00000101                    ; 0x20D  .....#.#
+    db 0b00000001                    ; 0x20E  .......#
+");
+    }
+
+    #[test]
+    fn odd_addresses_and_long_instructions() {
+        let rom = [0x12, 0x03, 0xFF, 0x60, 0x05, 0xF0, 0x00, 0x02, 0x0D, 0xD0, 0x11, 0x00, 0xEE, 0x3C];
+        assert_eq!(listing(&rom, Variant::XOCHIP), "\
+; 14 bytes at 0x200, xochip
+    JP loc_203                       ; 0x200  1203
+    db 0xFF                          ; 0x202
+
+loc_203:
+    LD V0 0x05                       ; 0x203  6005
+    LDL sprite_20D                   ; 0x205  F000 020D
+    DRW V0 V1 1                      ; 0x209  D011
+    RET                              ; 0x20B  00EE
+
+sprite_20D:
+    db 0b00111100                    ; 0x20D  ..####..
+");
+        // F000 is invalid before XO-CHIP, where the code ends
+        assert_eq!(listing(&rom, Variant::CHIP8), "\
+; 14 bytes at 0x200, chip8
+    JP loc_203                       ; 0x200  1203
+    db 0xFF                          ; 0x202
+
+loc_203:
+    LD V0 0x05                       ; 0x203  6005
+    db 0xF0 0x00 0x02 0x0D 0xD0 0x11 0x00 0xEE ; 0x205
+    db 0x3C                          ; 0x20D
+");
+    }
+}
@@ -1,69 +1,188 @@
-use super::super::platform::Platform;
+use super::super::frontend::Frontend;
 use std::fmt;
+use super::err::Chip8Error;
+use super::sav::{self, Reader, Writer};
 
 const DISPLAY_W: usize = 64;
 const DISPLAY_H: usize = 32;
 
+// SCHIP high resolution mode
+const HIRES_DISPLAY_W: usize = 128;
+const HIRES_DISPLAY_H: usize = 64;
+
+// XO-CHIP has two bit planes, every pixel is a bit mask of the planes it's set in
+pub const PLANES: u8 = 2;
+
 pub struct Display {
-    pixels: [[bool; DISPLAY_H]; DISPLAY_W],
+    // Large enough for high resolution mode, only the top left 64x32 pixels are used in low resolution mode
+    pixels: [[u8; HIRES_DISPLAY_H]; HIRES_DISPLAY_W],
+    hires: bool,
+    // Bit mask of the planes drawn to, cleared and scrolled. Only XO-CHIP can select anything other than plane 1.
+    planes: u8,
     redraw: bool,
 }
 
 impl Display {
-    pub fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
-            pixels: [[false; DISPLAY_H]; DISPLAY_W],
+            pixels: [[0; HIRES_DISPLAY_H]; HIRES_DISPLAY_W],
+            hires: false,
+            planes: 1,
             redraw: true,
         }
     }
 
+    pub fn save(&self, w: &mut Writer) {
+        w.bool(self.hires);
+        w.u8(self.planes);
+        for column in self.pixels.iter() {
+            w.bytes(column);
+        }
+    }
+
+    pub fn load(r: &mut Reader) -> Result<Self, Chip8Error> {
+        let mut display = Self::new();
+        display.hires = r.bool()?;
+        display.planes = r.u8()?;
+        for column in display.pixels.iter_mut() {
+            column.copy_from_slice(r.bytes(HIRES_DISPLAY_H)?);
+        }
+        let all_planes = (1 << PLANES) - 1;
+        if display.planes & !all_planes != 0 || display.pixels.iter().flatten().any(|pixel| pixel & !all_planes != 0) {
+            return Err(sav::invalid("display uses planes that don't exist".to_string()));
+        }
+        Ok(display)
+    }
+
+    // Clears the selected planes
     pub fn clear(&mut self) {
-        self.pixels = [[false; DISPLAY_H]; DISPLAY_W];
+        let keep = !self.planes;
+        for column in self.pixels.iter_mut() {
+            for pixel in column.iter_mut() {
+                *pixel &= keep;
+            }
+        }
+        self.redraw = true;
     }
 
-    pub fn set_pixel(&mut self, x: usize, y: usize, pixel: bool) -> bool {
-        let x = x % DISPLAY_W;
-        let y = y % DISPLAY_H;
-        if self.pixels[x][y] != pixel {
-            self.redraw = true;
+    // Flips the pixel in one plane, returns whether it was erased
+    pub fn set_pixel(&mut self, x: usize, y: usize, plane: u8, pixel: bool) -> bool {
+        let x = x % self.width();
+        let y = y % self.height();
+        if !pixel {
+            return false;
         }
-        let erased = self.pixels[x][y] && pixel; // pixel changed from 1 to 0
-        self.pixels[x][y] ^= pixel;
+        self.redraw = true;
+        let erased = self.pixels[x][y] & plane != 0; // pixel changed from 1 to 0
+        self.pixels[x][y] ^= plane;
         erased
     }
 
+    // Switching the resolution clears the screen
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.pixels = [[0; HIRES_DISPLAY_H]; HIRES_DISPLAY_W];
+        self.redraw = true;
+    }
+
+    pub fn hires(&self) -> bool {
+        self.hires
+    }
+
+    pub fn select_planes(&mut self, planes: u8) {
+        self.planes = planes & ((1 << PLANES) - 1);
+    }
+
+    pub fn planes(&self) -> u8 {
+        self.planes
+    }
+
+    // Moves the selected planes by dx, dy pixels, filling in with blank pixels
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (w, h) = (self.width() as isize, self.height() as isize);
+        let mask = self.planes;
+        let old = self.pixels;
+        for x in 0..w {
+            for y in 0..h {
+                let (src_x, src_y) = (x - dx, y - dy);
+                let src = if src_x >= 0 && src_x < w && src_y >= 0 && src_y < h {
+                    old[src_x as usize][src_y as usize]
+                } else {
+                    0
+                };
+                let pixel = &mut self.pixels[x as usize][y as usize];
+                *pixel = (*pixel & !mask) | (src & mask);
+            }
+        }
+        self.redraw = true;
+    }
+
+    pub fn scroll_down(&mut self, n: usize) {
+        self.scroll(0, n as isize);
+    }
+
+    pub fn scroll_up(&mut self, n: usize) {
+        self.scroll(0, -(n as isize));
+    }
+
+    pub fn scroll_right(&mut self, n: usize) {
+        self.scroll(n as isize, 0);
+    }
+
+    pub fn scroll_left(&mut self, n: usize) {
+        self.scroll(-(n as isize), 0);
+    }
+
+    pub fn width(&self) -> usize {
+        if self.hires { HIRES_DISPLAY_W } else { DISPLAY_W }
+    }
+
     pub fn height(&self) -> usize {
-        DISPLAY_H
+        if self.hires { HIRES_DISPLAY_H } else { DISPLAY_H }
+    }
+
+    // Whether the pixel is set in any plane
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.pixels[x][y] != 0
+    }
+
+    // Bit mask of the planes the pixel is set in, 0 to 3
+    pub fn color(&self, x: usize, y: usize) -> u8 {
+        self.pixels[x][y]
     }
 
-    pub fn draw(&mut self, platform: &mut Platform) {
-        for y in 0..DISPLAY_H {
-            for x in 0..DISPLAY_W {
-                if self.pixels[x][y] {
-                    platform.draw_pixel(x as u8, y as u8);
+    pub fn draw<F: Frontend>(&mut self, frontend: &mut F) {
+        frontend.set_resolution(self.width() as u8, self.height() as u8);
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                if self.pixels[x][y] != 0 {
+                    frontend.draw_pixel(x as u8, y as u8, self.pixels[x][y]);
                 }
             }
         }
-        self.redraw = false;
     }
 
-    pub fn redraw(&self) -> bool {
-        self.redraw
+    // Returns whether the display changed since the last call
+    pub fn take_redraw(&mut self) -> bool {
+        let redraw = self.redraw;
+        self.redraw = false;
+        redraw
     }
 }
 
 impl fmt::Display for Display {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for y in 0..DISPLAY_H {
-            for x in 0..DISPLAY_W {
-                if self.pixels[x][y] {
-                    write!(f, "\u{2588}")?;
-                } else {
-                    write!(f, " ")?;
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                match self.pixels[x][y] {
+                    0 => write!(f, " ")?,
+                    1 => write!(f, "\u{2588}")?,
+                    2 => write!(f, "\u{2591}")?,
+                    _ => write!(f, "\u{2593}")?,
                 }
             }
             writeln!(f)?;
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,73 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use super::asm::AsmError;
+
+pub enum Chip8Error {
+    InvalidOpcode { opcode: u16, addr: u16 },
+    // CALL with all 16 stack entries in use
+    StackOverflow { addr: u16 },
+    // RET with an empty stack
+    StackUnderflow { addr: u16 },
+    MemoryOutOfBounds { addr: u16 },
+    RomTooLarge { size: usize, max_size: usize },
+    // Octo source that doesn't compile
+    Compile(AsmError),
+    Io(io::Error),
+    PlatformInit(String),
+    // A key name in the keymap the frontend doesn't know
+    InvalidKeymap(String),
+    // Corrupted or truncated save state, or one written by an incompatible version
+    InvalidState(String),
+    // The save state was made with another ROM than the one loaded
+    StateRomMismatch,
+    // Corrupted or truncated movie file
+    InvalidMovie(String),
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidOpcode { opcode, addr } => write!(f, "Invalid opcode {:#06X} at {:#05X}", opcode, addr),
+            Self::StackOverflow { addr }         => write!(f, "Stack overflow at {:#05X}", addr),
+            Self::StackUnderflow { addr }        => write!(f, "Stack underflow at {:#05X}", addr),
+            Self::MemoryOutOfBounds { addr }     => write!(f, "Memory access out of bounds at {:#05X}", addr),
+            Self::RomTooLarge { size, max_size } => write!(f, "ROM too large: {} bytes, at most {} fit", size, max_size),
+            Self::Compile(error)                 => write!(f, "{}", error),
+            Self::Io(error)                      => write!(f, "{}", error),
+            Self::PlatformInit(error)            => write!(f, "Can't init platform: {}", error),
+            Self::InvalidKeymap(reason)          => write!(f, "Invalid keymap: {}", reason),
+            Self::InvalidState(reason)           => write!(f, "Invalid save state: {}", reason),
+            Self::StateRomMismatch               => write!(f, "Save state was made with a different ROM"),
+            Self::InvalidMovie(reason)           => write!(f, "Invalid movie: {}", reason),
+        }
+    }
+}
+
+impl fmt::Debug for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Error for Chip8Error {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Compile(error) => Some(error),
+            Self::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<AsmError> for Chip8Error {
+    fn from(error: AsmError) -> Self {
+        Self::Compile(error)
+    }
+}
+
+impl From<io::Error> for Chip8Error {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
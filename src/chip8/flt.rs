@@ -0,0 +1,182 @@
+use super::super::frontend::Frontend;
+use super::dsp::Display;
+
+// How the display is shown. Games erase and redraw sprites with XOR, so they flicker when every frame is shown as it
+// is. The filters only change what is drawn, never the display the program sees.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    // Every frame as it is
+    Off,
+    // Like the phosphor of a CRT, pixels fade out over decay_frames frames after they are cleared
+    Decay,
+    // Pixels set in the last frame or the one before it
+    Or,
+}
+
+impl Filter {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Decay => "decay",
+            Self::Or => "or",
+        }
+    }
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "off" => Ok(Self::Off),
+            "decay" => Ok(Self::Decay),
+            "or" => Ok(Self::Or),
+            _ => Err(format!("expected off, decay or or, got '{}'", value)),
+        }
+    }
+}
+
+// What the filter shows, updated from the display once per frame. Pixels are stored column by column like in Display.
+pub struct Persistence {
+    width: usize,
+    height: usize,
+    // The planes each pixel was last set in and its intensity, 255 while it's set
+    colors: Vec<u8>,
+    levels: Vec<u8>,
+    // The planes each pixel was set in at the end of the previous frame
+    previous: Vec<u8>,
+}
+
+impl Persistence {
+    pub fn new() -> Self {
+        Self { width: 0, height: 0, colors: Vec::new(), levels: Vec::new(), previous: Vec::new() }
+    }
+
+    // Shows the display as it is, forgetting the frames before it, e.g. after a state was loaded
+    pub fn reset(&mut self, display: &Display) {
+        self.width = display.width();
+        self.height = display.height();
+        self.colors = self.pixels(display).collect();
+        self.levels = self.colors.iter().map(|&color| if color != 0 { 255 } else { 0 }).collect();
+        self.previous = self.colors.clone();
+    }
+
+    // Takes in the display at the end of a frame. Returns whether what draw shows changed.
+    pub fn update(&mut self, display: &Display, filter: Filter, decay_frames: u8) -> bool {
+        // The resolution changed, the display was cleared anyway
+        if display.width() != self.width || display.height() != self.height {
+            self.reset(display);
+            return true;
+        }
+
+        // Levels count down to 0 in decay_frames steps
+        let step = 255_u16.div_ceil(decay_frames.max(1) as u16) as u8;
+        let mut changed = false;
+        for (i, color) in self.pixels(display).enumerate() {
+            let (new_color, new_level) = match filter {
+                _ if color != 0 && filter != Filter::Or => (color, 255),
+                Filter::Off => (self.colors[i], 0),
+                Filter::Decay => (self.colors[i], self.levels[i].saturating_sub(step)),
+                Filter::Or => match color | self.previous[i] {
+                    0 => (self.colors[i], 0),
+                    both => (both, 255),
+                },
+            };
+            let shown = |color, level| if level != 0 { color } else { 0 };
+            changed |= shown(new_color, new_level) != shown(self.colors[i], self.levels[i])
+                || new_level != self.levels[i];
+            self.colors[i] = new_color;
+            self.levels[i] = new_level;
+            self.previous[i] = color;
+        }
+        changed
+    }
+
+    // The planes the pixel is shown with and its intensity, 0 when it's not shown
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, u8) {
+        let i = x * self.height + y;
+        (self.colors[i], self.levels[i])
+    }
+
+    pub fn draw<F: Frontend>(&self, frontend: &mut F) {
+        frontend.set_resolution(self.width as u8, self.height as u8);
+        for x in 0..self.width {
+            for y in 0..self.height {
+                match self.pixel(x, y) {
+                    (_, 0) => {}
+                    (color, 255) => frontend.draw_pixel(x as u8, y as u8, color),
+                    (color, level) => frontend.draw_faded_pixel(x as u8, y as u8, color, level),
+                }
+            }
+        }
+    }
+
+    fn pixels<'a>(&self, display: &'a Display) -> impl Iterator<Item = u8> + 'a {
+        let height = display.height();
+        (0..display.width()).flat_map(move |x| (0..height).map(move |y| display.color(x, y)))
+    }
+}
+
+impl Default for Persistence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn display(pixels: &[(usize, usize)]) -> Display {
+        let mut display = Display::new();
+        for &(x, y) in pixels {
+            display.set_pixel(x, y, 1, true);
+        }
+        display
+    }
+
+    #[test]
+    fn decay_fades_pixels_out() {
+        let mut persistence = Persistence::new();
+        persistence.reset(&display(&[(1, 2)]));
+        let mut levels = Vec::new();
+        for _ in 0..4 {
+            assert!(persistence.update(&display(&[]), Filter::Decay, 4));
+            levels.push(persistence.pixel(1, 2).1);
+        }
+        assert_eq!(levels, [191, 127, 63, 0]);
+        assert_eq!(persistence.pixel(1, 2).0, 1);
+        assert!(!persistence.update(&display(&[]), Filter::Decay, 4));
+
+        assert!(persistence.update(&display(&[(1, 2)]), Filter::Decay, 4));
+        assert_eq!(persistence.pixel(1, 2), (1, 255));
+    }
+
+    #[test]
+    fn or_shows_the_last_two_frames() {
+        let mut persistence = Persistence::new();
+        persistence.reset(&display(&[]));
+        assert!(persistence.update(&display(&[(0, 0)]), Filter::Or, 4));
+        assert_eq!(persistence.pixel(0, 0), (1, 255));
+        // Erased to be drawn again in the next frame
+        assert!(!persistence.update(&display(&[]), Filter::Or, 4));
+        assert_eq!(persistence.pixel(0, 0), (1, 255));
+        assert!(!persistence.update(&display(&[(0, 0)]), Filter::Or, 4));
+        assert!(!persistence.update(&display(&[]), Filter::Or, 4));
+        assert!(persistence.update(&display(&[]), Filter::Or, 4));
+        assert_eq!(persistence.pixel(0, 0).1, 0);
+    }
+
+    #[test]
+    fn off_shows_the_display() {
+        let mut persistence = Persistence::new();
+        persistence.reset(&display(&[(3, 4)]));
+        assert!(!persistence.update(&display(&[(3, 4)]), Filter::Off, 4));
+        assert!(persistence.update(&display(&[]), Filter::Off, 4));
+        assert_eq!(persistence.pixel(3, 4).1, 0);
+    }
+
+    #[test]
+    fn parse_checks_names() {
+        for filter in [Filter::Off, Filter::Decay, Filter::Or] {
+            assert_eq!(Filter::parse(filter.name()), Ok(filter));
+        }
+        assert!(Filter::parse("blur").is_err());
+    }
+}
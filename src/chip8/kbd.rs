@@ -1,5 +1,9 @@
+use super::err::Chip8Error;
+use super::sav::{Reader, Writer};
+
 pub struct Keyboard {
     keys: [bool; 16],
+    // The key pressed since LDKP (Fx0A) started waiting
     kp: Option<u8>,
     kp_wait: bool,
 }
@@ -13,29 +17,74 @@ impl Keyboard {
         }
     }
 
+    pub fn save(&self, w: &mut Writer) {
+        for key in &self.keys {
+            w.bool(*key);
+        }
+        w.bool(self.kp.is_some());
+        w.u8(self.kp.unwrap_or(0));
+        w.bool(self.kp_wait);
+    }
+
+    pub fn load(r: &mut Reader) -> Result<Self, Chip8Error> {
+        let mut keyboard = Self::new();
+        for key in keyboard.keys.iter_mut() {
+            *key = r.bool()?;
+        }
+        let has_kp = r.bool()?;
+        let kp = r.u8()? & 0xF;
+        keyboard.kp = if has_kp { Some(kp) } else { None };
+        keyboard.kp_wait = r.bool()?;
+        Ok(keyboard)
+    }
+
+    // Keys that went down since the last call count as presses, the same as the ones given to push_keypress
     pub fn set_keys(&mut self, keys: [bool; 16]) {
+        let previous = self.keys;
+        for (key, (&down, &was_down)) in keys.iter().zip(&previous).enumerate() {
+            if down && !was_down {
+                self.push_keypress(key as u8);
+            }
+        }
         self.keys = keys;
     }
 
+    pub fn keys(&self) -> [bool; 16] {
+        self.keys
+    }
+
     pub fn get_key(&self, key: u8) -> bool {
         self.keys[key as usize]
     }
 
+    // Only the first key pressed during a wait counts
     pub fn push_keypress(&mut self, key: u8) {
-        if self.kp_wait {
+        if self.kp_wait && self.kp.is_none() {
             self.kp = Some(key);
         }
     }
 
-    pub fn wait_keypress(&mut self) -> Option<u8> {
+    pub fn waiting(&self) -> bool {
+        self.kp_wait
+    }
+
+    // The COSMAC VIP sounded the tone while the key it waited for was held
+    pub fn beep(&self) -> bool {
+        self.kp_wait && self.kp.is_some_and(|key| self.keys[key as usize])
+    }
+
+    // LDKP (Fx0A) calls this until it returns a key. The first call starts waiting, a key pressed after that ends
+    // the wait, or with release only once the key is up again, as on the COSMAC VIP. A key pressed and released
+    // between two calls to set_keys counts as released.
+    pub fn wait_keypress(&mut self, release: bool) -> Option<u8> {
         if self.kp_wait {
             match self.kp {
-                Some(key) => {
+                Some(key) if !release || !self.keys[key as usize] => {
                     self.kp = None;
                     self.kp_wait = false;
                     Some(key)
                 },
-                None => None
+                _ => None
             }
         } else {
             self.kp = None;
@@ -0,0 +1,247 @@
+use super::super::frontend::Frontend;
+use super::mem::SPRITES;
+
+// The CHIP-8 keys row by row as they are laid out on the COSMAC VIP keypad:
+//   1 2 3 C
+//   4 5 6 D
+//   7 8 9 E
+//   A 0 B F
+pub const LAYOUT: [u8; 16] = [0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF];
+
+// The settings for the host keys and the controller buttons of each CHIP-8 key, by key
+pub const SETTINGS: [&str; 16] = [
+    "key_0", "key_1", "key_2", "key_3", "key_4", "key_5", "key_6", "key_7",
+    "key_8", "key_9", "key_a", "key_b", "key_c", "key_d", "key_e", "key_f",
+];
+pub const PAD_SETTINGS: [&str; 16] = [
+    "pad_0", "pad_1", "pad_2", "pad_3", "pad_4", "pad_5", "pad_6", "pad_7",
+    "pad_8", "pad_9", "pad_a", "pad_b", "pad_c", "pad_d", "pad_e", "pad_f",
+];
+
+// Host keys for the keypad in LAYOUT order. The letter layouts keep the keypad's shape on the left of the keyboard,
+// numpad puts every digit on its own key and the letters around them.
+const PRESETS: [(&str, [&str; 16]); 3] = [
+    ("qwerty", ["1", "2", "3", "4", "Q", "W", "E", "R", "A", "S", "D", "F", "Z", "X", "C", "V"]),
+    ("azerty", ["1", "2", "3", "4", "A", "Z", "E", "R", "Q", "S", "D", "F", "W", "X", "C", "V"]),
+    ("numpad", [
+        "Keypad 1", "Keypad 2", "Keypad 3", "Keypad -", "Keypad 4", "Keypad 5", "Keypad 6", "Keypad +",
+        "Keypad 7", "Keypad 8", "Keypad 9", "Keypad Enter", "Keypad /", "Keypad 0", "Keypad *", "Keypad .",
+    ]),
+];
+
+// Controller buttons by key. The D-pad and the left stick press 2, 4, 6 and 8, which most games use as directions, and
+// A presses 5.
+const PAD: [&str; 16] = [
+    "", "", "dpup, lefty-", "", "dpleft, leftx-", "a", "dpright, leftx+", "",
+    "dpdown, lefty+", "", "", "", "", "", "", "",
+];
+
+// The host keys and controller buttons that press each CHIP-8 key. Both are given by name, keys e.g. "X", "Up" or
+// "Keypad 7" and buttons e.g. "a", "dpleft", "lefttrigger" or a stick direction such as "leftx-". Finding them is up
+// to the frontend.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Keymap {
+    keys: [Vec<String>; 16],
+    buttons: [Vec<String>; 16],
+}
+
+impl Keymap {
+    pub fn new() -> Self {
+        let mut keymap = Self { keys: Default::default(), buttons: Default::default() };
+        keymap.set_preset("qwerty").expect("qwerty is a preset");
+        for (key, value) in PAD.iter().enumerate() {
+            keymap.buttons[key] = parse_names(value).expect("The default buttons are valid");
+        }
+        keymap
+    }
+
+    // Bind every key to the keys of a preset, the controller buttons stay as they are
+    pub fn set_preset(&mut self, name: &str) -> Result<(), String> {
+        let (_, names) = PRESETS.iter().find(|(preset, _)| *preset == name).ok_or_else(|| {
+            let presets: Vec<&str> = PRESETS.iter().map(|(preset, _)| *preset).collect();
+            format!("expected {}, got '{}'", presets.join(", "), name)
+        })?;
+        for (&key, name) in LAYOUT.iter().zip(names) {
+            self.keys[key as usize] = vec![name.to_string()];
+        }
+        Ok(())
+    }
+
+    pub fn keys(&self, key: u8) -> &[String] {
+        &self.keys[key as usize]
+    }
+
+    pub fn buttons(&self, key: u8) -> &[String] {
+        &self.buttons[key as usize]
+    }
+
+    // Bind a comma separated list of host keys, e.g. "W, Up". An empty list leaves the key unbound.
+    pub fn set(&mut self, key: u8, value: &str) -> Result<(), String> {
+        self.keys[key as usize] = parse_names(value)?;
+        Ok(())
+    }
+
+    // Same for controller buttons, e.g. "dpleft, leftx-"
+    pub fn set_buttons(&mut self, key: u8, value: &str) -> Result<(), String> {
+        self.buttons[key as usize] = parse_names(value)?;
+        Ok(())
+    }
+
+    // The bindings of a key in the form set and set_buttons take
+    pub fn value(&self, key: u8) -> String {
+        self.keys[key as usize].join(", ")
+    }
+
+    pub fn buttons_value(&self, key: u8) -> String {
+        self.buttons[key as usize].join(", ")
+    }
+}
+
+fn parse_names(value: &str) -> Result<Vec<String>, String> {
+    let mut names = Vec::new();
+    if !value.trim().is_empty() {
+        for name in value.split(',') {
+            let name = name.trim();
+            if name.is_empty() {
+                return Err(format!("expected names separated by commas, got '{}'", value));
+            }
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The remap screen asks for a host key for each CHIP-8 key in turn, in LAYOUT order. It shows the keypad with the
+// key waiting for a binding highlighted.
+pub struct Remap {
+    keymap: Keymap,
+    position: usize,
+}
+
+impl Remap {
+    pub fn new(keymap: &Keymap) -> Self {
+        Self { keymap: keymap.clone(), position: 0 }
+    }
+
+    // The key waiting for a binding
+    pub fn key(&self) -> u8 {
+        LAYOUT[self.position]
+    }
+
+    // Bind the host key to the key waiting for one, replacing its bindings. Returns the new keymap once every key is
+    // bound.
+    pub fn bind(&mut self, name: &str) -> Option<Keymap> {
+        self.keymap.keys[self.key() as usize] = vec![name.to_string()];
+        self.position += 1;
+        if self.position == LAYOUT.len() {
+            Some(self.keymap.clone())
+        } else {
+            None
+        }
+    }
+
+    // The keypad in a 4x4 grid of 16x8 cells, keys bound so far dimmed and the waiting key in a filled cell
+    pub fn draw<F: Frontend>(&self, frontend: &mut F) {
+        frontend.set_resolution(64, 32);
+        frontend.clear();
+        for (position, &key) in LAYOUT.iter().enumerate() {
+            let cell_x = (position % 4 * 16) as u8;
+            let cell_y = (position / 4 * 8) as u8;
+            let sprite = &SPRITES[key as usize];
+            let digit = |x: u8, y: u8| (1..6).contains(&y) && (6..10).contains(&x)
+                && sprite[y as usize - 1] & (0x80 >> (x - 6)) != 0;
+
+            for y in 0..7 {
+                for x in 0..15 {
+                    let color = if position == self.position {
+                        // Filled, the digit left blank
+                        if digit(x, y) { 0 } else { 3 }
+                    } else if !digit(x, y) {
+                        0
+                    } else if position < self.position {
+                        2
+                    } else {
+                        1
+                    };
+                    if color != 0 {
+                        frontend.draw_pixel(cell_x + x, cell_y + y, color);
+                    }
+                }
+            }
+        }
+        frontend.present();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_bind_every_key_once() {
+        for (name, _) in PRESETS {
+            let mut keymap = Keymap::new();
+            keymap.set_preset(name).unwrap();
+            let mut names: Vec<&String> = (0..16).flat_map(|key| keymap.keys(key)).collect();
+            assert_eq!(names.len(), 16, "{}", name);
+            names.sort();
+            names.dedup();
+            assert_eq!(names.len(), 16, "{}", name);
+        }
+        assert!(Keymap::new().set_preset("dvorak").is_err());
+    }
+
+    #[test]
+    fn qwerty_keeps_the_keypad_shape() {
+        let keymap = Keymap::new();
+        assert_eq!(keymap.keys(0x1), ["1"]);
+        assert_eq!(keymap.keys(0xC), ["4"]);
+        assert_eq!(keymap.keys(0x0), ["X"]);
+        assert_eq!(keymap.keys(0xF), ["V"]);
+    }
+
+    #[test]
+    fn set_parses_lists() {
+        let mut keymap = Keymap::new();
+        keymap.set(0x5, "W, Up").unwrap();
+        assert_eq!(keymap.keys(0x5), ["W", "Up"]);
+        assert_eq!(keymap.value(0x5), "W, Up");
+        keymap.set(0x5, "Keypad 5").unwrap();
+        assert_eq!(keymap.keys(0x5), ["Keypad 5"]);
+        keymap.set(0x5, "").unwrap();
+        assert!(keymap.keys(0x5).is_empty());
+        assert!(keymap.set(0x5, "W,,Up").is_err());
+    }
+
+    #[test]
+    fn presets_keep_the_buttons() {
+        let mut keymap = Keymap::new();
+        assert_eq!(keymap.buttons(0x5), ["a"]);
+        keymap.set_buttons(0x5, "b, righttrigger").unwrap();
+        keymap.set_preset("numpad").unwrap();
+        assert_eq!(keymap.buttons(0x5), ["b", "righttrigger"]);
+        assert_eq!(keymap.buttons_value(0x5), "b, righttrigger");
+        assert_eq!(keymap.buttons(0x4), ["dpleft", "leftx-"]);
+    }
+
+    #[test]
+    fn remap_goes_through_the_keypad() {
+        let mut remap = Remap::new(&Keymap::new());
+        let mut keymap = None;
+        for (i, &key) in LAYOUT.iter().enumerate() {
+            assert_eq!(remap.key(), key);
+            assert!(keymap.is_none());
+            keymap = remap.bind(&format!("Key {}", i));
+        }
+        let keymap = keymap.unwrap();
+        assert_eq!(keymap.keys(0x1), ["Key 0"]);
+        assert_eq!(keymap.keys(0xC), ["Key 3"]);
+        assert_eq!(keymap.keys(0xF), ["Key 15"]);
+    }
+}
@@ -1,6 +1,8 @@
-const MEMORY_SIZE: usize = 4096;
+use std::cell::RefCell;
+use super::err::Chip8Error;
+use super::sav::{self, Reader, Writer};
 
-const SPRITES: [[u8; 5]; 16] = [
+pub const SPRITES: [[u8; 5]; 16] = [
     [0xF0, 0x90, 0x90, 0x90, 0xF0], // 0
     [0x20, 0x60, 0x20, 0x20, 0x70], // 1
     [0xF0, 0x10, 0xF0, 0x80, 0xF0], // 2
@@ -19,33 +21,119 @@ const SPRITES: [[u8; 5]; 16] = [
     [0xF0, 0x80, 0xF0, 0x80, 0x80], // F
 ];
 
+// SCHIP 8x10 font
+const BIG_SPRITES: [[u8; 10]; 16] = [
+    [0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C], // 0
+    [0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C], // 1
+    [0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF], // 2
+    [0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C], // 3
+    [0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06], // 4
+    [0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C], // 5
+    [0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C], // 6
+    [0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60], // 7
+    [0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C], // 8
+    [0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C], // 9
+    [0x18, 0x3C, 0x66, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3], // A
+    [0xFC, 0xFE, 0xC3, 0xC3, 0xFE, 0xFE, 0xC3, 0xC3, 0xFE, 0xFC], // B
+    [0x3C, 0x7E, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0x7E, 0x3C], // C
+    [0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC], // D
+    [0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xFF, 0xFF], // E
+    [0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xC0, 0xC0], // F
+];
+
 const SPRITES_ADDR: usize = 0x0000;
+const BIG_SPRITES_ADDR: usize = 0x0050;
+
+// A read or write made by the CPU, recorded for watchpoints
+#[derive(Clone, Copy)]
+pub enum Access {
+    Read { addr: u16, value: u8 },
+    Write { addr: u16, old: u8, new: u8 },
+}
 
 pub struct Memory {
-    bytes: [u8; MEMORY_SIZE]
+    bytes: Vec<u8>,
+    // Accesses since the last take_accesses, only recorded while tracing is enabled.
+    // Reads don't need a mutable Memory otherwise, so this is a RefCell.
+    trace: Option<RefCell<Vec<Access>>>,
 }
 
 impl Memory {
-    pub fn new() -> Self {
-        let mut bytes = [0; MEMORY_SIZE];
+    // 4 KiB for CHIP-8 and SCHIP, 64 KiB for XO-CHIP
+    pub fn new(size: usize) -> Self {
+        let mut bytes = vec![0; size];
 
         for (i, s) in SPRITES.iter().enumerate() {
             let addr = Self::sprite_address(i as u8) as usize;
             bytes[addr..addr+5].copy_from_slice(s);
         }
 
-        Self { bytes }
+        for (i, s) in BIG_SPRITES.iter().enumerate() {
+            let addr = Self::big_sprite_address(i as u8) as usize;
+            bytes[addr..addr+10].copy_from_slice(s);
+        }
+
+        Self { bytes, trace: None }
     }
 
     pub fn sprite_address(sprite: u8) -> u16 {
         (SPRITES_ADDR + (sprite as usize * 5)) as u16
     }
 
-    pub fn read(&self, addr: u16) -> u8 {
-        self.bytes[addr as usize]
+    pub fn big_sprite_address(sprite: u8) -> u16 {
+        (BIG_SPRITES_ADDR + (sprite as usize * 10)) as u16
+    }
+
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn save(&self, w: &mut Writer) {
+        w.block(&self.bytes);
+    }
+
+    // The size is given by the variant in the saved settings
+    pub fn load(r: &mut Reader, size: usize) -> Result<Self, Chip8Error> {
+        let bytes = r.block()?;
+        if bytes.len() != size {
+            return Err(sav::invalid(format!("expected {} bytes of memory, got {}", size, bytes.len())));
+        }
+        Ok(Self { bytes: bytes.to_vec(), trace: None })
     }
 
-    pub fn write(&mut self, addr: u16, byte: u8) {
-        self.bytes[addr as usize] = byte;
+    pub fn set_tracing(&mut self, tracing: bool) {
+        if tracing != self.trace.is_some() {
+            self.trace = if tracing { Some(RefCell::new(Vec::new())) } else { None };
+        }
+    }
+
+    pub fn take_accesses(&mut self) -> Vec<Access> {
+        match &self.trace {
+            Some(trace) => trace.replace(Vec::new()),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> Result<u8, Chip8Error> {
+        let value = self.peek(addr)?;
+        if let Some(trace) = &self.trace {
+            trace.borrow_mut().push(Access::Read { addr, value });
+        }
+        Ok(value)
+    }
+
+    // Reads without being seen by watchpoints, for the debugger
+    pub fn peek(&self, addr: u16) -> Result<u8, Chip8Error> {
+        self.bytes.get(addr as usize).copied().ok_or(Chip8Error::MemoryOutOfBounds { addr })
+    }
+
+    pub fn write(&mut self, addr: u16, byte: u8) -> Result<(), Chip8Error> {
+        let b = self.bytes.get_mut(addr as usize).ok_or(Chip8Error::MemoryOutOfBounds { addr })?;
+        let old = *b;
+        *b = byte;
+        if let Some(trace) = &self.trace {
+            trace.borrow_mut().push(Access::Write { addr, old, new: byte });
+        }
+        Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,172 @@
+use super::err::Chip8Error;
+use super::sav::{Reader, Writer};
+
+// Movies start with MAGIC and VERSION followed by the save state they start from and the input of every frame
+pub const MAGIC: &[u8; 4] = b"C8MV";
+pub const VERSION: u16 = 1;
+
+struct Frame {
+    // Bit N is set when key N is held
+    keys: u16,
+    // Keys pressed during the frame, for LDKP (Fx0A)
+    presses: Vec<u8>,
+}
+
+// Recorded input. The save state holds the settings, including the RNG seed, and the whole machine, so playing the
+// input back from it runs the program exactly as it ran while recording.
+pub struct Movie {
+    state: Vec<u8>,
+    frames: Vec<Frame>,
+}
+
+impl Movie {
+    pub fn new(state: Vec<u8>) -> Self {
+        Self { state, frames: Vec::new() }
+    }
+
+    pub fn state(&self) -> &[u8] {
+        &self.state
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn push(&mut self, keys: [bool; 16], mut presses: Vec<u8>) {
+        // While LDKP waits only the first press counts, the ones after it are ignored anyway
+        presses.truncate(u8::MAX as usize);
+        let keys = keys.iter().enumerate().fold(0, |mask, (i, held)| mask | (*held as u16) << i);
+        self.frames.push(Frame { keys, presses });
+    }
+
+    pub fn frame(&self, index: usize) -> Option<([bool; 16], &[u8])> {
+        let frame = self.frames.get(index)?;
+        let mut keys = [false; 16];
+        for (i, held) in keys.iter_mut().enumerate() {
+            *held = frame.keys & 1 << i != 0;
+        }
+        Some((keys, &frame.presses))
+    }
+
+    pub fn save(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.bytes(MAGIC);
+        w.u16(VERSION);
+        w.block(&self.state);
+        w.u32(self.frames.len() as u32);
+        for frame in &self.frames {
+            w.u16(frame.keys);
+            w.u8(frame.presses.len() as u8);
+            w.bytes(&frame.presses);
+        }
+        w.finish()
+    }
+
+    pub fn load(bytes: &[u8]) -> Result<Self, Chip8Error> {
+        let invalid = |error: Chip8Error| match error {
+            Chip8Error::InvalidState(reason) => Chip8Error::InvalidMovie(reason),
+            error => error,
+        };
+        let mut r = Reader::new(bytes);
+        if r.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(Chip8Error::InvalidMovie("not a movie".to_string()));
+        }
+        let version = r.u16().map_err(invalid)?;
+        if version != VERSION {
+            return Err(Chip8Error::InvalidMovie(format!("unsupported version {}", version)));
+        }
+        let mut movie = Self::new(r.block().map_err(invalid)?.to_vec());
+        let count = r.u32().map_err(invalid)?;
+        for _ in 0..count {
+            let keys = r.u16().map_err(invalid)?;
+            let presses = r.u8().map_err(invalid)? as usize;
+            let presses = r.bytes(presses).map_err(invalid)?.iter().map(|key| key & 0xF).collect();
+            movie.frames.push(Frame { keys, presses });
+        }
+        r.finish().map_err(invalid)?;
+        Ok(movie)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(held: &[usize]) -> [bool; 16] {
+        let mut keys = [false; 16];
+        for &key in held {
+            keys[key] = true;
+        }
+        keys
+    }
+
+    fn error(bytes: &[u8]) -> String {
+        match Movie::load(bytes) {
+            Err(Chip8Error::InvalidMovie(reason)) => reason,
+            Err(error) => panic!("unexpected error: {}", error),
+            Ok(_) => panic!("the movie loads"),
+        }
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let mut movie = Movie::new(vec![1, 2, 3]);
+        movie.push(keys(&[]), Vec::new());
+        movie.push(keys(&[0x0, 0x5, 0xF]), vec![0x5]);
+        movie.push(keys(&[0xA]), vec![0xA, 0x3]);
+
+        let movie = Movie::load(&movie.save()).unwrap();
+        assert_eq!(movie.state(), [1, 2, 3]);
+        assert_eq!(movie.len(), 3);
+        assert_eq!(movie.frame(0), Some((keys(&[]), &[][..])));
+        assert_eq!(movie.frame(1), Some((keys(&[0x0, 0x5, 0xF]), &[0x5][..])));
+        assert_eq!(movie.frame(2), Some((keys(&[0xA]), &[0xA, 0x3][..])));
+        assert_eq!(movie.frame(3), None);
+    }
+
+    #[test]
+    fn only_the_first_presses_of_a_frame_are_kept() {
+        let mut movie = Movie::new(Vec::new());
+        movie.push(keys(&[]), (0..300).map(|i| (i % 16) as u8).collect());
+        let movie = Movie::load(&movie.save()).unwrap();
+        let (_, presses) = movie.frame(0).unwrap();
+        assert_eq!(presses.len(), 255);
+        assert_eq!((presses[0], presses[254]), (0, (254 % 16) as u8));
+    }
+
+    #[test]
+    fn malformed_movies_are_rejected() {
+        let mut movie = Movie::new(vec![1, 2, 3]);
+        movie.push(keys(&[0x1]), vec![0x1]);
+        let bytes = movie.save();
+
+        assert_eq!(error(b"C8ST"), "not a movie");
+        assert_eq!(error(&[]), "not a movie");
+
+        let mut version = bytes.clone();
+        version[4] = 9;
+        assert_eq!(error(&version), "unsupported version 9");
+
+        for len in 4..bytes.len() {
+            assert_eq!(error(&bytes[..len]), "unexpected end of data", "{} bytes", len);
+        }
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(error(&trailing), "unexpected data at the end");
+    }
+
+    #[test]
+    fn presses_are_masked_to_keys() {
+        let mut bytes = Movie::new(Vec::new()).save();
+        // One frame with a press of 0x1F
+        bytes.truncate(bytes.len() - 4);
+        bytes.extend_from_slice(&[1, 0, 0, 0, 0x00, 0x00, 1, 0x1F]);
+        let movie = Movie::load(&bytes).unwrap();
+        assert_eq!(movie.frame(0).unwrap().1, [0xF]);
+    }
+}
@@ -0,0 +1,923 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::Path;
+use super::asm::{self, AsmError};
+use super::set::Settings;
+
+// A recursive macro would otherwise expand forever
+const MAX_EXPANSIONS: usize = 0x10000;
+
+#[derive(Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+// Label references that are filled in once the label is defined, by how the address is stored
+enum Patch {
+    Addr,               // low 12 bits of an instruction
+    Long,               // 2 bytes
+    High(Option<u8>),   // immediate of 6xkk, high byte or nibble combined with the high address bits
+    Low,                // immediate of 6xkk, low byte
+}
+
+struct Compiler {
+    file: String,
+    tokens: VecDeque<Token>,
+    end: Token,
+    rom_addr: u16,
+    here: u32,
+    rom: Vec<u8>,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    patches: Vec<(usize, Patch, Token)>,
+    // The ROM starts with a jump to main unless main is the first thing in it
+    main_jump: bool,
+    // Jumps of if ... begin ... else ... end waiting for their target
+    branches: Vec<(usize, Token)>,
+    // Start of each loop and the jumps out of its whiles
+    loops: Vec<(u16, Vec<usize>, Token)>,
+    expansions: usize,
+}
+
+// Compiles Octo source to a ROM loaded at the ROM address
+pub fn compile_octo(source: &str, settings: &Settings) -> Result<Vec<u8>, AsmError> {
+    Compiler::new(source, "", settings.rom_addr).compile()
+}
+
+pub fn compile_octo_file<P: AsRef<Path>>(path: P, settings: &Settings) -> Result<Vec<u8>, AsmError> {
+    let path = path.as_ref();
+    let file = path.display().to_string();
+    let source = fs::read_to_string(path).map_err(|e| AsmError {
+        file: file.clone(), line: 0, column: 0, message: e.to_string(),
+    })?;
+    Compiler::new(&source, &file, settings.rom_addr).compile()
+}
+
+impl Compiler {
+    fn new(source: &str, file: &str, rom_addr: u16) -> Self {
+        let tokens = tokenize(source);
+        let lines = source.lines().count();
+        let mut aliases = HashMap::new();
+        aliases.insert("unpack-hi".to_string(), 0x0);
+        aliases.insert("unpack-lo".to_string(), 0x1);
+        aliases.insert("compare-temp".to_string(), 0xF);
+        Self {
+            file: file.to_string(),
+            tokens,
+            end: Token { text: String::new(), line: lines.max(1), column: 1 },
+            rom_addr,
+            here: rom_addr as u32,
+            rom: Vec::new(),
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases,
+            macros: HashMap::new(),
+            patches: Vec::new(),
+            main_jump: true,
+            branches: Vec::new(),
+            loops: Vec::new(),
+            expansions: 0,
+        }
+    }
+
+    fn compile(mut self) -> Result<Vec<u8>, AsmError> {
+        let start = self.end.clone();
+        self.inst(0x0000, &start)?;
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+
+        if let Some((_, token)) = self.branches.first() {
+            return Err(self.error(token, "'begin' without 'end'".to_string()));
+        }
+        if let Some((_, _, token)) = self.loops.first() {
+            return Err(self.error(token, "'loop' without 'again'".to_string()));
+        }
+        if self.main_jump {
+            let main = match self.labels.get("main") {
+                Some(main) => *main,
+                None => return Err(self.error(&self.end, "the program has no 'main' label".to_string())),
+            };
+            self.patch(0, &Patch::Addr, main, &start)?;
+            self.rom[0] |= 0x10;
+        }
+        for (index, patch, token) in std::mem::take(&mut self.patches) {
+            match self.labels.get(&token.text) {
+                Some(addr) => self.patch(index, &patch, *addr, &token)?,
+                None => return Err(self.error(&token, format!("'{}' is not defined", token.text))),
+            }
+        }
+        Ok(self.rom)
+    }
+
+    fn statement(&mut self) -> Result<(), AsmError> {
+        let token = self.next()?;
+        match token.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                // No need to jump over nothing
+                if name.text == "main" && self.main_jump && self.rom.len() == 2 && self.labels.is_empty()
+                    && self.here == self.rom_addr as u32 + 2 {
+                    self.main_jump = false;
+                    self.rom.clear();
+                    self.here = self.rom_addr as u32;
+                }
+                self.define_label(&name, self.here)?;
+            }
+            ":next" => {
+                let name = self.name()?;
+                self.define_label(&name, self.here + 1)?;
+            }
+            ":alias" => {
+                let name = self.name()?;
+                if self.peek() == Some("{") {
+                    let value = self.calc()?;
+                    self.constants.insert(name.text, value);
+                } else {
+                    let reg = self.register()?;
+                    self.aliases.insert(name.text, reg as u8);
+                }
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.next()?;
+                let value = self.number(&value)?;
+                self.constants.insert(name.text, value as f64);
+            }
+            ":calc" => {
+                let name = self.name()?;
+                let value = self.calc()?;
+                self.constants.insert(name.text, value);
+            }
+            ":byte" => {
+                let byte = if self.peek() == Some("{") {
+                    self.calc()? as i64 as u8
+                } else {
+                    self.byte()?
+                };
+                self.emit(&[byte], &token)?;
+            }
+            ":pointer" => {
+                let target = self.next()?;
+                let addr = self.address(&target, Patch::Long, 0xFFFF)?;
+                self.emit(&addr.to_be_bytes(), &token)?;
+            }
+            ":org" => {
+                let addr = self.next()?;
+                let addr = self.number(&addr)?;
+                if addr < self.rom_addr as i64 || addr > 0xFFFF {
+                    return Err(self.error(&token, format!("can't place code at {:#X}", addr)));
+                }
+                self.here = addr as u32;
+            }
+            ":macro" => {
+                let name = self.name()?;
+                let mut args = Vec::new();
+                while self.peek() != Some("{") {
+                    args.push(self.next()?.text);
+                }
+                let body = self.block()?;
+                self.macros.insert(name.text, Macro { args, body });
+            }
+            ":unpack" => {
+                let kind = self.next()?;
+                let nibble = if kind.text == "long" {
+                    None
+                } else {
+                    match self.number(&kind)? {
+                        nibble @ 0..=0xF => Some(nibble as u8),
+                        _ => return Err(self.error(&kind, "expected a nibble or 'long'".to_string())),
+                    }
+                };
+                let target = self.next()?;
+                let hi = self.aliases["unpack-hi"] as u16;
+                let lo = self.aliases["unpack-lo"] as u16;
+                let addr = self.address(&target, Patch::High(nibble), 0xFFFF)?;
+                let high = match nibble {
+                    Some(nibble) => (nibble as u16) << 4 | addr >> 8 & 0xF,
+                    None => addr >> 8,
+                };
+                self.inst(0x6000 | hi << 8 | high, &token)?;
+                let addr = self.address(&target, Patch::Low, 0xFFFF)?;
+                self.inst(0x6000 | lo << 8 | addr & 0xFF, &token)?;
+            }
+            ":call" => {
+                let target = self.next()?;
+                let addr = self.address(&target, Patch::Addr, 0xFFF)?;
+                self.inst(0x2000 | addr, &token)?;
+            }
+            // Only meaningful to Octo's debugger
+            ":breakpoint" | ":proto" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+            ";" | "return" => self.inst(0x00EE, &token)?,
+            "clear"        => self.inst(0x00E0, &token)?,
+            "scroll-right" => self.inst(0x00FB, &token)?,
+            "scroll-left"  => self.inst(0x00FC, &token)?,
+            "exit"         => self.inst(0x00FD, &token)?,
+            "lores"        => self.inst(0x00FE, &token)?,
+            "hires"        => self.inst(0x00FF, &token)?,
+            "audio"        => self.inst(0xF002, &token)?,
+            "scroll-down"  => { let n = self.nibble()?; self.inst(0x00C0 | n, &token)? }
+            "scroll-up"    => { let n = self.nibble()?; self.inst(0x00D0 | n, &token)? }
+            "plane"        => { let n = self.nibble()?; self.inst(0xF001 | n << 8, &token)? }
+            "bcd"          => { let x = self.register()?; self.inst(0xF033 | x << 8, &token)? }
+            "saveflags"    => { let x = self.register()?; self.inst(0xF075 | x << 8, &token)? }
+            "loadflags"    => { let x = self.register()?; self.inst(0xF085 | x << 8, &token)? }
+            "save" | "load" => {
+                let x = self.register()?;
+                let save = token.text == "save";
+                if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    self.inst(if save { 0x5002 } else { 0x5003 } | x << 8 | y << 4, &token)?;
+                } else {
+                    self.inst(if save { 0xF055 } else { 0xF065 } | x << 8, &token)?;
+                }
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.inst(0xD000 | x << 8 | y << 4 | n, &token)?;
+            }
+            "jump" | "jump0" | "native" => {
+                let target = self.next()?;
+                let addr = self.address(&target, Patch::Addr, 0xFFF)?;
+                let opcode = match token.text.as_str() {
+                    "jump" => 0x1000,
+                    "jump0" => 0xB000,
+                    _ => 0x0000,
+                };
+                self.inst(opcode | addr, &token)?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                let opcode = match token.text.as_str() {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => 0xF03A,
+                };
+                self.inst(opcode | x << 8, &token)?;
+            }
+            "i" => {
+                let op = self.next()?;
+                match op.text.as_str() {
+                    ":=" => match self.peek() {
+                        Some("hex") => {
+                            self.next()?;
+                            let x = self.register()?;
+                            self.inst(0xF029 | x << 8, &token)?;
+                        }
+                        Some("bighex") => {
+                            self.next()?;
+                            let x = self.register()?;
+                            self.inst(0xF030 | x << 8, &token)?;
+                        }
+                        Some("long") => {
+                            self.next()?;
+                            self.inst(0xF000, &token)?;
+                            let target = self.next()?;
+                            let addr = self.address(&target, Patch::Long, 0xFFFF)?;
+                            self.emit(&addr.to_be_bytes(), &token)?;
+                        }
+                        _ => {
+                            let target = self.next()?;
+                            let addr = self.address(&target, Patch::Addr, 0xFFF)?;
+                            self.inst(0xA000 | addr, &token)?;
+                        }
+                    },
+                    "+=" => {
+                        let x = self.register()?;
+                        self.inst(0xF01E | x << 8, &token)?;
+                    }
+                    _ => return Err(self.error(&op, format!("expected ':=' or '+=' after 'i', got '{}'", op.text))),
+                }
+            }
+            "if" => {
+                // The condition is 2 or 3 tokens, then or begin decides how it's compiled
+                let begin = self.tokens.iter().take(4).any(|token| token.text == "begin");
+                if begin {
+                    self.condition(true)?;
+                    self.expect("begin")?;
+                    let index = self.jump_placeholder(&token)?;
+                    self.branches.push((index, token));
+                } else {
+                    self.condition(false)?;
+                    self.expect("then")?;
+                }
+            }
+            "else" => {
+                let (index, _) = match self.branches.pop() {
+                    Some(branch) => branch,
+                    None => return Err(self.error(&token, "'else' without 'if ... begin'".to_string())),
+                };
+                let jump = self.jump_placeholder(&token)?;
+                self.patch_jump(index, self.here, &token)?;
+                self.branches.push((jump, token));
+            }
+            "end" => {
+                let (index, _) = match self.branches.pop() {
+                    Some(branch) => branch,
+                    None => return Err(self.error(&token, "'end' without 'if ... begin'".to_string())),
+                };
+                self.patch_jump(index, self.here, &token)?;
+            }
+            "loop" => self.loops.push((self.here as u16, Vec::new(), token)),
+            "while" => {
+                if self.loops.is_empty() {
+                    return Err(self.error(&token, "'while' outside of a loop".to_string()));
+                }
+                self.condition(true)?;
+                let index = self.jump_placeholder(&token)?;
+                self.loops.last_mut().unwrap().1.push(index);
+            }
+            "again" => {
+                let (start, whiles, _) = match self.loops.pop() {
+                    Some(lp) => lp,
+                    None => return Err(self.error(&token, "'again' without 'loop'".to_string())),
+                };
+                self.inst(0x1000, &token)?;
+                self.patch_jump(self.index(self.here - 2), start as u32, &token)?;
+                for index in whiles {
+                    self.patch_jump(index, self.here, &token)?;
+                }
+            }
+            _ => {
+                if let Some(x) = self.register_index(&token.text) {
+                    self.assignment(x as u16, &token)?;
+                } else if self.macros.contains_key(&token.text) {
+                    self.expand(&token)?;
+                } else if token.text.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+                    let byte = self.byte_value(&token)?;
+                    self.emit(&[byte], &token)?;
+                } else {
+                    // Any other name is a subroutine call
+                    let addr = self.address(&token, Patch::Addr, 0xFFF)?;
+                    self.inst(0x2000 | addr, &token)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn assignment(&mut self, x: u16, token: &Token) -> Result<(), AsmError> {
+        let op = self.next()?;
+        let y = self.peek().and_then(|text| self.register_index(text)).map(|y| y as u16);
+        if y.is_some() {
+            self.next()?;
+        }
+        let opcode = match (op.text.as_str(), y) {
+            (":=", Some(y))  => 0x8000 | y << 4,
+            ("|=", Some(y))  => 0x8001 | y << 4,
+            ("&=", Some(y))  => 0x8002 | y << 4,
+            ("^=", Some(y))  => 0x8003 | y << 4,
+            ("+=", Some(y))  => 0x8004 | y << 4,
+            ("-=", Some(y))  => 0x8005 | y << 4,
+            (">>=", Some(y)) => 0x8006 | y << 4,
+            ("=-", Some(y))  => 0x8007 | y << 4,
+            ("<<=", Some(y)) => 0x800E | y << 4,
+            (":=", None) => match self.peek() {
+                Some("key") => {
+                    self.next()?;
+                    0xF00A
+                }
+                Some("delay") => {
+                    self.next()?;
+                    0xF007
+                }
+                Some("random") => {
+                    self.next()?;
+                    0xC000 | self.byte()? as u16
+                }
+                _ => 0x6000 | self.byte()? as u16,
+            },
+            ("+=", None) => 0x7000 | self.byte()? as u16,
+            ("-=", None) => 0x7000 | (self.byte()? as u16).wrapping_neg() & 0xFF,
+            _ => return Err(self.error(&op, format!("unknown operator '{}'", op.text))),
+        };
+        self.inst(opcode | x << 8, token)
+    }
+
+    // Emits the code that skips the next instruction unless the condition holds, or if it holds when negated
+    fn condition(&mut self, negate: bool) -> Result<(), AsmError> {
+        let x = self.register()?;
+        let op = self.next()?;
+        let mut text = op.text.as_str();
+        if negate {
+            text = match text {
+                "==" => "!=",
+                "!=" => "==",
+                "key" => "-key",
+                "-key" => "key",
+                "<" => ">=",
+                ">=" => "<",
+                ">" => "<=",
+                "<=" => ">",
+                other => other,
+            };
+        }
+        let y = self.peek().and_then(|text| self.register_index(text)).map(|y| y as u16);
+        match text {
+            "key" => self.inst(0xE0A1 | x << 8, &op),
+            "-key" => self.inst(0xE09E | x << 8, &op),
+            "==" | "!=" => {
+                let opcode = match (text, y) {
+                    ("==", Some(y)) => 0x9000 | y << 4,
+                    ("!=", Some(y)) => 0x5000 | y << 4,
+                    ("==", None) => 0x4000 | self.byte()? as u16,
+                    _ => 0x3000 | self.byte()? as u16,
+                };
+                if y.is_some() {
+                    self.next()?;
+                }
+                self.inst(opcode | x << 8, &op)
+            }
+            // Compared by subtracting in VF, which is then 1 when there's no borrow
+            "<" | ">" | "<=" | ">=" => {
+                let temp = self.aliases["compare-temp"] as u16;
+                match y {
+                    Some(y) => {
+                        self.next()?;
+                        self.inst(0x8000 | temp << 8 | y << 4, &op)?;
+                    }
+                    None => {
+                        let byte = self.byte()? as u16;
+                        self.inst(0x6000 | temp << 8 | byte, &op)?;
+                    }
+                }
+                let (opcode, skip) = match text {
+                    ">"  => (0x8005, 0x3001),  // temp = y - x, skip if y >= x
+                    "<"  => (0x8007, 0x3001),  // temp = x - y, skip if x >= y
+                    ">=" => (0x8007, 0x3000),  // temp = x - y, skip if x < y
+                    _    => (0x8005, 0x3000),  // temp = y - x, skip if y < x
+                };
+                self.inst(opcode | temp << 8 | x << 4, &op)?;
+                self.inst(skip | 0xF << 8, &op)
+            }
+            _ => Err(self.error(&op, format!("unknown condition '{}'", op.text))),
+        }
+    }
+
+    fn expand(&mut self, token: &Token) -> Result<(), AsmError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(self.error(token, format!("macro '{}' expanded too many times", token.text)));
+        }
+        let count = self.macros[&token.text].args.len();
+        let mut args = HashMap::new();
+        for i in 0..count {
+            let arg = self.next()?;
+            args.insert(self.macros[&token.text].args[i].clone(), arg);
+        }
+        let body: Vec<Token> = self.macros[&token.text].body.iter()
+            .map(|t| args.get(&t.text).cloned().unwrap_or_else(|| t.clone()))
+            .collect();
+        for t in body.into_iter().rev() {
+            self.tokens.push_front(t);
+        }
+        Ok(())
+    }
+
+    // Tokens between { and the matching }
+    fn block(&mut self) -> Result<Vec<Token>, AsmError> {
+        self.expect("{")?;
+        let mut depth = 1;
+        let mut body = Vec::new();
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(body);
+                    }
+                }
+                _ => {}
+            }
+            body.push(token);
+        }
+    }
+
+    fn calc(&mut self) -> Result<f64, AsmError> {
+        let tokens = self.block()?;
+        let mut pos = 0;
+        let value = self.expression(&tokens, &mut pos)?;
+        match tokens.get(pos) {
+            Some(token) => Err(self.error(token, format!("unexpected '{}'", token.text))),
+            None => Ok(value),
+        }
+    }
+
+    // Operators have no precedence and are evaluated right to left, like in Octo
+    fn expression(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, AsmError> {
+        let lhs = self.term(tokens, pos)?;
+        let op = match tokens.get(*pos) {
+            Some(op) if op.text != ")" => op,
+            _ => return Ok(lhs),
+        };
+        *pos += 1;
+        let rhs = self.expression(tokens, pos)?;
+        let (a, b) = (lhs as i64, rhs as i64);
+        let value = match op.text.as_str() {
+            "+"   => lhs + rhs,
+            "-"   => lhs - rhs,
+            "*"   => lhs * rhs,
+            "/"   => lhs / rhs,
+            "%"   => lhs % rhs,
+            "pow" => lhs.powf(rhs),
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            "&"   => (a & b) as f64,
+            "|"   => (a | b) as f64,
+            "^"   => (a ^ b) as f64,
+            "<<"  => a.checked_shl(b as u32).unwrap_or(0) as f64,
+            ">>"  => a.checked_shr(b as u32).unwrap_or(0) as f64,
+            "<"   => (lhs < rhs) as i64 as f64,
+            ">"   => (lhs > rhs) as i64 as f64,
+            "<="  => (lhs <= rhs) as i64 as f64,
+            ">="  => (lhs >= rhs) as i64 as f64,
+            "=="  => (lhs == rhs) as i64 as f64,
+            "!="  => (lhs != rhs) as i64 as f64,
+            _ => return Err(self.error(op, format!("unknown operator '{}'", op.text))),
+        };
+        Ok(value)
+    }
+
+    fn term(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, AsmError> {
+        let token = match tokens.get(*pos) {
+            Some(token) => token,
+            None => return Err(self.error(tokens.last().unwrap_or(&self.end), "expected a value".to_string())),
+        };
+        *pos += 1;
+        let unary = |f: fn(f64) -> f64, pos: &mut usize| self.term(tokens, pos).map(f);
+        match token.text.as_str() {
+            "(" => {
+                let value = self.expression(tokens, pos)?;
+                match tokens.get(*pos) {
+                    Some(close) if close.text == ")" => {
+                        *pos += 1;
+                        Ok(value)
+                    }
+                    _ => Err(self.error(token, "'(' without ')'".to_string())),
+                }
+            }
+            "-"     => unary(|x| -x, pos),
+            "~"     => unary(|x| !(x as i64) as f64, pos),
+            "!"     => unary(|x| (x == 0.0) as i64 as f64, pos),
+            "sin"   => unary(f64::sin, pos),
+            "cos"   => unary(f64::cos, pos),
+            "tan"   => unary(f64::tan, pos),
+            "exp"   => unary(f64::exp, pos),
+            "log"   => unary(f64::ln, pos),
+            "abs"   => unary(f64::abs, pos),
+            "sqrt"  => unary(f64::sqrt, pos),
+            "sign"  => unary(f64::signum, pos),
+            "ceil"  => unary(f64::ceil, pos),
+            "floor" => unary(f64::floor, pos),
+            // Byte already compiled at an address
+            "@" => {
+                let addr = self.term(tokens, pos)? as i64;
+                let index = addr - self.rom_addr as i64;
+                Ok(self.rom.get(index as usize).filter(|_| index >= 0).copied().unwrap_or(0) as f64)
+            }
+            "HERE" => Ok(self.here as f64),
+            text => {
+                if let Some(value) = self.constants.get(text) {
+                    Ok(*value)
+                } else if let Some(addr) = self.labels.get(text) {
+                    Ok(*addr as f64)
+                } else if let Some(value) = literal(text) {
+                    Ok(value as f64)
+                } else {
+                    Err(self.error(token, format!("'{}' is not defined", text)))
+                }
+            }
+        }
+    }
+
+    // The address of a label or a number, labels defined later are patched at the end
+    fn address(&mut self, token: &Token, patch: Patch, max: i64) -> Result<u16, AsmError> {
+        if let Some(addr) = self.labels.get(&token.text) {
+            return Ok(*addr);
+        }
+        if literal(&token.text).is_some() || self.constants.contains_key(&token.text) {
+            let addr = self.number(token)?;
+            if addr < 0 || addr > max {
+                return Err(self.error(token, format!("address {:#X} is out of range", addr)));
+            }
+            return Ok(addr as u16);
+        }
+        self.check_name(token)?;
+        let index = self.index(self.here) + match patch {
+            Patch::High(_) | Patch::Low => 1,
+            Patch::Addr | Patch::Long => 0,
+        };
+        self.patches.push((index, patch, token.clone()));
+        Ok(0)
+    }
+
+    fn patch(&mut self, index: usize, patch: &Patch, addr: u16, token: &Token) -> Result<(), AsmError> {
+        match patch {
+            Patch::Addr => {
+                if addr > 0xFFF {
+                    return Err(self.error(token, format!("'{}' at {:#X} is out of reach of a 12-bit address", token.text, addr)));
+                }
+                self.rom[index] |= (addr >> 8) as u8;
+                self.rom[index + 1] = addr as u8;
+            }
+            Patch::Long => self.rom[index..index + 2].copy_from_slice(&addr.to_be_bytes()),
+            Patch::High(Some(nibble)) => self.rom[index] = nibble << 4 | (addr >> 8 & 0xF) as u8,
+            Patch::High(None) => self.rom[index] = (addr >> 8) as u8,
+            Patch::Low => self.rom[index] = addr as u8,
+        }
+        Ok(())
+    }
+
+    fn jump_placeholder(&mut self, token: &Token) -> Result<usize, AsmError> {
+        let index = self.index(self.here);
+        self.inst(0x1000, token)?;
+        Ok(index)
+    }
+
+    fn patch_jump(&mut self, index: usize, target: u32, token: &Token) -> Result<(), AsmError> {
+        self.patch(index, &Patch::Addr, target as u16, token)
+    }
+
+    fn define_label(&mut self, name: &Token, addr: u32) -> Result<(), AsmError> {
+        self.check_name(name)?;
+        if self.labels.insert(name.text.clone(), addr as u16).is_some() {
+            return Err(self.error(name, format!("'{}' is already defined", name.text)));
+        }
+        Ok(())
+    }
+
+    fn check_name(&self, name: &Token) -> Result<(), AsmError> {
+        if self.register_index(&name.text).is_some() || literal(&name.text).is_some() {
+            return Err(self.error(name, format!("'{}' can't be used as a name", name.text)));
+        }
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<Token, AsmError> {
+        let name = self.next()?;
+        self.check_name(&name)?;
+        Ok(name)
+    }
+
+    fn register_index(&self, text: &str) -> Option<u8> {
+        if let Some(reg) = self.aliases.get(text) {
+            return Some(*reg);
+        }
+        match text.strip_prefix(|c| c == 'v' || c == 'V') {
+            Some(digit) if digit.len() == 1 => u8::from_str_radix(digit, 16).ok(),
+            _ => None,
+        }
+    }
+
+    fn register(&mut self) -> Result<u16, AsmError> {
+        let token = self.next()?;
+        self.register_index(&token.text)
+            .map(|reg| reg as u16)
+            .ok_or_else(|| self.error(&token, format!("expected a register, got '{}'", token.text)))
+    }
+
+    // A number or a constant
+    fn number(&self, token: &Token) -> Result<i64, AsmError> {
+        if let Some(value) = self.constants.get(&token.text) {
+            return Ok(value.floor() as i64);
+        }
+        literal(&token.text).ok_or_else(|| self.error(token, format!("expected a number, got '{}'", token.text)))
+    }
+
+    fn byte_value(&self, token: &Token) -> Result<u8, AsmError> {
+        match self.number(token)? {
+            value @ -128..=255 => Ok(value as u8),
+            value => Err(self.error(token, format!("{} doesn't fit in a byte", value))),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, AsmError> {
+        let token = self.next()?;
+        self.byte_value(&token)
+    }
+
+    fn nibble(&mut self) -> Result<u16, AsmError> {
+        let token = self.next()?;
+        match self.number(&token)? {
+            value @ 0..=0xF => Ok(value as u16),
+            value => Err(self.error(&token, format!("{} doesn't fit in a nibble", value))),
+        }
+    }
+
+    fn inst(&mut self, opcode: u16, token: &Token) -> Result<(), AsmError> {
+        self.emit(&opcode.to_be_bytes(), token)
+    }
+
+    fn emit(&mut self, bytes: &[u8], token: &Token) -> Result<(), AsmError> {
+        for byte in bytes {
+            if self.here > 0xFFFF {
+                return Err(self.error(token, "the program doesn't fit in 64 KiB".to_string()));
+            }
+            let index = self.index(self.here);
+            if self.rom.len() <= index {
+                self.rom.resize(index + 1, 0);
+            }
+            self.rom[index] = *byte;
+            self.here += 1;
+        }
+        Ok(())
+    }
+
+    fn index(&self, addr: u32) -> usize {
+        (addr - self.rom_addr as u32) as usize
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|token| token.text.as_str())
+    }
+
+    fn next(&mut self) -> Result<Token, AsmError> {
+        match self.tokens.pop_front() {
+            Some(token) => Ok(token),
+            None => Err(self.error(&self.end, "unexpected end of file".to_string())),
+        }
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), AsmError> {
+        let token = self.next()?;
+        if token.text != text {
+            return Err(self.error(&token, format!("expected '{}', got '{}'", text, token.text)));
+        }
+        Ok(())
+    }
+
+    fn error(&self, token: &Token, message: String) -> AsmError {
+        AsmError { file: self.file.clone(), line: token.line, column: token.column, message }
+    }
+}
+
+// Numbers may be negative, e.g. to subtract with +=
+fn literal(text: &str) -> Option<i64> {
+    match text.strip_prefix('-') {
+        Some(rest) if rest.starts_with(|c: char| c.is_ascii_digit()) => asm::parse_number(rest).map(|n| -n),
+        Some(_) => None,
+        None if text.starts_with(|c: char| c.is_ascii_digit()) => asm::parse_number(text),
+        None => None,
+    }
+}
+
+// Tokens are separated by whitespace and # starts a comment
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (i, line) in source.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let mut start = None;
+        for (j, c) in line.char_indices().chain(std::iter::once((line.len(), ' '))) {
+            match (start, c.is_whitespace()) {
+                (None, false) => start = Some(j),
+                (Some(s), true) => {
+                    tokens.push_back(Token { text: line[s..j].to_string(), line: i + 1, column: s + 1 });
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(source: &str) -> Vec<u8> {
+        compile_octo(source, &Settings::new()).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    // Line, column and message
+    fn error(source: &str) -> (usize, usize, String) {
+        let error = compile_octo(source, &Settings::new()).expect_err("the source compiles");
+        (error.line, error.column, error.message)
+    }
+
+    #[test]
+    fn main_first_needs_no_jump() {
+        assert_eq!(compile(": main v0 := 5 v1 += v0 ;"), [0x60, 0x05, 0x81, 0x04, 0x00, 0xEE]);
+    }
+
+    #[test]
+    fn main_later_is_jumped_to() {
+        assert_eq!(compile(": f ;\n: main f"), [0x12, 0x04, 0x00, 0xEE, 0x22, 0x02]);
+    }
+
+    #[test]
+    fn labels_can_be_used_before_they_are_defined() {
+        let source = "
+            : main
+                i := smile
+                sprite v0 v1 2
+                jump main
+            : smile
+                0b10000001 0x7E
+        ";
+        assert_eq!(compile(source), [0xA2, 0x06, 0xD0, 0x12, 0x12, 0x00, 0x81, 0x7E]);
+    }
+
+    #[test]
+    fn assignments() {
+        let source = ": main v1 := v2 v1 -= 3 v1 =- v2 v1 := random 0x0F v1 := key v1 := delay i += v1";
+        assert_eq!(compile(source), [
+            0x81, 0x20, 0x71, 0xFD, 0x81, 0x27, 0xC1, 0x0F, 0xF1, 0x0A, 0xF1, 0x07, 0xF1, 0x1E,
+        ]);
+    }
+
+    #[test]
+    fn if_then_skips_when_the_condition_fails() {
+        assert_eq!(compile(": main if v0 == 3 then v1 := 1"), [0x40, 0x03, 0x61, 0x01]);
+        assert_eq!(compile(": main if v0 key then v1 := 1"), [0xE0, 0xA1, 0x61, 0x01]);
+    }
+
+    #[test]
+    fn if_begin_else_end_jumps_around() {
+        let source = ": main if v0 == 3 begin v1 := 1 else v1 := 2 end";
+        assert_eq!(compile(source), [0x30, 0x03, 0x12, 0x08, 0x61, 0x01, 0x12, 0x0A, 0x61, 0x02]);
+    }
+
+    #[test]
+    fn comparisons_subtract_in_vf() {
+        // vF := 5, vF =- v0, if vF != 1 then
+        assert_eq!(compile(": main if v0 < 5 then ;"), [0x6F, 0x05, 0x8F, 0x07, 0x3F, 0x01, 0x00, 0xEE]);
+    }
+
+    #[test]
+    fn loop_while_again() {
+        let source = ": main loop v0 += 1 while v0 != 10 again";
+        assert_eq!(compile(source), [0x70, 0x01, 0x40, 0x0A, 0x12, 0x08, 0x12, 0x00]);
+    }
+
+    #[test]
+    fn aliases_and_constants() {
+        let source = ":alias x v3 :const SPEED 4 :alias OFFSET { SPEED * 2 } : main x := SPEED x += OFFSET";
+        assert_eq!(compile(source), [0x63, 0x04, 0x73, 0x08]);
+    }
+
+    #[test]
+    fn macros_and_calc() {
+        let source = "
+            :macro twice REG { REG += 1 REG += 1 }
+            # Right to left: 10 - (2 - 3)
+            :calc N { 10 - 2 - 3 }
+            : main twice v2 v0 := N :byte { N * 2 }
+        ";
+        assert_eq!(compile(source), [0x72, 0x01, 0x72, 0x01, 0x60, 11, 22]);
+    }
+
+    #[test]
+    fn long_addresses() {
+        let source = ": main i := long data :unpack long data ;\n:org 0x1234 : data 1";
+        let rom = compile(source);
+        assert_eq!(rom[..10], [0xF0, 0x00, 0x12, 0x34, 0x60, 0x12, 0x61, 0x34, 0x00, 0xEE]);
+        assert_eq!(rom.len(), 0x1234 - 0x200 + 1);
+        assert_eq!(rom[0x1234 - 0x200], 1);
+    }
+
+    #[test]
+    fn errors_point_at_the_token() {
+        assert_eq!(error(": main\n    jump nowhere"), (2, 10, "'nowhere' is not defined".to_string()));
+        assert_eq!(error(": main\n  v0 *= 2"), (2, 6, "unknown operator '*='".to_string()));
+        assert_eq!(error(": main\n  v0 := 256"), (2, 9, "256 doesn't fit in a byte".to_string()));
+        assert_eq!(error(": main\n  if v0 == 1 begin\n  v1 := 1"), (2, 3, "'begin' without 'end'".to_string()));
+        assert_eq!(error(": main again"), (1, 8, "'again' without 'loop'".to_string()));
+        assert_eq!(error(": main :calc X { 1 + }"), (1, 20, "expected a value".to_string()));
+        assert_eq!(error(": f ;\n: f ;"), (2, 3, "'f' is already defined".to_string()));
+        assert_eq!(error(": v0 ;"), (1, 3, "'v0' can't be used as a name".to_string()));
+        assert_eq!(error(": main v0 :="), (1, 1, "unexpected end of file".to_string()));
+        assert_eq!(error(": f ;"), (1, 1, "the program has no 'main' label".to_string()));
+    }
+
+    #[test]
+    fn recursive_macros_are_stopped() {
+        let (line, column, message) = error(":macro forever { forever }\n: main forever");
+        assert_eq!((line, column), (1, 18));
+        assert_eq!(message, "macro 'forever' expanded too many times");
+    }
+}
@@ -1,14 +1,24 @@
 use std::fmt;
+use super::set::Variant;
 
 pub enum Op {
     SYS  { addr: u16 },                         // 0nnn
+    SCD  { nibble: u8 },                        // 00Cn (SCHIP)
+    SCU  { nibble: u8 },                        // 00Dn (XO-CHIP)
     CLS,                                        // 00E0
     RET,                                        // 00EE
+    SCR,                                        // 00FB (SCHIP)
+    SCL,                                        // 00FC (SCHIP)
+    EXIT,                                       // 00FD (SCHIP)
+    LOW,                                        // 00FE (SCHIP)
+    HIGH,                                       // 00FF (SCHIP)
     JP   { addr: u16 },                         // 1nnn
     CALL { addr: u16 },                         // 2nnn
     SE   { reg: u8, byte: u8 },                 // 3xkk
     SNE  { reg: u8, byte: u8 },                 // 4xkk
     SER  { reg_a: u8, reg_b: u8 },              // 5xy0
+    STRG { reg_a: u8, reg_b: u8 },              // 5xy2 (XO-CHIP)
+    LDRG { reg_a: u8, reg_b: u8 },              // 5xy3 (XO-CHIP)
     LD   { reg: u8, byte: u8 },                 // 6xkk
     ADD  { reg: u8, byte: u8 },                 // 7xkk
     LDR  { reg_a: u8, reg_b: u8 },              // 8xy0
@@ -27,26 +37,108 @@ pub enum Op {
     DRW  { reg_a: u8, reg_b: u8, nibble: u8 },  // Dxyn
     SKP  { reg: u8 },                           // Ex9E
     SKNP { reg: u8 },                           // ExA1
+    LDL  { addr: u16 },                         // F000 nnnn (XO-CHIP)
+    PLN  { nibble: u8 },                        // Fn01 (XO-CHIP)
+    LDAU,                                       // F002 (XO-CHIP)
     LDDT { reg: u8 },                           // Fx07
     LDKP { reg: u8 },                           // Fx0A
     STDT { reg: u8 },                           // Fx15
     STST { reg: u8 },                           // Fx18
     ADDA { reg: u8 },                           // Fx1E
     LDSA { reg: u8 },                           // Fx29
+    LDHF { reg: u8 },                           // Fx30 (SCHIP)
+    PTCH { reg: u8 },                           // Fx3A (XO-CHIP)
     STDR { reg: u8 },                           // Fx33
     STRR { reg: u8 },                           // Fx55
-    LDRR { reg: u8 },                           // Fx66
+    LDRR { reg: u8 },                           // Fx65
+    STRP { reg: u8 },                           // Fx75 (SCHIP)
+    LDRP { reg: u8 },                           // Fx85 (SCHIP)
     INV  { opcode: u16 },                       // invalid
 }
 
 impl Op {
-    pub fn decode(opcode: u16) -> Self {
+    // F000 nnnn (LDL) is the only instruction that is 4 bytes long, its address is in the 2 bytes after the opcode
+    pub fn is_long(opcode: u16, variant: Variant) -> bool {
+        variant == Variant::XOCHIP && opcode == 0xF000
+    }
+
+    // Inverse of decode, LDL (F000 nnnn) only gives the first word and is followed by its address
+    pub fn encode(&self) -> u16 {
+        let x = |reg: u8| (reg as u16 & 0xF) << 8;
+        let xy = |reg_a: u8, reg_b: u8| x(reg_a) | (reg_b as u16 & 0xF) << 4;
+        match *self {
+            Self::SYS  { addr }                 => addr & 0x0FFF,
+            Self::SCD  { nibble }               => 0x00C0 | (nibble as u16 & 0xF),
+            Self::SCU  { nibble }               => 0x00D0 | (nibble as u16 & 0xF),
+            Self::CLS                           => 0x00E0,
+            Self::RET                           => 0x00EE,
+            Self::SCR                           => 0x00FB,
+            Self::SCL                           => 0x00FC,
+            Self::EXIT                          => 0x00FD,
+            Self::LOW                           => 0x00FE,
+            Self::HIGH                          => 0x00FF,
+            Self::JP   { addr }                 => 0x1000 | (addr & 0x0FFF),
+            Self::CALL { addr }                 => 0x2000 | (addr & 0x0FFF),
+            Self::SE   { reg, byte }            => 0x3000 | x(reg) | byte as u16,
+            Self::SNE  { reg, byte }            => 0x4000 | x(reg) | byte as u16,
+            Self::SER  { reg_a, reg_b }         => 0x5000 | xy(reg_a, reg_b),
+            Self::STRG { reg_a, reg_b }         => 0x5002 | xy(reg_a, reg_b),
+            Self::LDRG { reg_a, reg_b }         => 0x5003 | xy(reg_a, reg_b),
+            Self::LD   { reg, byte }            => 0x6000 | x(reg) | byte as u16,
+            Self::ADD  { reg, byte }            => 0x7000 | x(reg) | byte as u16,
+            Self::LDR  { reg_a, reg_b }         => 0x8000 | xy(reg_a, reg_b),
+            Self::OR   { reg_a, reg_b }         => 0x8001 | xy(reg_a, reg_b),
+            Self::AND  { reg_a, reg_b }         => 0x8002 | xy(reg_a, reg_b),
+            Self::XOR  { reg_a, reg_b }         => 0x8003 | xy(reg_a, reg_b),
+            Self::ADDR { reg_a, reg_b }         => 0x8004 | xy(reg_a, reg_b),
+            Self::SUB  { reg_a, reg_b }         => 0x8005 | xy(reg_a, reg_b),
+            Self::SHR  { reg_a, reg_b }         => 0x8006 | xy(reg_a, reg_b),
+            Self::SUBN { reg_a, reg_b }         => 0x8007 | xy(reg_a, reg_b),
+            Self::SHL  { reg_a, reg_b }         => 0x800E | xy(reg_a, reg_b),
+            Self::SNER { reg_a, reg_b }         => 0x9000 | xy(reg_a, reg_b),
+            Self::LDA  { addr }                 => 0xA000 | (addr & 0x0FFF),
+            Self::JPO  { addr }                 => 0xB000 | (addr & 0x0FFF),
+            Self::RND  { reg, byte }            => 0xC000 | x(reg) | byte as u16,
+            Self::DRW  { reg_a, reg_b, nibble } => 0xD000 | xy(reg_a, reg_b) | (nibble as u16 & 0xF),
+            Self::SKP  { reg }                  => 0xE09E | x(reg),
+            Self::SKNP { reg }                  => 0xE0A1 | x(reg),
+            Self::LDL  { .. }                   => 0xF000,
+            Self::PLN  { nibble }               => 0xF001 | x(nibble),
+            Self::LDAU                          => 0xF002,
+            Self::LDDT { reg }                  => 0xF007 | x(reg),
+            Self::LDKP { reg }                  => 0xF00A | x(reg),
+            Self::STDT { reg }                  => 0xF015 | x(reg),
+            Self::STST { reg }                  => 0xF018 | x(reg),
+            Self::ADDA { reg }                  => 0xF01E | x(reg),
+            Self::LDSA { reg }                  => 0xF029 | x(reg),
+            Self::LDHF { reg }                  => 0xF030 | x(reg),
+            Self::PTCH { reg }                  => 0xF03A | x(reg),
+            Self::STDR { reg }                  => 0xF033 | x(reg),
+            Self::STRR { reg }                  => 0xF055 | x(reg),
+            Self::LDRR { reg }                  => 0xF065 | x(reg),
+            Self::STRP { reg }                  => 0xF075 | x(reg),
+            Self::LDRP { reg }                  => 0xF085 | x(reg),
+            Self::INV  { opcode }               => opcode,
+        }
+    }
+
+    pub fn decode(opcode: u16, variant: Variant) -> Self {
+        // XO-CHIP extends SCHIP
+        let schip = variant != Variant::CHIP8;
+        let xochip = variant == Variant::XOCHIP;
         match opcode & 0xF000 {
             0x0000 => {
                 let addr = opcode & 0x0FFF;
                 match addr {
+                    0x00C0..=0x00CF if schip => Self::SCD { nibble: (addr & 0x000F) as u8 },
+                    0x00D0..=0x00DF if xochip => Self::SCU { nibble: (addr & 0x000F) as u8 },
                     0x00E0 => Self::CLS,
                     0x00EE => Self::RET,
+                    0x00FB if schip => Self::SCR,
+                    0x00FC if schip => Self::SCL,
+                    0x00FD if schip => Self::EXIT,
+                    0x00FE if schip => Self::LOW,
+                    0x00FF if schip => Self::HIGH,
                     _ => Self::SYS { addr }
                 }
             }
@@ -69,12 +161,12 @@ impl Op {
                 Self::SNE { reg, byte }
             }
             0x5000 => {
+                let reg_a = ((opcode & 0x0F00) >> 8) as u8;
+                let reg_b = ((opcode & 0x00F0) >> 4) as u8;
                 match opcode & 0x000F {
-                    0x0 => {
-                        let reg_a = ((opcode & 0x0F00) >> 8) as u8;
-                        let reg_b = ((opcode & 0x00F0) >> 4) as u8;
-                        Self::SER { reg_a, reg_b }
-                    }
+                    0x0 => Self::SER  { reg_a, reg_b },
+                    0x2 if xochip => Self::STRG { reg_a, reg_b },
+                    0x3 if xochip => Self::LDRG { reg_a, reg_b },
                     _ => Self::INV { opcode }
                 }
             }
@@ -144,15 +236,21 @@ impl Op {
             0xF000 => {
                 let reg = ((opcode & 0x0F00) >> 8) as u8;
                 match opcode & 0x00FF {
+                    0x01 if xochip => Self::PLN { nibble: reg },
+                    0x02 if xochip && reg == 0 => Self::LDAU,
                     0x07 => Self::LDDT { reg },
                     0x0A => Self::LDKP { reg },
                     0x15 => Self::STDT { reg },
                     0x18 => Self::STST { reg },
                     0x1E => Self::ADDA { reg },
                     0x29 => Self::LDSA { reg },
+                    0x30 if schip => Self::LDHF { reg },
+                    0x3A if xochip => Self::PTCH { reg },
                     0x33 => Self::STDR { reg },
                     0x55 => Self::STRR { reg },
                     0x65 => Self::LDRR { reg },
+                    0x75 if schip => Self::STRP { reg },
+                    0x85 if schip => Self::LDRP { reg },
                     _ => Self::INV { opcode }
                 }
             }
@@ -163,13 +261,22 @@ impl Op {
     pub fn name(&self) -> &'static str {
         match self{
             Self::SYS  {..} => "SYS",
+            Self::SCD  {..} => "SCD",
+            Self::SCU  {..} => "SCU",
             Self::CLS       => "CLS",
             Self::RET       => "RET",
+            Self::SCR       => "SCR",
+            Self::SCL       => "SCL",
+            Self::EXIT      => "EXIT",
+            Self::LOW       => "LOW",
+            Self::HIGH      => "HIGH",
             Self::JP   {..} => "JP",
             Self::CALL {..} => "CALL",
             Self::SE   {..} => "SE",
             Self::SNE  {..} => "SNE",
             Self::SER  {..} => "SER",
+            Self::STRG {..} => "STRG",
+            Self::LDRG {..} => "LDRG",
             Self::LD   {..} => "LD",
             Self::ADD  {..} => "ADD",
             Self::LDR  {..} => "LDR",
@@ -188,15 +295,22 @@ impl Op {
             Self::DRW  {..} => "DRW",
             Self::SKP  {..} => "SKP",
             Self::SKNP {..} => "SKNP",
+            Self::LDL  {..} => "LDL",
+            Self::PLN  {..} => "PLN",
+            Self::LDAU      => "LDAU",
             Self::LDDT {..} => "LDDT",
             Self::LDKP {..} => "LDKP",
             Self::STDT {..} => "STDT",
             Self::STST {..} => "STST",
             Self::ADDA {..} => "ADDA",
             Self::LDSA {..} => "LDSA",
+            Self::LDHF {..} => "LDHF",
+            Self::PTCH {..} => "PTCH",
             Self::STDR {..} => "STDR",
             Self::STRR {..} => "STRR",
             Self::LDRR {..} => "LDRR",
+            Self::STRP {..} => "STRP",
+            Self::LDRP {..} => "LDRP",
             Self::INV  {..} => "INV",
         }
     }
@@ -208,6 +322,16 @@ impl fmt::Display for Op {
         match self{
             Self::CLS                           => Ok(()),
             Self::RET                           => Ok(()),
+            Self::SCR                           => Ok(()),
+            Self::SCL                           => Ok(()),
+            Self::EXIT                          => Ok(()),
+            Self::LOW                           => Ok(()),
+            Self::HIGH                          => Ok(()),
+            Self::LDAU                          => Ok(()),
+            Self::SCD  { nibble }               => write!(f, " {:X}", nibble),
+            Self::SCU  { nibble }               => write!(f, " {:X}", nibble),
+            Self::PLN  { nibble }               => write!(f, " {:X}", nibble),
+            Self::LDL  { addr }                 => write!(f, " {:#06X}", addr),
             Self::SYS  { addr }                 => write!(f, " {:#05X}", addr),
             Self::JP   { addr }                 => write!(f, " {:#05X}", addr),
             Self::CALL { addr }                 => write!(f, " {:#05X}", addr),
@@ -221,15 +345,21 @@ impl fmt::Display for Op {
             Self::STST { reg }                  => write!(f, " V{:X}", reg),
             Self::ADDA { reg }                  => write!(f, " V{:X}", reg),
             Self::LDSA { reg }                  => write!(f, " V{:X}", reg),
+            Self::LDHF { reg }                  => write!(f, " V{:X}", reg),
+            Self::PTCH { reg }                  => write!(f, " V{:X}", reg),
             Self::STDR { reg }                  => write!(f, " V{:X}", reg),
             Self::STRR { reg }                  => write!(f, " V{:X}", reg),
             Self::LDRR { reg }                  => write!(f, " V{:X}", reg),
+            Self::STRP { reg }                  => write!(f, " V{:X}", reg),
+            Self::LDRP { reg }                  => write!(f, " V{:X}", reg),
             Self::SE   { reg, byte }            => write!(f, " V{:X} {:#04X}", reg, byte),
             Self::SNE  { reg, byte }            => write!(f, " V{:X} {:#04X}", reg, byte),
             Self::LD   { reg, byte }            => write!(f, " V{:X} {:#04X}", reg, byte),
             Self::ADD  { reg, byte }            => write!(f, " V{:X} {:#04X}", reg, byte),
             Self::RND  { reg, byte }            => write!(f, " V{:X} {:#04X}", reg, byte),
             Self::SER  { reg_a, reg_b }         => write!(f, " V{:X} V{:X}", reg_a, reg_b),
+            Self::STRG { reg_a, reg_b }         => write!(f, " V{:X} V{:X}", reg_a, reg_b),
+            Self::LDRG { reg_a, reg_b }         => write!(f, " V{:X} V{:X}", reg_a, reg_b),
             Self::LDR  { reg_a, reg_b }         => write!(f, " V{:X} V{:X}", reg_a, reg_b),
             Self::OR   { reg_a, reg_b }         => write!(f, " V{:X} V{:X}", reg_a, reg_b),
             Self::AND  { reg_a, reg_b }         => write!(f, " V{:X} V{:X}", reg_a, reg_b),
@@ -245,3 +375,104 @@ impl fmt::Display for Op {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VARIANTS: [Variant; 3] = [Variant::CHIP8, Variant::SCHIP, Variant::XOCHIP];
+
+    // Every opcode pattern and how it decodes in CHIP8, SCHIP and XO-CHIP
+    const PATTERNS: &[(u16, [&str; 3])] = &[
+        (0x0123, ["SYS 0x123", "SYS 0x123", "SYS 0x123"]),
+        (0x00C5, ["SYS 0x0C5", "SCD 5", "SCD 5"]),
+        (0x00DA, ["SYS 0x0DA", "SYS 0x0DA", "SCU A"]),
+        (0x00E0, ["CLS", "CLS", "CLS"]),
+        (0x00EE, ["RET", "RET", "RET"]),
+        (0x00FB, ["SYS 0x0FB", "SCR", "SCR"]),
+        (0x00FC, ["SYS 0x0FC", "SCL", "SCL"]),
+        (0x00FD, ["SYS 0x0FD", "EXIT", "EXIT"]),
+        (0x00FE, ["SYS 0x0FE", "LOW", "LOW"]),
+        (0x00FF, ["SYS 0x0FF", "HIGH", "HIGH"]),
+        (0x1ABC, ["JP 0xABC", "JP 0xABC", "JP 0xABC"]),
+        (0x2345, ["CALL 0x345", "CALL 0x345", "CALL 0x345"]),
+        (0x31F0, ["SE V1 0xF0", "SE V1 0xF0", "SE V1 0xF0"]),
+        (0x4A05, ["SNE VA 0x05", "SNE VA 0x05", "SNE VA 0x05"]),
+        (0x5120, ["SER V1 V2", "SER V1 V2", "SER V1 V2"]),
+        (0x5121, ["INV 0x5121", "INV 0x5121", "INV 0x5121"]),
+        (0x5122, ["INV 0x5122", "INV 0x5122", "STRG V1 V2"]),
+        (0x5123, ["INV 0x5123", "INV 0x5123", "LDRG V1 V2"]),
+        (0x512F, ["INV 0x512F", "INV 0x512F", "INV 0x512F"]),
+        (0x6E7F, ["LD VE 0x7F", "LD VE 0x7F", "LD VE 0x7F"]),
+        (0x7301, ["ADD V3 0x01", "ADD V3 0x01", "ADD V3 0x01"]),
+        (0x8AB0, ["LDR VA VB", "LDR VA VB", "LDR VA VB"]),
+        (0x8AB1, ["OR VA VB", "OR VA VB", "OR VA VB"]),
+        (0x8AB2, ["AND VA VB", "AND VA VB", "AND VA VB"]),
+        (0x8AB3, ["XOR VA VB", "XOR VA VB", "XOR VA VB"]),
+        (0x8AB4, ["ADDR VA VB", "ADDR VA VB", "ADDR VA VB"]),
+        (0x8AB5, ["SUB VA VB", "SUB VA VB", "SUB VA VB"]),
+        (0x8AB6, ["SHR VA VB", "SHR VA VB", "SHR VA VB"]),
+        (0x8AB7, ["SUBN VA VB", "SUBN VA VB", "SUBN VA VB"]),
+        (0x8AB8, ["INV 0x8AB8", "INV 0x8AB8", "INV 0x8AB8"]),
+        (0x8ABE, ["SHL VA VB", "SHL VA VB", "SHL VA VB"]),
+        (0x8ABF, ["INV 0x8ABF", "INV 0x8ABF", "INV 0x8ABF"]),
+        (0x9120, ["SNER V1 V2", "SNER V1 V2", "SNER V1 V2"]),
+        (0x9121, ["INV 0x9121", "INV 0x9121", "INV 0x9121"]),
+        (0xA2F0, ["LDA 0x2F0", "LDA 0x2F0", "LDA 0x2F0"]),
+        (0xB300, ["JPO 0x300", "JPO 0x300", "JPO 0x300"]),
+        (0xC40F, ["RND V4 0x0F", "RND V4 0x0F", "RND V4 0x0F"]),
+        (0xD125, ["DRW V1 V2 5", "DRW V1 V2 5", "DRW V1 V2 5"]),
+        (0xD120, ["DRW V1 V2 0", "DRW V1 V2 0", "DRW V1 V2 0"]),
+        (0xE59E, ["SKP V5", "SKP V5", "SKP V5"]),
+        (0xE5A1, ["SKNP V5", "SKNP V5", "SKNP V5"]),
+        (0xE5FF, ["INV 0xE5FF", "INV 0xE5FF", "INV 0xE5FF"]),
+        (0xF000, ["INV 0xF000", "INV 0xF000", "INV 0xF000"]),
+        (0xF301, ["INV 0xF301", "INV 0xF301", "PLN 3"]),
+        (0xF002, ["INV 0xF002", "INV 0xF002", "LDAU"]),
+        (0xF102, ["INV 0xF102", "INV 0xF102", "INV 0xF102"]),
+        (0xF607, ["LDDT V6", "LDDT V6", "LDDT V6"]),
+        (0xF60A, ["LDKP V6", "LDKP V6", "LDKP V6"]),
+        (0xF615, ["STDT V6", "STDT V6", "STDT V6"]),
+        (0xF618, ["STST V6", "STST V6", "STST V6"]),
+        (0xF61E, ["ADDA V6", "ADDA V6", "ADDA V6"]),
+        (0xF629, ["LDSA V6", "LDSA V6", "LDSA V6"]),
+        (0xF630, ["INV 0xF630", "LDHF V6", "LDHF V6"]),
+        (0xF633, ["STDR V6", "STDR V6", "STDR V6"]),
+        (0xF63A, ["INV 0xF63A", "INV 0xF63A", "PTCH V6"]),
+        (0xF655, ["STRR V6", "STRR V6", "STRR V6"]),
+        (0xF665, ["LDRR V6", "LDRR V6", "LDRR V6"]),
+        (0xF675, ["INV 0xF675", "STRP V6", "STRP V6"]),
+        (0xF685, ["INV 0xF685", "LDRP V6", "LDRP V6"]),
+        (0xF6FF, ["INV 0xF6FF", "INV 0xF6FF", "INV 0xF6FF"]),
+    ];
+
+    #[test]
+    fn decode_and_format() {
+        for (opcode, expected) in PATTERNS {
+            for (variant, expected) in VARIANTS.iter().zip(expected) {
+                let op = Op::decode(*opcode, *variant);
+                assert_eq!(op.to_string(), *expected, "{:#06X} in {}", opcode, variant.name());
+                assert_eq!(op.name(), expected.split(' ').next().unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn encode_inverts_decode() {
+        for variant in VARIANTS {
+            for opcode in 0..=u16::MAX {
+                assert_eq!(Op::decode(opcode, variant).encode(), opcode, "{:#06X} in {}", opcode, variant.name());
+            }
+        }
+    }
+
+    #[test]
+    fn long_instructions() {
+        let op = Op::LDL { addr: 0x1234 };
+        assert_eq!(op.to_string(), "LDL 0x1234");
+        assert_eq!(op.encode(), 0xF000);
+        assert!(Op::is_long(0xF000, Variant::XOCHIP));
+        assert!(!Op::is_long(0xF000, Variant::SCHIP));
+        assert!(!Op::is_long(0xF001, Variant::XOCHIP));
+    }
+}
@@ -0,0 +1,139 @@
+// Colours of the display as 0xRRGGBB, indexed by the bit mask of the XO-CHIP planes a pixel is set in: slot 0 is the
+// background, 1 plane 1, 2 plane 2 and 3 both. There are slots for up to four planes.
+const SLOTS: usize = 16;
+
+const PRESETS: [(&str, [u32; 4]); 5] = [
+    ("default",       [0x191428, 0xC8C8FF, 0x6E5AB4, 0xFFFFFF]),
+    ("green",         [0x0A1A0A, 0x33FF33, 0x1A801A, 0xAAFFAA]),
+    ("amber",         [0x1A1000, 0xFFB000, 0x805800, 0xFFE0A0]),
+    ("lcd",           [0xB8C0B0, 0x303830, 0x788070, 0x101410]),
+    ("high-contrast", [0x000000, 0xFFFFFF, 0xFFFF00, 0x00FFFF]),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    colors: [u32; SLOTS],
+}
+
+impl Palette {
+    pub fn new() -> Self {
+        Self::from_colors(&PRESETS[0].1)
+    }
+
+    pub fn preset(name: &str) -> Result<Self, String> {
+        let (_, colors) = PRESETS.iter().find(|(preset, _)| *preset == name).ok_or_else(|| {
+            let presets: Vec<&str> = PRESETS.iter().map(|(preset, _)| *preset).collect();
+            format!("expected {}, got '{}'", presets.join(", "), name)
+        })?;
+        Ok(Self::from_colors(colors))
+    }
+
+    // A comma separated list of hex colours for the first slots, e.g. "#000000, #FFFFFF". The slots left out use the
+    // colour of plane 1.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut colors = Vec::new();
+        for color in value.split(',') {
+            let color = color.trim();
+            let hex = color.strip_prefix('#').unwrap_or(color);
+            if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("expected a colour such as #C8C8FF, got '{}'", color));
+            }
+            colors.push(u32::from_str_radix(hex, 16).unwrap());
+        }
+        if colors.len() < 2 || colors.len() > SLOTS {
+            return Err(format!("expected 2 to {} colours, got {}", SLOTS, colors.len()));
+        }
+        Ok(Self::from_colors(&colors))
+    }
+
+    fn from_colors(colors: &[u32]) -> Self {
+        let mut palette = Self { colors: [colors[1]; SLOTS] };
+        palette.colors[..colors.len()].copy_from_slice(colors);
+        palette
+    }
+
+    // Colour of a slot as (r, g, b)
+    pub fn color(&self, slot: u8) -> (u8, u8, u8) {
+        let rgb = self.colors[slot as usize % SLOTS];
+        ((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+    }
+
+    // Colour of a slot blended over the background, level / 255 of the way from it
+    pub fn blend(&self, slot: u8, level: u8) -> (u8, u8, u8) {
+        let mix = |background: u8, color: u8| {
+            let level = level as u32;
+            ((background as u32 * (255 - level) + color as u32 * level) / 255) as u8
+        };
+        let (background, color) = (self.color(0), self.color(slot));
+        (mix(background.0, color.0), mix(background.1, color.1), mix(background.2, color.2))
+    }
+
+    // Every slot in the form parse takes
+    pub fn value(&self) -> String {
+        let colors: Vec<String> = self.colors.iter().map(|rgb| format!("#{:06X}", rgb)).collect();
+        colors.join(", ")
+    }
+
+    // The preset after this palette, or the first one if it isn't a preset
+    pub fn next(&self) -> (&'static str, Self) {
+        let current = PRESETS.iter().position(|(_, colors)| Self::from_colors(colors) == *self);
+        let (name, colors) = PRESETS[current.map_or(0, |i| (i + 1) % PRESETS.len())];
+        (name, Self::from_colors(&colors))
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_slots_use_plane_1() {
+        let palette = Palette::parse("#000000, #102030").unwrap();
+        assert_eq!(palette.color(0), (0x00, 0x00, 0x00));
+        assert_eq!(palette.color(1), (0x10, 0x20, 0x30));
+        assert_eq!(palette.color(3), (0x10, 0x20, 0x30));
+        assert_eq!(palette.color(15), (0x10, 0x20, 0x30));
+        assert_eq!(Palette::preset("amber").unwrap().color(4), (0xFF, 0xB0, 0x00));
+    }
+
+    #[test]
+    fn parse_checks_colours() {
+        assert_eq!(Palette::parse("191428, C8C8FF, 6E5AB4, FFFFFF").unwrap(), Palette::new());
+        assert!(Palette::parse("#000000").is_err());
+        assert!(Palette::parse("#000000, #FFF").is_err());
+        assert!(Palette::parse("#000000, white").is_err());
+        assert!(Palette::parse(&vec!["#000000"; 17].join(", ")).is_err());
+    }
+
+    #[test]
+    fn blend_fades_to_the_background() {
+        let palette = Palette::parse("#204060, #A0C0E0").unwrap();
+        assert_eq!(palette.blend(1, 255), (0xA0, 0xC0, 0xE0));
+        assert_eq!(palette.blend(1, 0), (0x20, 0x40, 0x60));
+        assert_eq!(palette.blend(1, 128), (0x60, 0x80, 0xA0));
+    }
+
+    #[test]
+    fn value_round_trips() {
+        let palette = Palette::preset("lcd").unwrap();
+        assert_eq!(Palette::parse(&palette.value()).unwrap(), palette);
+    }
+
+    #[test]
+    fn next_cycles_through_the_presets() {
+        let mut palette = Palette::parse("#000000, #123456").unwrap();
+        let mut names = Vec::new();
+        for _ in 0..PRESETS.len() + 1 {
+            let (name, next) = palette.next();
+            names.push(name);
+            palette = next;
+        }
+        assert_eq!(names, ["default", "green", "amber", "lcd", "high-contrast", "default"]);
+    }
+}
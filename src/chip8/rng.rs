@@ -3,6 +3,9 @@
 
 // RNG(n+1) = (A * RNG(n) + C) mod M
 
+use super::err::Chip8Error;
+use super::sav::{Reader, Writer};
+
 const RNG_A: u32 = 48271;
 const RNG_C: u32 = 1;
 const RNG_M: u32 = 2147483647;
@@ -16,6 +19,14 @@ impl RNG {
         Self { seed }
     }
 
+    pub fn save(&self, w: &mut Writer) {
+        w.u32(self.seed);
+    }
+
+    pub fn load(r: &mut Reader) -> Result<Self, Chip8Error> {
+        Ok(Self { seed: r.u32()? })
+    }
+
     pub fn next(&mut self) -> u8 {
         self.seed = RNG_A.overflowing_mul(self.seed).0.overflowing_add(RNG_C).0 % RNG_M;
         (self.seed % 256) as u8
@@ -0,0 +1,197 @@
+use std::collections::VecDeque;
+
+// History of save states for rewinding, one per frame.
+// Only the newest state is kept whole, each older one is stored as the difference to the state after it:
+// the XOR of both, which is mostly zero between consecutive frames, as runs of zeros and literal bytes.
+pub struct Rewind {
+    capacity: usize,
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    // capacity is the number of states to keep, 0 disables rewinding
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            newest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(newest) = self.newest.take() {
+            // The state size only changes with the variant, older states can't be restored past that point
+            if newest.len() == state.len() {
+                self.deltas.push_back(encode(&newest, &state));
+                if self.deltas.len() >= self.capacity {
+                    self.deltas.pop_front();
+                }
+            } else {
+                self.deltas.clear();
+            }
+        }
+        self.newest = Some(state);
+    }
+
+    // Removes the newest state and returns the one before it
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let delta = self.deltas.pop_back()?;
+        let state = self.newest.as_mut()?;
+        decode(&delta, state);
+        Some(state.clone())
+    }
+}
+
+// Runs of the XOR of a and b: length of zeros, length of literal bytes, the literal bytes
+fn encode(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut i = 0;
+    while i < a.len() {
+        let zeros = a[i..].iter().zip(&b[i..]).take_while(|(x, y)| x == y).count();
+        i += zeros;
+        let literal = a[i..].iter().zip(&b[i..]).take_while(|(x, y)| x != y).count();
+        write_len(&mut delta, zeros);
+        write_len(&mut delta, literal);
+        delta.extend(a[i..i + literal].iter().zip(&b[i..i + literal]).map(|(x, y)| x ^ y));
+        i += literal;
+    }
+    delta
+}
+
+// XORs the delta into the state, turning one of the states it was made from into the other
+fn decode(delta: &[u8], state: &mut [u8]) {
+    let mut d = 0;
+    let mut i = 0;
+    while d < delta.len() {
+        i += read_len(delta, &mut d);
+        let literal = read_len(delta, &mut d);
+        for (byte, x) in state[i..i + literal].iter_mut().zip(&delta[d..d + literal]) {
+            *byte ^= x;
+        }
+        i += literal;
+        d += literal;
+    }
+}
+
+// LEB128, 7 bits per byte with the high bit set on all but the last
+fn write_len(delta: &mut Vec<u8>, mut len: usize) {
+    while len >= 0x80 {
+        delta.push((len & 0x7F) as u8 | 0x80);
+        len >>= 7;
+    }
+    delta.push(len as u8);
+}
+
+fn read_len(delta: &[u8], d: &mut usize) -> usize {
+    let mut len = 0;
+    let mut shift = 0;
+    loop {
+        let byte = delta[*d];
+        *d += 1;
+        len |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return len;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use super::super::super::frontend::Headless;
+    use super::super::rng::RNG;
+    use super::super::set::Settings;
+    use super::super::CHIP8;
+
+    // A state of random bytes and another one differing from it in runs of random length, some longer than 0x7F so
+    // their lengths take more than one byte
+    fn states(rng: &mut RNG, len: usize) -> (Vec<u8>, Vec<u8>) {
+        let a: Vec<u8> = (0..len).map(|_| rng.next()).collect();
+        let mut b = a.clone();
+        let mut i = 0;
+        while i < len {
+            i += rng.next() as usize * (1 + rng.next() as usize % 4);
+            let changed = 1 + rng.next() as usize % 200;
+            for byte in b.iter_mut().skip(i).take(changed) {
+                *byte = rng.next();
+            }
+            i += changed;
+        }
+        (a, b)
+    }
+
+    #[test]
+    fn deltas_turn_either_state_into_the_other() {
+        let mut rng = RNG::new(1);
+        for len in [0, 1, 2, 100, 5000, 70000] {
+            let (a, b) = states(&mut rng, len);
+            let delta = encode(&a, &b);
+            let mut state = b.clone();
+            decode(&delta, &mut state);
+            assert_eq!(state, a, "{} bytes", len);
+            decode(&delta, &mut state);
+            assert_eq!(state, b, "{} bytes", len);
+        }
+        // Equal states need no literals
+        assert_eq!(encode(&[7; 300], &[7; 300]), [0xAC, 0x02, 0x00]);
+    }
+
+    #[test]
+    fn rewinding_restores_the_states_in_reverse() {
+        let rom = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("roms").join("BRIX");
+        let mut chip8 = CHIP8::new(Headless, Settings::new());
+        chip8.load_rom(rom).unwrap();
+
+        let mut rewind = Rewind::new(100);
+        let mut states = Vec::new();
+        for frame in 0..60 {
+            let mut keys = [false; 16];
+            keys[if frame < 30 { 0x4 } else { 0x6 }] = true;
+            chip8.set_keys(keys);
+            chip8.run_frame().unwrap();
+            let state = chip8.machine_state();
+            rewind.push(state.clone());
+            states.push(state);
+        }
+
+        states.pop();
+        while let Some(expected) = states.pop() {
+            let state = rewind.pop().expect("every state is kept");
+            assert!(state == expected, "{} states back", 60 - states.len() - 1);
+            chip8.load_machine_state(&state).unwrap();
+            assert!(chip8.machine_state() == expected);
+        }
+        assert!(rewind.pop().is_none());
+    }
+
+    #[test]
+    fn the_oldest_states_are_dropped() {
+        let mut rewind = Rewind::new(4);
+        for i in 0..10 {
+            rewind.push(vec![i; 16]);
+        }
+        let restored: Vec<Vec<u8>> = std::iter::from_fn(|| rewind.pop()).collect();
+        assert_eq!(restored, [vec![8; 16], vec![7; 16], vec![6; 16]]);
+
+        let mut rewind = Rewind::new(0);
+        rewind.push(vec![1]);
+        rewind.push(vec![2]);
+        assert!(rewind.pop().is_none());
+    }
+
+    #[test]
+    fn states_of_another_size_end_the_history() {
+        let mut rewind = Rewind::new(10);
+        rewind.push(vec![1; 4]);
+        rewind.push(vec![2; 8]);
+        rewind.push(vec![3; 8]);
+        assert_eq!(rewind.pop(), Some(vec![2; 8]));
+        assert_eq!(rewind.pop(), None);
+    }
+}
@@ -0,0 +1,159 @@
+use super::err::Chip8Error;
+
+// Save states start with MAGIC and VERSION followed by the SHA-1 of the ROM they were made with.
+// VERSION must be increased whenever the layout of the data that follows changes.
+pub const MAGIC: &[u8; 4] = b"C8ST";
+pub const VERSION: u16 = 3;
+
+// Appends values to a save state, numbers are stored little endian
+pub struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self { bytes: Vec::new() }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    // Length prefixed, for data whose size isn't fixed by the format
+    pub fn block(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.bytes(bytes);
+    }
+
+    pub fn str(&mut self, value: &str) {
+        self.block(value.as_bytes());
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+// Reads values back in the order they were written, failing instead of panicking on truncated or corrupted data
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Chip8Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Chip8Error> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(invalid(format!("expected a boolean, got {}", value))),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Chip8Error> {
+        let mut value = [0; 2];
+        value.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_le_bytes(value))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Chip8Error> {
+        let mut value = [0; 4];
+        value.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(value))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Chip8Error> {
+        let mut value = [0; 8];
+        value.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], Chip8Error> {
+        if self.bytes.len() - self.position < len {
+            return Err(invalid("unexpected end of data".to_string()));
+        }
+        let bytes = &self.bytes[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub fn block(&mut self) -> Result<&'a [u8], Chip8Error> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+
+    pub fn str(&mut self) -> Result<&'a str, Chip8Error> {
+        std::str::from_utf8(self.block()?).map_err(|_| invalid("expected UTF-8 text".to_string()))
+    }
+
+    pub fn finish(self) -> Result<(), Chip8Error> {
+        if self.position != self.bytes.len() {
+            return Err(invalid("unexpected data at the end".to_string()));
+        }
+        Ok(())
+    }
+}
+
+pub fn invalid(reason: String) -> Chip8Error {
+    Chip8Error::InvalidState(reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::super::super::frontend::Headless;
+    use super::super::flt::Filter;
+    use super::super::set::Settings;
+    use super::super::CHIP8;
+
+    #[test]
+    fn loading_a_state_keeps_the_host_settings() {
+        let rom = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("roms").join("BRIX");
+        let mut chip8 = CHIP8::new(Headless, Settings::new());
+        chip8.load_rom(rom).unwrap();
+        chip8.run_frame().unwrap();
+        let state = chip8.save_state();
+
+        chip8.settings.cpu_freq = 1000;
+        chip8.settings.mute = true;
+        chip8.settings.filter = Filter::Decay;
+        chip8.settings.rewind_seconds = 5;
+        chip8.settings.print_rom = true;
+        chip8.settings.print_opcodes = true;
+        chip8.settings.debug = true;
+        chip8.load_state(&state).unwrap();
+
+        assert_eq!(chip8.settings.cpu_freq, 700);
+        assert!(chip8.settings.mute);
+        assert_eq!(chip8.settings.filter, Filter::Decay);
+        assert_eq!(chip8.settings.rewind_seconds, 5);
+        assert!(chip8.settings.print_rom && chip8.settings.print_opcodes && chip8.settings.debug);
+    }
+}
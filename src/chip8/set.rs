@@ -1,4 +1,60 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
+use super::cfg;
+use super::err::Chip8Error;
+use super::flt::Filter;
+use super::key::{self, Keymap};
+use super::pal::Palette;
+use super::sav::{self, Reader, Writer};
+
+// Which instruction set and display the ROM was written for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Variant {
+    // Original COSMAC VIP CHIP-8, 64x32 display
+    CHIP8,
+    // SUPER-CHIP 1.1, adds a 128x64 display, scrolling, 16x16 sprites, a large font and RPL flags
+    SCHIP,
+    // XO-CHIP, extends SCHIP with 64 KiB of memory, two bit planes and programmable audio
+    XOCHIP,
+}
+
+impl Variant {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::CHIP8 => "chip8",
+            Self::SCHIP => "schip",
+            Self::XOCHIP => "xochip",
+        }
+    }
+
+    pub fn memory_size(&self) -> usize {
+        match self {
+            Self::CHIP8 | Self::SCHIP => 0x1000,
+            Self::XOCHIP => 0x10000,
+        }
+    }
+}
+
+// The settings set by a quirk preset
+const QUIRKS: [&str; 10] = [
+    "vf_reset_quirk", "load_store_quirk", "load_store_x_quirk", "shift_quirk", "address_overflow_quirk", "jump_quirk",
+    "display_wait_quirk", "wait_key_release", "horizontal_wrap", "vertical_wrap",
+];
+
+// How the original interpreters behave, as checked by Timendus' quirks test ROM
+const QUIRK_PRESETS: [(&str, [bool; 10]); 4] = [
+    //             vf_reset load_store load_store_x shift  address_overflow jump   display_wait key_release h_wrap v_wrap
+    ("cosmac-vip", [true,   true,      false,       false, false,           false, true,        true,       false, false]),
+    ("chip-48",    [false,  true,      true,        true,  false,           true,  false,       false,      false, false]),
+    ("schip-1.1",  [false,  false,     false,       true,  false,           true,  false,       false,      false, false]),
+    ("xo-chip",    [false,  true,      false,       false, false,           false, false,       false,      true,  true]),
+];
+
+#[derive(Clone)]
 pub struct Settings {
+    pub variant: Variant,
+
     // Where to load the ROM in memory
     pub rom_addr: u16,
 
@@ -8,40 +64,307 @@ pub struct Settings {
 
     pub rng_seed: u32,
 
+    // Charge every instruction the machine cycles it took on the COSMAC VIP instead of running cpu_freq instructions
+    // per second, for CHIP-8 ROMs that depend on the original speed
+    pub vip_timing: bool,
+
+    // The COSMAC VIP interpreter left VF reset to 0 after OR (8xy1), AND (8xy2) and XOR (8xy3).
+    // When vf_reset_quirk is true, VF will be set to 0 after these instructions.
+    pub vf_reset_quirk: bool,
     // Some games expect STRR (FX55) and LDRR (FX65) instructions to increment the I register.
-    // When load_store_quirk is true, I will be incremented after STRR and LDRR.
+    // When load_store_quirk is true, I will be incremented by X + 1 after STRR and LDRR.
     pub load_store_quirk: bool,
+    // CHIP-48 incremented I by one less than the COSMAC VIP.
+    // When load_store_x_quirk is true as well, I will be incremented by X instead.
+    pub load_store_x_quirk: bool,
     // Some games expect SHR (8xy6) and SHL (8xyE) operations to always shift Vx and ignore Vy.
     // When shift_quirk is true, Vy will be set to Vx before executing the instruction.
     pub shift_quirk: bool,
     // Some games expect ADDA (Fx1E) to set the VF flag register when overflow occurs.
     // When address_overflow_quirk is true, the VF will be set.
     pub address_overflow_quirk: bool,
+    // CHIP-48 and SCHIP read Bnnn as Bxnn and jump to xnn + Vx.
+    // When jump_quirk is true, JPO will add Vx instead of V0.
+    pub jump_quirk: bool,
+    // The COSMAC VIP waited for the vertical blank interrupt before drawing a sprite.
+    // When display_wait_quirk is true, DRW (Dxyn) will draw at most one sprite per frame.
+    pub display_wait_quirk: bool,
+
+    // The COSMAC VIP finished LDKP (Fx0A) when the key was released, other interpreters when it's pressed
+    pub wait_key_release: bool,
 
-    // Wrap drawing on the screen vertically
+    // Sprites always start on the screen, these wrap the parts going past the edges around instead of clipping them
+    pub horizontal_wrap: bool,
     pub vertical_wrap: bool,
 
     pub mute: bool,
 
+    // Host keys for the CHIP-8 keys, set all at once with "keymap" or one by one with key_0 to key_f, and the
+    // controller buttons for them with pad_0 to pad_f
+    pub keymap: Keymap,
+
+    // Display colours, set to a preset with "palette" or to a list of hex colours with "colors"
+    pub palette: Palette,
+
+    // Display filter against flicker, decay_frames is how long pixels take to fade out with the decay filter
+    pub filter: Filter,
+    pub decay_frames: u8,
+
+    // Seconds of history kept for rewinding in the SDL frontend, 0 disables rewinding
+    pub rewind_seconds: u16,
+
     pub print_rom: bool,
     pub print_opcodes: bool,
+
+    // Start paused and read debugger commands from the terminal
+    pub debug: bool,
 }
 
 impl Settings {
     pub fn new() -> Self {
         Self {
+            variant: Variant::CHIP8,
             rom_addr: 0x200,
             cpu_freq: 700,
             dt_freq: 60,
             st_freq: 60,
             rng_seed: 0,
+            vip_timing: false,
+            vf_reset_quirk: false,
             load_store_quirk: false,
+            load_store_x_quirk: false,
             shift_quirk: true,
             address_overflow_quirk: false,
+            jump_quirk: false,
+            display_wait_quirk: false,
+            wait_key_release: false,
+            horizontal_wrap: true,
             vertical_wrap: false,
             mute: false,
+            keymap: Keymap::new(),
+            palette: Palette::new(),
+            filter: Filter::Off,
+            decay_frames: 4,
+            rewind_seconds: 60,
             print_rom: false,
             print_opcodes: false,
+            debug: false,
+        }
+    }
+
+    // Change a setting by the name of its field, e.g. set("shift_quirk", "true"). "quirks" sets all the quirks at
+    // once to one of the QUIRK_PRESETS, "keymap" every key to one of the keymap presets, key_0 to key_f the keys one
+    // by one and pad_0 to pad_f their controller buttons.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "variant"                => self.variant = parse_variant(value)?,
+            "cpu_freq"               => self.cpu_freq = parse_freq(value)?,
+            "dt_freq"                => self.dt_freq = parse_freq(value)?,
+            "st_freq"                => self.st_freq = parse_freq(value)?,
+            "rng_seed"               => self.rng_seed = parse_number(value)?,
+            "vip_timing"             => self.vip_timing = parse_bool(value)?,
+            "quirks"                 => self.set_quirks(value)?,
+            "vf_reset_quirk"         => self.vf_reset_quirk = parse_bool(value)?,
+            "load_store_quirk"       => self.load_store_quirk = parse_bool(value)?,
+            "load_store_x_quirk"     => self.load_store_x_quirk = parse_bool(value)?,
+            "shift_quirk"            => self.shift_quirk = parse_bool(value)?,
+            "address_overflow_quirk" => self.address_overflow_quirk = parse_bool(value)?,
+            "jump_quirk"             => self.jump_quirk = parse_bool(value)?,
+            "display_wait_quirk"     => self.display_wait_quirk = parse_bool(value)?,
+            "wait_key_release"       => self.wait_key_release = parse_bool(value)?,
+            "horizontal_wrap"        => self.horizontal_wrap = parse_bool(value)?,
+            "vertical_wrap"          => self.vertical_wrap = parse_bool(value)?,
+            "mute"                   => self.mute = parse_bool(value)?,
+            "keymap"                 => self.keymap.set_preset(value)?,
+            "palette"                => self.palette = Palette::preset(value)?,
+            "colors"                 => self.palette = Palette::parse(value)?,
+            "filter"                 => self.filter = Filter::parse(value)?,
+            "decay_frames"           => self.decay_frames = parse_frames(value)?,
+            "rewind_seconds"         => self.rewind_seconds = parse_number(value)?,
+            "print_rom"              => self.print_rom = parse_bool(value)?,
+            "print_opcodes"          => self.print_opcodes = parse_bool(value)?,
+            "debug"                  => self.debug = parse_bool(value)?,
+            _ => if let Some(i) = key::SETTINGS.iter().position(|setting| *setting == key) {
+                self.keymap.set(i as u8, value)?
+            } else if let Some(i) = key::PAD_SETTINGS.iter().position(|setting| *setting == key) {
+                self.keymap.set_buttons(i as u8, value)?
+            } else {
+                return Err(format!("unknown setting '{}'", key));
+            },
+        }
+        Ok(())
+    }
+
+    fn set_quirks(&mut self, preset: &str) -> Result<(), String> {
+        let (_, values) = QUIRK_PRESETS.iter().find(|(name, _)| *name == preset).ok_or_else(|| {
+            let names: Vec<&str> = QUIRK_PRESETS.iter().map(|(name, _)| *name).collect();
+            format!("expected {}, got '{}'", names.join(", "), preset)
+        })?;
+        for (key, value) in QUIRKS.iter().zip(values) {
+            self.set(key, &value.to_string())?;
+        }
+        Ok(())
+    }
+
+    pub fn apply(&mut self, profile: &Profile) {
+        for (key, value) in &profile.values {
+            self.set(key, value).expect("Profile values are validated when they are added");
+        }
+    }
+
+    // Every setting that can be changed with set, with its current value
+    pub fn values(&self) -> Vec<(&'static str, String)> {
+        let mut values = vec![
+            ("variant",                self.variant.name().to_string()),
+            ("cpu_freq",               self.cpu_freq.to_string()),
+            ("dt_freq",                self.dt_freq.to_string()),
+            ("st_freq",                self.st_freq.to_string()),
+            ("rng_seed",               self.rng_seed.to_string()),
+            ("vip_timing",             self.vip_timing.to_string()),
+            ("vf_reset_quirk",         self.vf_reset_quirk.to_string()),
+            ("load_store_quirk",       self.load_store_quirk.to_string()),
+            ("load_store_x_quirk",     self.load_store_x_quirk.to_string()),
+            ("shift_quirk",            self.shift_quirk.to_string()),
+            ("address_overflow_quirk", self.address_overflow_quirk.to_string()),
+            ("jump_quirk",             self.jump_quirk.to_string()),
+            ("display_wait_quirk",     self.display_wait_quirk.to_string()),
+            ("wait_key_release",       self.wait_key_release.to_string()),
+            ("horizontal_wrap",        self.horizontal_wrap.to_string()),
+            ("vertical_wrap",          self.vertical_wrap.to_string()),
+            ("mute",                   self.mute.to_string()),
+            ("colors",                 self.palette.value()),
+            ("filter",                 self.filter.name().to_string()),
+            ("decay_frames",           self.decay_frames.to_string()),
+            ("rewind_seconds",         self.rewind_seconds.to_string()),
+            ("print_rom",              self.print_rom.to_string()),
+            ("print_opcodes",          self.print_opcodes.to_string()),
+            ("debug",                  self.debug.to_string()),
+        ];
+        for (key, setting) in key::SETTINGS.iter().enumerate() {
+            values.push((setting, self.keymap.value(key as u8)));
+        }
+        for (key, setting) in key::PAD_SETTINGS.iter().enumerate() {
+            values.push((setting, self.keymap.buttons_value(key as u8)));
+        }
+        values
+    }
+
+    // Stored by name so states stay readable when settings are added
+    pub fn save(&self, w: &mut Writer) {
+        w.u16(self.rom_addr);
+        let values = self.values();
+        w.u32(values.len() as u32);
+        for (key, value) in values {
+            w.str(key);
+            w.str(&value);
+        }
+    }
+
+    pub fn load(r: &mut Reader) -> Result<Self, Chip8Error> {
+        let mut settings = Self::new();
+        settings.rom_addr = r.u16()?;
+        for _ in 0..r.u32()? {
+            let key = r.str()?;
+            let value = r.str()?;
+            settings.set(key, value).map_err(sav::invalid)?;
         }
+        Ok(settings)
     }
-}
\ No newline at end of file
+}
+
+pub fn parse_number<T: TryFrom<u64>>(value: &str) -> Result<T, String> {
+    let number = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse::<u64>(),
+    };
+    let number = number.map_err(|_| format!("expected a number, got '{}'", value))?;
+    T::try_from(number).map_err(|_| format!("number {} is too large", value))
+}
+
+fn parse_freq(value: &str) -> Result<u16, String> {
+    match parse_number(value)? {
+        0 => Err("frequency must be greater than 0".to_string()),
+        freq => Ok(freq),
+    }
+}
+
+fn parse_frames(value: &str) -> Result<u8, String> {
+    match parse_number(value)? {
+        0 => Err("number of frames must be greater than 0".to_string()),
+        frames => Ok(frames),
+    }
+}
+
+fn parse_variant(value: &str) -> Result<Variant, String> {
+    match value {
+        "chip8" => Ok(Variant::CHIP8),
+        "schip" => Ok(Variant::SCHIP),
+        "xochip" => Ok(Variant::XOCHIP),
+        _ => Err(format!("expected chip8, schip or xochip, got '{}'", value)),
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("expected true or false, got '{}'", value)),
+    }
+}
+
+// A set of settings to change, e.g. the quirks a particular ROM needs or the options given on the command line.
+// Values are checked when they are added so applying a profile can't fail.
+#[derive(Clone, Default)]
+pub struct Profile {
+    values: Vec<(String, String)>,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self { values: Vec::new() }
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        Settings::new().set(key, value)?;
+        self.values.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    // Add the values of another profile, they take precedence over the values already in this one
+    pub fn extend(&mut self, other: Profile) {
+        self.values.extend(other.values);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub(crate) fn from_entries<'a, I: IntoIterator<Item = &'a cfg::Entry>>(entries: I) -> Result<Self, String> {
+        let mut profile = Self::new();
+        for entry in entries {
+            profile.set(&entry.key, &entry.value).map_err(|e| format!("line {}: {}", entry.line, e))?;
+        }
+        Ok(profile)
+    }
+
+    // Parse a settings file, one `setting = value` per line
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let sections = cfg::parse(text)?;
+        if let Some(section) = sections.get(1) {
+            return Err(format!("line {}: sections are not allowed in a settings file", section.line));
+        }
+        Self::from_entries(&sections[0].entries)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,128 @@
+use super::op::Op;
+
+// COSMAC VIP timing, used with vip_timing. The VIP runs its CDP1802 at 1.76064 MHz and every machine cycle takes 8
+// clock cycles. Costs are in machine cycles and follow Laurence Scotford's analysis of the original interpreter.
+const MACHINE_CYCLE_FREQ: u32 = 1_760_640 / 8;
+
+// The display interrupt and the DMA of the 64x32 display keep the CPU busy for about half of every 60 Hz frame, only
+// the rest is left for the interpreter
+pub const FRAME_CYCLES: u32 = MACHINE_CYCLE_FREQ / 60;
+pub const INTERRUPT_CYCLES: u32 = 1832;
+
+// Every instruction is fetched and dispatched by the interpreter loop before it runs
+const FETCH_CYCLES: u32 = 40;
+
+// Clearing the 256 bytes of display memory
+const CLS_CYCLES: u32 = 3078;
+
+// Machine cycles taken by an instruction at opaddr that left pc at next, given the registers before it ran.
+// Instructions the VIP doesn't have only take the fetch, as do LDKP (Fx0A) and DRW (Dxyn) while they wait.
+pub fn cycles(op: &Op, v: &[u8; 16], i: u16, opaddr: u16, next: u16) -> u32 {
+    let skip = if next != opaddr.wrapping_add(2) { 4 } else { 0 };
+    let waiting = next == opaddr;
+    // Adding to the low byte of an address takes longer when it carries into the high byte
+    let page = |from: u16, to: u16| if from & 0xFF00 != to & 0xFF00 { 2 } else { 0 };
+
+    FETCH_CYCLES + match *op {
+        Op::CLS                        => CLS_CYCLES,
+        Op::RET                        => 10,
+        Op::JP   { .. }                => 12,
+        Op::CALL { .. }                => 26,
+        Op::SE   { .. }                => 10 + skip,
+        Op::SNE  { .. }                => 10 + skip,
+        Op::SER  { .. }                => 14 + skip,
+        Op::LD   { .. }                => 6,
+        Op::ADD  { .. }                => 10,
+        Op::LDR  { .. }                => 12,
+        Op::OR   { .. } |
+        Op::AND  { .. } |
+        Op::XOR  { .. } |
+        Op::ADDR { .. } |
+        Op::SUB  { .. } |
+        Op::SHR  { .. } |
+        Op::SUBN { .. } |
+        Op::SHL  { .. }                => 44,
+        Op::SNER { .. }                => 14 + skip,
+        Op::LDA  { .. }                => 12,
+        Op::JPO  { addr }              => 22 + page(addr, next),
+        Op::RND  { .. }                => 36,
+        Op::DRW  { .. } if waiting     => 0,
+        Op::DRW  { reg_a, nibble, .. } => drw_cycles(v[reg_a as usize], nibble),
+        Op::SKP  { .. }                => 14 + skip,
+        Op::SKNP { .. }                => 14 + skip,
+        Op::LDDT { .. }                => 10,
+        Op::LDKP { .. } if waiting     => 0,
+        Op::LDKP { .. }                => 10,
+        Op::STDT { .. }                => 10,
+        Op::STST { .. }                => 10,
+        Op::ADDA { reg }               => 16 + page(i, i.wrapping_add(v[reg as usize] as u16)),
+        Op::LDSA { .. }                => 16,
+        Op::STDR { reg }               => stdr_cycles(v[reg as usize]),
+        Op::STRR { reg }               => 14 + 14 * (reg as u32 + 1),
+        Op::LDRR { reg }               => 14 + 14 * (reg as u32 + 1),
+        _                              => 0,
+    }
+}
+
+// The sprite is prepared row by row, shifting every byte right to the pixel it starts at, one bit at a time
+fn drw_cycles(x: u8, rows: u8) -> u32 {
+    let shift = (x % 8) as u32;
+    68 + rows as u32 * (46 + 20 * shift)
+}
+
+// Each decimal digit is found by repeated subtraction
+fn stdr_cycles(value: u8) -> u32 {
+    let digits = (value / 100 + value / 10 % 10 + value % 10) as u32;
+    80 + 16 * digits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_cost_more() {
+        let v = [0; 16];
+        let op = Op::SE { reg: 0, byte: 0 };
+        assert_eq!(cycles(&op, &v, 0, 0x200, 0x202), FETCH_CYCLES + 10);
+        assert_eq!(cycles(&op, &v, 0, 0x200, 0x204), FETCH_CYCLES + 14);
+    }
+
+    #[test]
+    fn page_crossings_cost_more() {
+        let mut v = [0; 16];
+        v[1] = 0x20;
+        let op = Op::ADDA { reg: 1 };
+        assert_eq!(cycles(&op, &v, 0x2D0, 0x200, 0x202), FETCH_CYCLES + 16);
+        assert_eq!(cycles(&op, &v, 0x2F0, 0x200, 0x202), FETCH_CYCLES + 18);
+        let op = Op::JPO { addr: 0x2FF };
+        assert_eq!(cycles(&op, &v, 0, 0x200, 0x2FF), FETCH_CYCLES + 22);
+        assert_eq!(cycles(&op, &v, 0, 0x200, 0x300), FETCH_CYCLES + 24);
+    }
+
+    #[test]
+    fn drw_depends_on_height_and_alignment() {
+        let mut v = [0; 16];
+        let op = Op::DRW { reg_a: 1, reg_b: 2, nibble: 5 };
+        assert_eq!(cycles(&op, &v, 0, 0x200, 0x202), FETCH_CYCLES + 68 + 5 * 46);
+        v[1] = 11;
+        assert_eq!(cycles(&op, &v, 0, 0x200, 0x202), FETCH_CYCLES + 68 + 5 * (46 + 3 * 20));
+        // Waiting for the display interrupt
+        assert_eq!(cycles(&op, &v, 0, 0x200, 0x200), FETCH_CYCLES);
+    }
+
+    #[test]
+    fn stdr_depends_on_the_digits() {
+        let mut v = [0; 16];
+        let op = Op::STDR { reg: 3 };
+        assert_eq!(cycles(&op, &v, 0, 0x200, 0x202), FETCH_CYCLES + 80);
+        v[3] = 255;
+        assert_eq!(cycles(&op, &v, 0, 0x200, 0x202), FETCH_CYCLES + 80 + 16 * 12);
+    }
+
+    #[test]
+    fn frame_length() {
+        // 262 lines of 14 machine cycles
+        assert_eq!(FRAME_CYCLES, 3668);
+    }
+}
@@ -0,0 +1,265 @@
+use std::path::{Path, PathBuf};
+use std::process;
+use super::{Profile, Settings};
+
+pub enum Command {
+    Run {
+        rom: PathBuf,
+        // Settings file applied before the ROM database
+        config: Option<PathBuf>,
+        // Additional ROM database entries
+        database: Option<PathBuf>,
+        // Settings given as options, applied after the ROM database
+        overrides: Profile,
+        // Movie file to record the input to, or to play back
+        record: Option<PathBuf>,
+        play: Option<PathBuf>,
+    },
+    Help,
+}
+
+pub fn usage(program: &str) -> String {
+    let defaults = Settings::new();
+    let on_off = |value: bool| if value { "on" } else { "off" };
+
+    let mut usage = String::new();
+    usage += &format!("Usage: {} [OPTIONS] <ROM>\n", program);
+    usage += "\n";
+    usage += "Arguments:\n";
+    usage += "  <ROM>                       Path to the ROM file, or Octo source ending in .8o\n";
+    usage += "\n";
+    usage += "Options:\n";
+    usage += "  --config <FILE>             Settings file, applied before the ROM database\n";
+    usage += "  --rom-db <FILE>             Additional ROM database entries\n";
+    usage += "  --record <FILE>             Record the input to a movie, saved when quitting\n";
+    usage += "  --play <FILE>               Play back a movie recorded with --record\n";
+    usage += "  --variant <chip8|schip|xochip>\n";
+    usage += &format!("                              Instruction set and display (default: {})\n", defaults.variant.name());
+    usage += &format!("  --cpu-freq <HZ>             Instructions per second (default: {})\n", defaults.cpu_freq);
+    usage += &format!("  --dt-freq <HZ>              Delay timer frequency (default: {})\n", defaults.dt_freq);
+    usage += &format!("  --st-freq <HZ>              Sound timer frequency (default: {})\n", defaults.st_freq);
+    usage += &format!("  --rng-seed <SEED>           Random number generator seed (default: {})\n", defaults.rng_seed);
+    usage += &format!("  --[no-]vip-timing           Run at the speed of the COSMAC VIP instead of --cpu-freq (default: {})\n",
+        on_off(defaults.vip_timing));
+    usage += "  --quirks <cosmac-vip|chip-48|schip-1.1|xo-chip>\n";
+    usage += "                              Set every quirk below to match an interpreter\n";
+    usage += &format!("  --[no-]vf-reset-quirk       Reset VF after 8xy1, 8xy2 and 8xy3 (default: {})\n",
+        on_off(defaults.vf_reset_quirk));
+    usage += &format!("  --[no-]load-store-quirk     Increment I by X + 1 after Fx55 and Fx65 (default: {})\n",
+        on_off(defaults.load_store_quirk));
+    usage += &format!("  --[no-]load-store-x-quirk   Increment I by X instead, with --load-store-quirk (default: {})\n",
+        on_off(defaults.load_store_x_quirk));
+    usage += &format!("  --[no-]shift-quirk          Shift Vx in place in 8xy6 and 8xyE (default: {})\n",
+        on_off(defaults.shift_quirk));
+    usage += "  --[no-]address-overflow-quirk\n";
+    usage += &format!("                              Set VF when Fx1E overflows (default: {})\n",
+        on_off(defaults.address_overflow_quirk));
+    usage += &format!("  --[no-]jump-quirk           Jump to xnn + Vx in Bxnn (default: {})\n",
+        on_off(defaults.jump_quirk));
+    usage += &format!("  --[no-]display-wait-quirk   Draw at most one sprite per frame (default: {})\n",
+        on_off(defaults.display_wait_quirk));
+    usage += &format!("  --[no-]wait-key-release     Finish Fx0A when the key is released, not pressed (default: {})\n",
+        on_off(defaults.wait_key_release));
+    usage += &format!("  --[no-]horizontal-wrap      Wrap sprites around the right of the screen (default: {})\n",
+        on_off(defaults.horizontal_wrap));
+    usage += &format!("  --[no-]vertical-wrap        Wrap sprites around the bottom of the screen (default: {})\n",
+        on_off(defaults.vertical_wrap));
+    usage += &format!("  --[no-]mute                 Disable sound (default: {})\n", on_off(defaults.mute));
+    usage += "  --palette <default|green|amber|lcd|high-contrast>\n";
+    usage += "                              Display colours (default: default)\n";
+    usage += "  --colors <COLORS>           Display colours as hex, background first, e.g. \"#000000, #FFFFFF\"\n";
+    usage += &format!("  --filter <off|decay|or>     Display filter against flicker (default: {})\n",
+        defaults.filter.name());
+    usage += &format!("  --decay-frames <FRAMES>     Frames pixels take to fade out with --filter decay (default: {})\n",
+        defaults.decay_frames);
+    usage += "  --keymap <qwerty|azerty|numpad>\n";
+    usage += "                              Host keys for the CHIP-8 keypad (default: qwerty)\n";
+    usage += &format!("  --rewind-seconds <SECONDS>  History kept for rewinding, 0 to disable (default: {})\n",
+        defaults.rewind_seconds);
+    usage += &format!("  --[no-]print-rom            Print the ROM after loading it (default: {})\n",
+        on_off(defaults.print_rom));
+    usage += &format!("  --[no-]print-opcodes        Print every executed instruction (default: {})\n",
+        on_off(defaults.print_opcodes));
+    usage += &format!("  --[no-]debug                Start paused with a debugger prompt in the terminal (default: {})\n",
+        on_off(defaults.debug));
+    usage += "  -h, --help                  Print this help\n";
+    usage += "\n";
+    usage += "Numbers can be given in decimal or in hexadecimal with a 0x prefix.\n";
+    usage += "Settings in <ROM>.toml next to the ROM file override the ROM database, options override both.\n";
+    usage
+}
+
+// Parses the arguments following the program name
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut rom = None;
+    let mut config = None;
+    let mut database = None;
+    let mut record = None;
+    let mut play = None;
+    let mut overrides = Profile::new();
+
+    let mut args = Parser::new(args);
+    while let Some(arg) = args.next_arg()? {
+        let name = match arg {
+            Arg::Help => return Ok(Command::Help),
+            Arg::Path(path) => {
+                set_path(&mut rom, path, "ROM")?;
+                continue;
+            }
+            Arg::Option(name) if name.starts_with("--") => name,
+            Arg::Option(name) => return Err(format!("unknown option '{}'", name)),
+        };
+        let mut value = || args.value();
+
+        let (key, value) = match name.as_str() {
+            "--config" => {
+                config = Some(PathBuf::from(value()?));
+                continue;
+            }
+            "--rom-db" => {
+                database = Some(PathBuf::from(value()?));
+                continue;
+            }
+            "--record" => {
+                record = Some(PathBuf::from(value()?));
+                continue;
+            }
+            "--play" => {
+                play = Some(PathBuf::from(value()?));
+                continue;
+            }
+            "--variant"  => ("variant", value()?),
+            "--cpu-freq" => ("cpu_freq", value()?),
+            "--dt-freq"  => ("dt_freq", value()?),
+            "--st-freq"  => ("st_freq", value()?),
+            "--rng-seed" => ("rng_seed", value()?),
+            "--quirks"   => ("quirks", value()?),
+            "--keymap"   => ("keymap", value()?),
+            "--palette"  => ("palette", value()?),
+            "--colors"   => ("colors", value()?),
+            "--filter"   => ("filter", value()?),
+            "--decay-frames" => ("decay_frames", value()?),
+            "--rewind-seconds" => ("rewind_seconds", value()?),
+            _ => {
+                let (flag, on) = match name.strip_prefix("--no-") {
+                    Some(flag) => (flag, false),
+                    None => (&name[2..], true),
+                };
+                let key = match flag {
+                    "vip-timing"             => "vip_timing",
+                    "vf-reset-quirk"         => "vf_reset_quirk",
+                    "load-store-quirk"       => "load_store_quirk",
+                    "load-store-x-quirk"     => "load_store_x_quirk",
+                    "shift-quirk"            => "shift_quirk",
+                    "address-overflow-quirk" => "address_overflow_quirk",
+                    "jump-quirk"             => "jump_quirk",
+                    "display-wait-quirk"     => "display_wait_quirk",
+                    "wait-key-release"       => "wait_key_release",
+                    "horizontal-wrap"        => "horizontal_wrap",
+                    "vertical-wrap"          => "vertical_wrap",
+                    "mute"                   => "mute",
+                    "print-rom"              => "print_rom",
+                    "print-opcodes"          => "print_opcodes",
+                    "debug"                  => "debug",
+                    _ => return Err(format!("unknown option '{}'", name)),
+                };
+                (key, on.to_string())
+            }
+        };
+
+        overrides.set(key, &value).map_err(|e| format!("invalid value '{}' for '{}': {}", value, name, e))?;
+    }
+
+    if record.is_some() && play.is_some() {
+        return Err("options '--record' and '--play' can't be used together".to_string());
+    }
+
+    match rom {
+        Some(rom) => Ok(Command::Run { rom, config, database, overrides, record, play }),
+        None => Err("missing ROM path".to_string()),
+    }
+}
+
+pub enum Arg {
+    Help,
+    // An option such as "-o" or "--config", its value is taken with Parser::value
+    Option(String),
+    Path(PathBuf),
+}
+
+// Reads the arguments following the program name one at a time. Options take their value either as --name=value or
+// as the next argument.
+pub struct Parser<I: Iterator<Item = String>> {
+    args: I,
+    // The last option and the value given after its =, until it's taken
+    option: String,
+    inline_value: Option<String>,
+}
+
+impl<I: Iterator<Item = String>> Parser<I> {
+    pub fn new<A: IntoIterator<IntoIter = I>>(args: A) -> Self {
+        Self { args: args.into_iter(), option: String::new(), inline_value: None }
+    }
+
+    pub fn next_arg(&mut self) -> Result<Option<Arg>, String> {
+        if self.inline_value.take().is_some() {
+            return Err(format!("option '{}' doesn't take a value", self.option));
+        }
+        let arg = match self.args.next() {
+            Some(arg) => arg,
+            None => return Ok(None),
+        };
+        if arg == "-h" || arg == "--help" {
+            return Ok(Some(Arg::Help));
+        }
+        if !arg.starts_with('-') {
+            return Ok(Some(Arg::Path(PathBuf::from(arg))));
+        }
+        match arg.find('=') {
+            Some(i) if arg.starts_with("--") => {
+                self.option = arg[..i].to_string();
+                self.inline_value = Some(arg[i + 1..].to_string());
+            }
+            _ => self.option = arg,
+        }
+        Ok(Some(Arg::Option(self.option.clone())))
+    }
+
+    // The value of the last option
+    pub fn value(&mut self) -> Result<String, String> {
+        match self.inline_value.take() {
+            Some(value) => Ok(value),
+            None => self.args.next().ok_or_else(|| format!("option '{}' needs a value", self.option)),
+        }
+    }
+}
+
+// Sets the path of a program that takes only one, what it is is given for the error, e.g. "ROM"
+pub fn set_path(path: &mut Option<PathBuf>, arg: PathBuf, what: &str) -> Result<(), String> {
+    if path.is_some() {
+        return Err(format!("unexpected argument '{}', only one {} can be given", arg.display(), what));
+    }
+    *path = Some(arg);
+    Ok(())
+}
+
+// Default settings with the settings file given with --config applied
+pub fn settings(config: Option<&Path>) -> Result<Settings, String> {
+    let mut settings = Settings::new();
+    if let Some(config) = config {
+        settings.apply(&Profile::load(config)?);
+    }
+    Ok(settings)
+}
+
+// For mistakes in the arguments, exits after printing the error and the usage
+pub fn exit_with_usage(error: &str, usage: &str) -> ! {
+    eprintln!("error: {}\n", error);
+    eprint!("{}", usage);
+    process::exit(2);
+}
+
+pub fn exit_with_error(error: String) -> ! {
+    eprintln!("error: {}", error);
+    process::exit(1);
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+use super::chip8::{Keymap, Palette};
+
+pub enum FrontendEvent {
+    KeyPress(u8),
+    // Save states, slots are numbered from 1
+    SaveState(u8),
+    LoadState(u8),
+    // The rewind key was pressed (true) or released (false)
+    Rewind(bool),
+    // Same for the fast-forward key, execution runs unthrottled while it's held
+    FastForward(bool),
+    // The palette key was pressed, which switches to the next palette preset
+    NextPalette,
+    // The remap key was pressed, which opens the remap screen or closes it without changing the keymap
+    Remap,
+    // While capturing keys, the name of a key that was pressed
+    KeyName(String),
+    Quit,
+    None,
+}
+
+// Everything the emulator needs from the outside world. The SDL Platform is one implementation,
+// tools and tests can provide their own (e.g. one that draws nothing and never beeps).
+pub trait Frontend {
+    // Video, the resolution is 64x32 or 128x64 (SCHIP high resolution mode) and is set before drawing a frame
+    fn set_resolution(&mut self, width: u8, height: u8);
+    fn set_palette(&mut self, palette: &Palette);
+    fn clear(&mut self);
+    // color is the bit mask of the XO-CHIP planes the pixel is set in, always 1 for CHIP-8 and SCHIP, and the palette
+    // slot to draw it with
+    fn draw_pixel(&mut self, x: u8, y: u8, color: u8);
+    // A pixel fading out with the display filter, blended over the background at level / 255 of its colour
+    fn draw_faded_pixel(&mut self, x: u8, y: u8, color: u8, level: u8);
+    fn present(&mut self);
+
+    // Audio, while beeping the 128 bit pattern is played 1 bit per sample at 4000 * 2^((pitch - 64) / 48) Hz
+    fn beep(&mut self, beep: bool);
+    fn set_audio(&mut self, pattern: [u8; 16], pitch: u8);
+
+    // Input, the keymap gives the names of the host keys that press each CHIP-8 key. Unknown names are an error.
+    fn set_keymap(&mut self, keymap: &Keymap) -> Result<(), String>;
+    fn keyboard_state(&mut self) -> [bool; 16];
+    // While capturing, key presses are reported as KeyName instead of pressing CHIP-8 keys
+    fn capture_keys(&mut self, capture: bool);
+    // Next pending event, None once there are no more
+    fn poll_event(&mut self) -> FrontendEvent;
+
+    // Time elapsed since some fixed point in the past, only differences between calls matter
+    fn time(&mut self) -> Duration;
+    // Block the thread until the next frame is due
+    fn sleep(&mut self, duration: Duration);
+}
+
+// A frontend that shows nothing, plays nothing and has no keys pressed. Meant for driving the emulator with
+// CHIP8::step, run_cycles and run_frame, e.g. in tools and tests.
+pub struct Headless;
+
+impl Frontend for Headless {
+    fn set_resolution(&mut self, _width: u8, _height: u8) {}
+    fn set_palette(&mut self, _palette: &Palette) {}
+    fn clear(&mut self) {}
+    fn draw_pixel(&mut self, _x: u8, _y: u8, _color: u8) {}
+    fn draw_faded_pixel(&mut self, _x: u8, _y: u8, _color: u8, _level: u8) {}
+    fn present(&mut self) {}
+
+    fn beep(&mut self, _beep: bool) {}
+    fn set_audio(&mut self, _pattern: [u8; 16], _pitch: u8) {}
+
+    fn set_keymap(&mut self, _keymap: &Keymap) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn keyboard_state(&mut self) -> [bool; 16] {
+        [false; 16]
+    }
+
+    fn capture_keys(&mut self, _capture: bool) {}
+
+    fn poll_event(&mut self) -> FrontendEvent {
+        FrontendEvent::None
+    }
+
+    fn time(&mut self) -> Duration {
+        Duration::from_secs(0)
+    }
+
+    fn sleep(&mut self, _duration: Duration) {}
+}
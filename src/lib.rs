@@ -0,0 +1,13 @@
+// Type and instruction names follow the CHIP-8 documentation (CPU, RNG, DRW, LDKP, ...)
+#![allow(clippy::upper_case_acronyms)]
+
+pub use self::chip8::{AsmError, CHIP8, Chip8Error, Database, Display, Filter, Keymap, Movie, Palette, Profile, Settings, Status, Variant, assemble, assemble_file, compile_octo, compile_octo_file, disassemble, rom_config_path};
+pub use self::frontend::{Frontend, FrontendEvent, Headless};
+#[cfg(feature = "sdl")]
+pub use self::platform::Platform;
+
+mod chip8;
+pub mod cli;
+mod frontend;
+#[cfg(feature = "sdl")]
+mod platform;
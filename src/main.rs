@@ -1,10 +1,57 @@
-use chip8::CHIP8;
-
-mod chip8;
-mod platform;
+use std::env;
+use std::fs;
+use chip8::cli::{self, Command, exit_with_error};
+use chip8::{CHIP8, Movie, Platform, Profile, rom_config_path};
 
 fn main() {
-    let mut chip8 = CHIP8::new();
-    chip8.load_rom("roms/BLINKY").expect("Failed to load ROM!");
+    let mut args = env::args();
+    let program = args.next().unwrap_or_else(|| "chip8".to_string());
+
+    let (rom, config, database, overrides, record, play) = match cli::parse(args) {
+        Ok(Command::Run { rom, config, database, overrides, record, play }) => (rom, config, database, overrides, record, play),
+        Ok(Command::Help) => {
+            print!("{}", cli::usage(&program));
+            return;
+        }
+        Err(error) => cli::exit_with_usage(&error, &cli::usage(&program)),
+    };
+
+    let settings = cli::settings(config.as_deref()).unwrap_or_else(|error| exit_with_error(error));
+
+    // Settings in <ROM>.toml apply to this ROM only, options given on the command line take precedence
+    let mut rom_overrides = Profile::new();
+    let rom_config = rom_config_path(&rom);
+    if rom_config.exists() {
+        rom_overrides = Profile::load(rom_config).unwrap_or_else(|error| exit_with_error(error));
+    }
+    rom_overrides.extend(overrides);
+
+    let platform = Platform::new().unwrap_or_else(|error| exit_with_error(error.to_string()));
+    let mut chip8 = CHIP8::new(platform, settings);
+    chip8.set_overrides(rom_overrides);
+    if let Some(database) = database {
+        chip8.database_mut().load(database).unwrap_or_else(|error| exit_with_error(error));
+    }
+    if let Err(error) = chip8.load_rom(&rom) {
+        exit_with_error(format!("can't load ROM {}: {}", rom.display(), error));
+    }
+    if let Some(play) = play {
+        let movie = fs::read(&play).map_err(|error| error.into()).and_then(|data| Movie::load(&data))
+            .and_then(|movie| chip8.play(movie));
+        if let Err(error) = movie {
+            exit_with_error(format!("can't play movie {}: {}", play.display(), error));
+        }
+    }
+    if record.is_some() {
+        chip8.record();
+    }
     chip8.run();
+
+    if let Some(record) = record {
+        let movie = chip8.stop_recording().expect("Recording only stops here");
+        match fs::write(&record, movie.save()) {
+            Ok(()) => println!("Movie saved to {} ({} frames)", record.display(), movie.len()),
+            Err(error) => exit_with_error(format!("can't save movie {}: {}", record.display(), error)),
+        }
+    }
 }
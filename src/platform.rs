@@ -1,66 +1,79 @@
 use fermium::*;
-use std::ffi::CString;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
 use std::ptr::null_mut;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use super::chip8::{Chip8Error, Keymap, Palette};
+use super::frontend::{Frontend, FrontendEvent};
 
-struct Keymap;
-
-impl Keymap {
-    pub fn new() -> Keymap {
-        Self
-    }
-
-    pub fn keycode(&self, key: u8) -> i32 {
-        /*
-            CHIP-8 layout is:
-            1	2	3	C
-            4	5	6	D
-            7	8	9	E
-            A	0	B	F
-        */
-        match key {
-            0x1 => SDLK_1,
-            0x2 => SDLK_2,
-            0x3 => SDLK_3,
-            0xC => SDLK_4,
-            0x4 => SDLK_q,
-            0x5 => SDLK_w,
-            0x6 => SDLK_e,
-            0xD => SDLK_r,
-            0x7 => SDLK_a,
-            0x8 => SDLK_s,
-            0x9 => SDLK_d,
-            0xE => SDLK_f,
-            0xA => SDLK_z,
-            0x0 => SDLK_x,
-            0xB => SDLK_c,
-            0xF => SDLK_v,
-            _ => panic!("Key out of range"),
-        }
-    }
+// F1 to F9 load save state slots 1 to 9, with shift held they save them
+const STATE_KEYS: [i32; 9] = [SDLK_F1, SDLK_F2, SDLK_F3, SDLK_F4, SDLK_F5, SDLK_F6, SDLK_F7, SDLK_F8, SDLK_F9];
+
+// Held to rewind
+const REWIND_KEY: i32 = SDLK_BACKSPACE;
+
+// Held to fast-forward
+const FAST_FORWARD_KEY: i32 = SDLK_TAB;
+
+// Opens the remap screen, or closes it without changing the keymap
+const REMAP_KEY: i32 = SDLK_F12;
+
+// Switches to the next palette
+const PALETTE_KEY: i32 = SDLK_F11;
+
+// Sticks and triggers closer to rest than this don't press anything, out of 32767
+const DEADZONE: i32 = 8000;
+
+// A controller input that can be bound to a CHIP-8 key
+#[derive(Clone, Copy, PartialEq)]
+enum Input {
+    Button(SDL_GameControllerButton),
+    // An axis pushed past the deadzone towards negative (-1) or positive (1) values
+    Axis(SDL_GameControllerAxis, i32),
 }
 
 const DISPLAY_W: i32 = 64;
 const DISPLAY_H: i32 = 32;
 const DISPLAY_SCALE: i32 = 20;
 
-pub enum PlatformEvent {
-    KeyPress(u8),
-    Quit,
-    None,
+const AUDIO_FREQ: i32 = 44100;
+
+// Shared with the audio callback, which runs on its own thread
+struct Audio {
+    pattern: [u8; 16],
+    pitch: u8,
+    // Position in the pattern in bits
+    position: f64,
 }
 
+static AUDIO: Mutex<Audio> = Mutex::new(Audio { pattern: [0; 16], pitch: 64, position: 0.0 });
+
 pub struct Platform {
     #[allow(unused)]
     window: *mut SDL_Window,
     renderer: *mut SDL_Renderer,
-    keymap: Keymap,
+    // Host keys and controller inputs for each CHIP-8 key
+    keycodes: [Vec<SDL_Keycode>; 16],
+    inputs: [Vec<Input>; 16],
+    capture: bool,
+    // Connected controllers, opened and closed as they are plugged in and out
+    controllers: Vec<*mut SDL_GameController>,
+    // Direction each axis of each controller is pushed in, to report presses only when it changes
+    axes: HashMap<(SDL_JoystickID, u8), i32>,
+    start: Instant,
+    // Size of an emulated pixel, smaller in SCHIP high resolution mode so the window size stays the same
+    scale: i32,
+    palette: Palette,
 }
 
 impl Platform {
-    pub fn new() -> Self {
+    pub fn new() -> Result<Self, Chip8Error> {
         unsafe {
-            if SDL_Init(SDL_INIT_VIDEO | SDL_INIT_AUDIO | SDL_INIT_EVENTS | SDL_INIT_TIMER) != 0 {
-                panic!("Can't init SDL");
+            // Controllers connected at startup are reported with the same events as the ones plugged in later
+            let flags = SDL_INIT_VIDEO | SDL_INIT_AUDIO | SDL_INIT_EVENTS | SDL_INIT_TIMER | SDL_INIT_GAMECONTROLLER;
+            if SDL_Init(flags) != 0 {
+                return Err(sdl_error("Can't init SDL"));
             }
 
             let name = CString::new("CHIP-8").unwrap();
@@ -71,109 +84,348 @@ impl Platform {
             let window = SDL_CreateWindow(name.as_ptr(), SDL_WINDOWPOS_CENTERED, SDL_WINDOWPOS_CENTERED, w, h,
                 flags);
             if window.is_null() {
-                panic!("Can't create window");
+                return Err(sdl_error("Can't create window"));
             }
 
             let flags = (SDL_RENDERER_ACCELERATED | SDL_RENDERER_PRESENTVSYNC) as u32;
 
             let renderer = SDL_CreateRenderer(window, -1, flags);
             if renderer.is_null() {
-                panic!("Can't create renderer");
+                return Err(sdl_error("Can't create renderer"));
             }
 
-            let keymap = Keymap::new();
-
             let mut audio_spec_want = SDL_AudioSpec {
-                freq: 523, // C5
+                freq: AUDIO_FREQ,
                 format: AUDIO_S16 as u16,
                 channels: 1,
-                samples: 32,
+                samples: 512,
                 callback: Some(audio_callback),
                 ..SDL_AudioSpec::default()
             };
             if SDL_OpenAudio(&mut audio_spec_want, null_mut()) != 0 {
-                panic!("Can't create audio device");
+                return Err(sdl_error("Can't create audio device"));
+            }
+
+            let mut platform = Self {
+                window,
+                renderer,
+                keycodes: Default::default(),
+                inputs: Default::default(),
+                capture: false,
+                controllers: Vec::new(),
+                axes: HashMap::new(),
+                start: Instant::now(),
+                scale: DISPLAY_SCALE,
+                palette: Palette::new(),
+            };
+            platform.set_keymap(&Keymap::new()).expect("The default keymap uses SDL key names");
+            Ok(platform)
+        }
+    }
+
+    // Translates an SDL event, None for the ones the emulator doesn't handle
+    unsafe fn event(&mut self, event: &SDL_Event) -> FrontendEvent {
+        match event.type_ as i32 {
+            SDL_KEYDOWN if event.key.keysym.sym == REMAP_KEY => {
+                if event.key.repeat == 0 { FrontendEvent::Remap } else { FrontendEvent::None }
+            },
+            SDL_KEYDOWN if event.key.keysym.sym == PALETTE_KEY => {
+                if event.key.repeat == 0 { FrontendEvent::NextPalette } else { FrontendEvent::None }
+            },
+            // The keys the emulator uses itself can't be bound
+            SDL_KEYDOWN if self.capture => {
+                let sym = event.key.keysym.sym;
+                let reserved = [REWIND_KEY, FAST_FORWARD_KEY, PALETTE_KEY].contains(&sym) || STATE_KEYS.contains(&sym);
+                if event.key.repeat != 0 || reserved {
+                    FrontendEvent::None
+                } else {
+                    key_name(sym).map_or(FrontendEvent::None, FrontendEvent::KeyName)
+                }
+            },
+            SDL_KEYDOWN if event.key.keysym.sym == REWIND_KEY => {
+                // Ignore key repeat
+                if event.key.repeat == 0 { FrontendEvent::Rewind(true) } else { FrontendEvent::None }
+            },
+            SDL_KEYUP if event.key.keysym.sym == REWIND_KEY => FrontendEvent::Rewind(false),
+            SDL_KEYDOWN if event.key.keysym.sym == FAST_FORWARD_KEY => {
+                if event.key.repeat == 0 { FrontendEvent::FastForward(true) } else { FrontendEvent::None }
+            },
+            SDL_KEYUP if event.key.keysym.sym == FAST_FORWARD_KEY => FrontendEvent::FastForward(false),
+            SDL_KEYDOWN => {
+                if let Some(i) = STATE_KEYS.iter().position(|&key| key == event.key.keysym.sym) {
+                    let slot = i as u8 + 1;
+                    let shift = (KMOD_LSHIFT | KMOD_RSHIFT) as u16;
+                    return if event.key.keysym.mod_ & shift != 0 {
+                        FrontendEvent::SaveState(slot)
+                    } else {
+                        FrontendEvent::LoadState(slot)
+                    };
+                }
+                match self.keycodes.iter().position(|keycodes| keycodes.contains(&event.key.keysym.sym)) {
+                    Some(key) => FrontendEvent::KeyPress(key as u8),
+                    None => FrontendEvent::None,
+                }
+            },
+            SDL_CONTROLLERDEVICEADDED => {
+                self.open_controller(event.cdevice.which);
+                FrontendEvent::None
+            },
+            SDL_CONTROLLERDEVICEREMOVED => {
+                self.close_controller(event.cdevice.which);
+                FrontendEvent::None
+            },
+            SDL_CONTROLLERBUTTONDOWN => self.press(Input::Button(event.cbutton.button as SDL_GameControllerButton)),
+            SDL_CONTROLLERAXISMOTION => {
+                let axis = event.caxis;
+                let direction = if axis.value as i32 > DEADZONE {
+                    1
+                } else if (axis.value as i32) < -DEADZONE {
+                    -1
+                } else {
+                    0
+                };
+                let previous = self.axes.insert((axis.which, axis.axis), direction).unwrap_or(0);
+                if direction != 0 && direction != previous {
+                    self.press(Input::Axis(axis.axis as SDL_GameControllerAxis, direction))
+                } else {
+                    FrontendEvent::None
+                }
+            },
+            SDL_QUIT => FrontendEvent::Quit,
+            _ => FrontendEvent::None
+        }
+    }
+
+    // The key press for a controller input, the remap screen only takes keys
+    fn press(&self, input: Input) -> FrontendEvent {
+        match self.inputs.iter().position(|inputs| inputs.contains(&input)) {
+            Some(key) if !self.capture => FrontendEvent::KeyPress(key as u8),
+            _ => FrontendEvent::None,
+        }
+    }
+
+    fn fill_pixel(&mut self, x: u8, y: u8, (r, g, b): (u8, u8, u8)) {
+        unsafe {
+            let rect = SDL_Rect {
+                x: self.scale * x as i32,
+                y: self.scale * y as i32,
+                w: self.scale,
+                h: self.scale,
+            };
+            SDL_SetRenderDrawColor(self.renderer, r, g, b, 0xFF);
+            SDL_RenderFillRect(self.renderer, &rect);
+        }
+    }
+
+    // index is the joystick device index
+    unsafe fn open_controller(&mut self, index: i32) {
+        let controller = SDL_GameControllerOpen(index);
+        if controller.is_null() {
+            eprintln!("{}", sdl_error("Can't open controller"));
+            return;
+        }
+        println!("Controller connected: {}", controller_name(controller));
+        self.controllers.push(controller);
+    }
+
+    // id is the joystick instance ID
+    unsafe fn close_controller(&mut self, id: SDL_JoystickID) {
+        let position = self.controllers.iter()
+            .position(|&controller| SDL_JoystickInstanceID(SDL_GameControllerGetJoystick(controller)) == id);
+        if let Some(position) = position {
+            let controller = self.controllers.remove(position);
+            println!("Controller disconnected: {}", controller_name(controller));
+            SDL_GameControllerClose(controller);
+            self.axes.retain(|&(which, _), _| which != id);
+        }
+    }
+}
+
+unsafe fn controller_name(controller: *mut SDL_GameController) -> String {
+    let name = SDL_GameControllerName(controller);
+    if name.is_null() { "unknown".to_string() } else { CStr::from_ptr(name).to_string_lossy().into_owned() }
+}
+
+// Buttons and axes go by SDL's names ("a", "dpleft", "lefttrigger", ...), the sticks followed by the direction
+// ("leftx-", "righty+")
+unsafe fn input(name: &str) -> Option<Input> {
+    let (axis_name, direction) = match (name.strip_suffix('-'), name.strip_suffix('+')) {
+        (Some(axis_name), _) => (axis_name, Some(-1)),
+        (_, Some(axis_name)) => (axis_name, Some(1)),
+        _ => (name, None),
+    };
+    let axis_name = CString::new(axis_name).ok()?;
+    let axis = SDL_GameControllerGetAxisFromString(axis_name.as_ptr());
+    if axis != SDL_CONTROLLER_AXIS_INVALID {
+        // Triggers only go one way
+        let trigger = axis == SDL_CONTROLLER_AXIS_TRIGGERLEFT || axis == SDL_CONTROLLER_AXIS_TRIGGERRIGHT;
+        return match direction {
+            Some(direction) if !trigger || direction == 1 => Some(Input::Axis(axis, direction)),
+            None if trigger => Some(Input::Axis(axis, 1)),
+            _ => None,
+        };
+    }
+
+    let name = CString::new(name).ok()?;
+    let button = SDL_GameControllerGetButtonFromString(name.as_ptr());
+    if button != SDL_CONTROLLER_BUTTON_INVALID { Some(Input::Button(button)) } else { None }
+}
+
+// SDL names keys after what they type, the ones with a comma are spelled out so names can be listed with commas
+unsafe fn key_name(keycode: SDL_Keycode) -> Option<String> {
+    let name = CStr::from_ptr(SDL_GetKeyName(keycode)).to_string_lossy();
+    match name.as_ref() {
+        "" => None,
+        "," => Some("Comma".to_string()),
+        "Keypad ," => Some("Keypad Comma".to_string()),
+        name => Some(name.to_string()),
+    }
+}
+
+unsafe fn keycode(name: &str) -> Option<SDL_Keycode> {
+    let name = match name {
+        "Comma" => ",",
+        "Keypad Comma" => "Keypad ,",
+        name => name,
+    };
+    let name = CString::new(name).ok()?;
+    let keycode = SDL_GetKeyFromName(name.as_ptr());
+    if keycode == SDLK_UNKNOWN { None } else { Some(keycode) }
+}
+
+// Adds the reason reported by SDL for the last failure
+unsafe fn sdl_error(message: &str) -> Chip8Error {
+    let reason = CStr::from_ptr(SDL_GetError()).to_string_lossy();
+    Chip8Error::PlatformInit(format!("{}: {}", message, reason))
+}
+
+impl Frontend for Platform {
+    fn set_keymap(&mut self, keymap: &Keymap) -> Result<(), String> {
+        let mut keycodes: [Vec<SDL_Keycode>; 16] = Default::default();
+        for (key, keycodes) in keycodes.iter_mut().enumerate() {
+            for name in keymap.keys(key as u8) {
+                let keycode = unsafe { keycode(name) };
+                keycodes.push(keycode.ok_or_else(|| format!("unknown key '{}' for key {:X}", name, key))?);
             }
+        }
 
-            Self { window, renderer, keymap }
+        let mut inputs: [Vec<Input>; 16] = Default::default();
+        for (key, inputs) in inputs.iter_mut().enumerate() {
+            for name in keymap.buttons(key as u8) {
+                let input = unsafe { input(name) };
+                inputs.push(input.ok_or_else(|| format!("unknown controller button '{}' for key {:X}", name, key))?);
+            }
         }
+
+        self.keycodes = keycodes;
+        self.inputs = inputs;
+        Ok(())
     }
 
-    pub fn keyboard_state(&mut self) -> [bool; 16] {
+    fn keyboard_state(&mut self) -> [bool; 16] {
         unsafe {
             let state = SDL_GetKeyboardState(null_mut());
             let state = std::slice::from_raw_parts(state, SDL_NUM_SCANCODES as usize);
             let mut keys = [false; 16];
-            for key in 0..0xF {
-                if state[SDL_GetScancodeFromKey(self.keymap.keycode(key)) as usize] == 1 {
-                    keys[key as usize] = true;
+            for (key, keycodes) in self.keycodes.iter().enumerate() {
+                keys[key] = keycodes.iter().any(|&keycode| state[SDL_GetScancodeFromKey(keycode) as usize] == 1);
+            }
+
+            for &controller in &self.controllers {
+                for (key, inputs) in self.inputs.iter().enumerate() {
+                    keys[key] |= inputs.iter().any(|&input| match input {
+                        Input::Button(button) => SDL_GameControllerGetButton(controller, button) == 1,
+                        Input::Axis(axis, direction) => {
+                            SDL_GameControllerGetAxis(controller, axis) as i32 * direction > DEADZONE
+                        }
+                    });
                 }
             }
             keys
         }
     }
 
-    pub fn beep(&mut self, beep: bool) {
+    fn capture_keys(&mut self, capture: bool) {
+        self.capture = capture;
+    }
+
+    fn beep(&mut self, beep: bool) {
         unsafe {
             SDL_PauseAudio(if beep { 0 } else { 1 });
         }
     }
 
-    pub fn clear(&mut self) {
+    fn set_resolution(&mut self, width: u8, _height: u8) {
+        self.scale = DISPLAY_W * DISPLAY_SCALE / width as i32;
+    }
+
+    fn set_audio(&mut self, pattern: [u8; 16], pitch: u8) {
+        let mut audio = AUDIO.lock().unwrap();
+        audio.pattern = pattern;
+        audio.pitch = pitch;
+    }
+
+    fn set_palette(&mut self, palette: &Palette) {
+        self.palette = *palette;
+    }
+
+    fn clear(&mut self) {
         unsafe {
-            SDL_SetRenderDrawColor(self.renderer, 0x19, 0x14, 0x28, 0xFF);
+            let (r, g, b) = self.palette.color(0);
+            SDL_SetRenderDrawColor(self.renderer, r, g, b, 0xFF);
             SDL_RenderClear(self.renderer);
         }
     }
 
-    pub fn present(&mut self) {
+    fn present(&mut self) {
         unsafe {
             SDL_RenderPresent(self.renderer);
         }
     }
 
-    pub fn draw_pixel(&mut self, x: u8, y: u8) {
-        unsafe {
-            let rect = SDL_Rect {
-                x: DISPLAY_SCALE as i32 * x as i32,
-                y: DISPLAY_SCALE as i32 * y as i32,
-                w: DISPLAY_SCALE as i32 * 1,
-                h: DISPLAY_SCALE as i32 * 1,
-            };
-            SDL_SetRenderDrawColor(self.renderer, 0xC8, 0xC8, 0xFF, 0xFF);
-            SDL_RenderFillRect(self.renderer, &rect);
-        }
+    fn draw_pixel(&mut self, x: u8, y: u8, color: u8) {
+        self.fill_pixel(x, y, self.palette.color(color));
+    }
+
+    fn draw_faded_pixel(&mut self, x: u8, y: u8, color: u8, level: u8) {
+        self.fill_pixel(x, y, self.palette.blend(color, level));
     }
 
-    pub fn poll_event(&mut self) -> PlatformEvent {
+    fn poll_event(&mut self) -> FrontendEvent {
         unsafe {
             let mut event = SDL_Event::default();
-            SDL_PollEvent(&mut event);
-            match event.type_ as i32 {
-                SDL_KEYDOWN => {
-                    let mut result = PlatformEvent::None;
-                    for key in 0..0xF {
-                        if event.key.keysym.sym == self.keymap.keycode(key) {
-                            result = PlatformEvent::KeyPress(key);
-                            break;
-                        }
-                    }
-                    result
-                },
-                SDL_QUIT => PlatformEvent::Quit,
-                _ => PlatformEvent::None
+            // Skip the events the emulator doesn't handle, None means there are no more
+            while SDL_PollEvent(&mut event) != 0 {
+                match self.event(&event) {
+                    FrontendEvent::None => continue,
+                    result => return result,
+                }
             }
+            FrontendEvent::None
         }
     }
+
+    fn time(&mut self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
 }
 
 unsafe extern "C" fn audio_callback(_userdata: *mut c_void, stream: *mut Uint8, len: c_int) {
-    let stream = std::slice::from_raw_parts_mut(stream as *mut i16, len as usize);
-    for i in 0..len {
-        if i % 2 == 0 {
-            stream[i as usize] = i16::MAX;
+    // len is in bytes, samples are 16 bit
+    let stream = std::slice::from_raw_parts_mut(stream as *mut i16, len as usize / 2);
+    let mut audio = AUDIO.lock().unwrap();
+    let rate = 4000.0 * 2f64.powf((audio.pitch as f64 - 64.0) / 48.0);
+    for sample in stream.iter_mut() {
+        let bit = audio.position as usize;
+        if (audio.pattern[bit / 8] >> (7 - bit % 8)) & 1 == 1 {
+            *sample = i16::MAX;
         } else {
-            stream[i as usize] = i16::MIN;
+            *sample = i16::MIN;
         }
+        audio.position = (audio.position + rate / AUDIO_FREQ as f64) % 128.0;
     }
-}
\ No newline at end of file
+}
@@ -1,2 +1,6 @@
 @echo off
-cargo run
\ No newline at end of file
+if "%~1"=="" (
+    cargo run --features sdl -- roms/BLINKY
+) else (
+    cargo run --features sdl -- %*
+)
\ No newline at end of file
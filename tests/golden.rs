@@ -0,0 +1,105 @@
+// Runs every bundled ROM for a fixed number of frames with scripted input and compares the display with the
+// snapshot in tests/golden/<ROM>.txt. After an intended change regenerate the snapshots with
+//
+//     UPDATE_GOLDEN=1 cargo test --test golden
+//
+// and check the differences in the snapshots before committing them.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use chip8::{CHIP8, Headless, Settings};
+
+// Frames a key stays held after it's pressed
+const HOLD_FRAMES: u32 = 4;
+
+// Runs the ROM with the settings from the built-in database and the default RNG seed. Each (frame, key) of input
+// pushes a key press at that frame and holds the key for a few frames.
+fn run(rom: &str, frames: u32, input: &[(u32, u8)]) -> String {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("roms").join(rom);
+    let mut chip8 = CHIP8::new(Headless, Settings::new());
+    chip8.load_rom(&path).unwrap_or_else(|error| panic!("{}: can't load ROM: {}", rom, error));
+
+    for frame in 0..frames {
+        let mut keys = [false; 16];
+        for &(start, key) in input {
+            if frame == start {
+                chip8.push_keypress(key);
+            }
+            if frame >= start && frame < start + HOLD_FRAMES {
+                keys[key as usize] = true;
+            }
+        }
+        chip8.set_keys(keys);
+        chip8.run_frame().unwrap_or_else(|error| panic!("{}: frame {}: {}\n{}", rom, frame, error, chip8.cpu_state()));
+    }
+
+    // Blank pixels are dots so the snapshots don't depend on editors keeping trailing spaces
+    chip8.display().to_string().replace(' ', ".")
+}
+
+fn check(rom: &str, frames: u32, input: &[(u32, u8)]) {
+    let actual = run(rom, frames, input);
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden").join(format!("{}.txt", rom));
+
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&path, &actual).unwrap_or_else(|error| panic!("can't write {}: {}", path.display(), error));
+        return;
+    }
+
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|error| panic!("can't read {}: {}, run with UPDATE_GOLDEN=1 to create it", path.display(), error))
+        .replace("\r\n", "\n");
+    if actual == expected {
+        return;
+    }
+
+    let mut report = format!("{} differs from {} after {} frames\n", rom, path.display(), frames);
+    let expected_rows: Vec<&str> = expected.lines().collect();
+    let actual_rows: Vec<&str> = actual.lines().collect();
+    for row in 0..expected_rows.len().max(actual_rows.len()) {
+        let expected_row = expected_rows.get(row).copied().unwrap_or("");
+        let actual_row = actual_rows.get(row).copied().unwrap_or("");
+        if expected_row != actual_row {
+            report += &format!("row {:2} expected |{}|\n       actual   |{}|\n", row, expected_row, actual_row);
+        }
+    }
+    report += "\nActual display:\n";
+    report += &actual;
+    panic!("{}", report);
+}
+
+macro_rules! golden {
+    ($name:ident, $rom:expr, $frames:expr, [$($input:expr),*]) => {
+        #[test]
+        fn $name() {
+            check($rom, $frames, &[$($input),*]);
+        }
+    };
+}
+
+// The input is timed for when each game reacts to it, e.g. BLINKY only starts after drawing its maze and MERLIN
+// needs the sequence it shows repeated. Every press changes the snapshot, MAZE doesn't read the keypad.
+golden!(puzzle15, "15PUZZLE", 300,  [(60, 0x6), (140, 0x0), (220, 0x1)]);
+golden!(blinky,   "BLINKY",   1800, [(1400, 0x3), (1600, 0x6), (1700, 0x7)]);
+golden!(blitz,    "BLITZ",    600,  [(60, 0x5), (300, 0x5)]);
+golden!(brix,     "BRIX",     600,  [(120, 0x6), (240, 0x6), (360, 0x4)]);
+golden!(connect4, "CONNECT4", 300,  [(60, 0x6), (90, 0x5), (150, 0x4), (180, 0x5)]);
+golden!(guess,    "GUESS",    900,  [(350, 0x5), (550, 0xA), (750, 0x5)]);
+golden!(hidden,   "HIDDEN",   300,  [(60, 0x5), (180, 0x6), (210, 0x5)]);
+golden!(invaders, "INVADERS", 600,  [(60, 0x5), (200, 0x4), (300, 0x5), (400, 0x6)]);
+golden!(kaleid,   "KALEID",   300,  [(30, 0x2), (60, 0x6), (90, 0x8), (120, 0x4), (150, 0x0)]);
+golden!(maze,     "MAZE",     120,  []);
+golden!(merlin,   "MERLIN",   600,  [(300, 0x5), (330, 0x4), (360, 0x7), (390, 0x4)]);
+golden!(missile,  "MISSILE",  600,  [(250, 0x8), (350, 0x8), (550, 0x8)]);
+golden!(pong,     "PONG",     600,  [(120, 0x4), (160, 0x1), (300, 0xD)]);
+golden!(pong2,    "PONG2",    600,  [(120, 0x4), (160, 0x1), (300, 0xD)]);
+golden!(puzzle,   "PUZZLE",   1200, [(900, 0x4), (975, 0x2), (1050, 0x6), (1125, 0x8)]);
+golden!(syzygy,   "SYZYGY",   600,  [(60, 0xF), (140, 0xB), (220, 0xE), (260, 0x6), (300, 0xB)]);
+golden!(tank,     "TANK",     600,  [(120, 0x4), (200, 0x2), (250, 0x2), (400, 0x8)]);
+golden!(tetris,   "TETRIS",   600,  [(60, 0x4), (120, 0x5), (180, 0x6), (240, 0x7)]);
+golden!(tictac,   "TICTAC",   300,  [(60, 0x1), (120, 0x5), (180, 0x9)]);
+golden!(ufo,      "UFO",      600,  [(60, 0x5), (200, 0x4), (300, 0x6)]);
+golden!(vbrix,    "VBRIX",    600,  [(60, 0x7), (200, 0x4), (300, 0x1)]);
+golden!(vers,     "VERS",     600,  [(60, 0x7), (70, 0xA)]);
+golden!(wipeoff,  "WIPEOFF",  600,  [(60, 0x4), (120, 0x6), (240, 0x4)]);
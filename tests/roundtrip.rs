@@ -0,0 +1,31 @@
+// Disassembles every bundled ROM and assembles the listing again, which has to give back the exact same bytes.
+
+use std::fs;
+use std::path::PathBuf;
+use chip8::{Database, Settings, assemble, disassemble};
+
+#[test]
+fn disassembly_assembles_to_the_rom() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("roms");
+    let database = Database::builtin();
+    let mut roms: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap_or_else(|error| panic!("can't read {}: {}", dir.display(), error))
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    roms.sort();
+    assert!(!roms.is_empty(), "no ROMs in {}", dir.display());
+
+    for path in roms {
+        let rom = fs::read(&path).unwrap_or_else(|error| panic!("can't read {}: {}", path.display(), error));
+        // The variant decides how the ROM is disassembled
+        let mut settings = Settings::new();
+        if let Some((_, profile)) = database.lookup(&rom) {
+            settings.apply(profile);
+        }
+
+        let listing = disassemble(&rom, &settings);
+        let assembled = assemble(&listing, &settings)
+            .unwrap_or_else(|error| panic!("{}: {}\n{}", path.display(), error, listing));
+        assert!(assembled == rom, "{} changed after disassembling and assembling it", path.display());
+    }
+}